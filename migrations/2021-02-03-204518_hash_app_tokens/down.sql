@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+-- Hashed tokens can't be recovered, so every app will need a new token after this.
+ALTER TABLE apps RENAME COLUMN token_hash TO token;
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE apps RENAME COLUMN token TO token_hash;
+UPDATE apps SET token_hash = crypt(RTRIM(token_hash), gen_salt('bf'));
\ No newline at end of file
@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE repos ADD COLUMN apps BIGINT[] NOT NULL DEFAULT '{}';
+
+UPDATE repos SET apps = COALESCE((
+    SELECT array_agg(repo_apps.app_id ORDER BY repo_apps.position)
+    FROM repo_apps
+    WHERE repo_apps.repo_id = repos.id
+), '{}');
+
+DROP TABLE repo_apps;
\ No newline at end of file
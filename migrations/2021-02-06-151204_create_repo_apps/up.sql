@@ -0,0 +1,19 @@
+-- Your SQL goes here
+CREATE TABLE repo_apps (
+    repo_id BIGINT NOT NULL REFERENCES repos(id) ON DELETE CASCADE,
+    app_id BIGINT NOT NULL REFERENCES apps(id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    added_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (repo_id, app_id)
+);
+
+CREATE INDEX repo_apps_app_id_idx on repo_apps (app_id);
+
+-- Keeps the first occurrence of duplicated apps and drops ids of apps that no longer exist
+INSERT INTO repo_apps (repo_id, app_id, position)
+SELECT DISTINCT ON (repos.id, entries.app_id) repos.id, entries.app_id, entries.position::INTEGER
+FROM repos, unnest(repos.apps) WITH ORDINALITY AS entries(app_id, position)
+WHERE EXISTS (SELECT 1 FROM apps WHERE apps.id = entries.app_id)
+ORDER BY repos.id, entries.app_id, entries.position;
+
+ALTER TABLE repos DROP COLUMN apps;
\ No newline at end of file
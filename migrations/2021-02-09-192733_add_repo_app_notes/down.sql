@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE repo_apps DROP COLUMN note;
\ No newline at end of file
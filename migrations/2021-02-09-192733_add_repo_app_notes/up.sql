@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE repo_apps ADD COLUMN note VARCHAR(256) NOT NULL DEFAULT '';
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE repo_redirects;
+DROP TABLE app_redirects;
\ No newline at end of file
@@ -0,0 +1,18 @@
+-- Your SQL goes here
+CREATE TABLE app_redirects (
+    id BIGSERIAL PRIMARY KEY,
+    old_title VARCHAR NOT NULL,
+    app_id BIGINT NOT NULL REFERENCES apps(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX app_redirects_old_title_unique_idx on app_redirects (LOWER(old_title));
+
+CREATE TABLE repo_redirects (
+    id BIGSERIAL PRIMARY KEY,
+    old_title VARCHAR NOT NULL,
+    repo_id BIGINT NOT NULL REFERENCES repos(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX repo_redirects_old_title_unique_idx on repo_redirects (LOWER(old_title));
\ No newline at end of file
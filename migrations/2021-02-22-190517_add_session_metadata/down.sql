@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX sessions_logged_in_user_idx;
+
+ALTER TABLE sessions
+    DROP COLUMN created_at,
+    DROP COLUMN last_seen_at,
+    DROP COLUMN user_agent,
+    DROP COLUMN ip;
\ No newline at end of file
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+DELETE FROM sessions WHERE logged_in_user IS NULL;
+
+ALTER TABLE sessions
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    ADD COLUMN last_seen_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    ADD COLUMN user_agent VARCHAR(256) NOT NULL DEFAULT '',
+    ADD COLUMN ip VARCHAR(45);
+
+CREATE INDEX sessions_logged_in_user_idx on sessions (logged_in_user);
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX sessions_key_unique_idx;
+
+ALTER TABLE sessions DROP COLUMN key;
\ No newline at end of file
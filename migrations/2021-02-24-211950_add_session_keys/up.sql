@@ -0,0 +1,7 @@
+-- Your SQL goes here
+-- Existing cookies hold sequential ids, which are no longer accepted, so their sessions are useless.
+DELETE FROM sessions;
+
+ALTER TABLE sessions ADD COLUMN key VARCHAR(64) NOT NULL;
+
+CREATE UNIQUE INDEX sessions_key_unique_idx on sessions (key);
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE login_failures;
+
+DROP TABLE login_attempts;
\ No newline at end of file
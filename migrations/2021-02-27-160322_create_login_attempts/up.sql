@@ -0,0 +1,19 @@
+-- Your SQL goes here
+CREATE TABLE login_attempts (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    ip VARCHAR(45),
+    user_agent VARCHAR(256) NOT NULL DEFAULT '',
+    succeeded BOOLEAN NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX login_attempts_user_id_idx on login_attempts (user_id, created_at);
+
+CREATE TABLE login_failures (
+    id BIGSERIAL PRIMARY KEY,
+    key VARCHAR(300) NOT NULL,
+    failed_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX login_failures_key_idx on login_failures (key, failed_at);
\ No newline at end of file
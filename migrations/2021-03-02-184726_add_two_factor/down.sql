@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE recovery_codes;
+
+ALTER TABLE users
+    DROP COLUMN totp_secret,
+    DROP COLUMN totp_enabled_at,
+    DROP COLUMN totp_last_counter;
\ No newline at end of file
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+CREATE TABLE external_identities (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    issuer VARCHAR(256) NOT NULL,
+    subject VARCHAR(256) NOT NULL,
+    email VARCHAR(254),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX external_identities_issuer_subject_unique_idx on external_identities (issuer, subject);
+CREATE INDEX external_identities_user_id_idx on external_identities (user_id);
\ No newline at end of file
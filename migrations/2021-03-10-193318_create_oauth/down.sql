@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE oauth_access_tokens;
+DROP TABLE oauth_codes;
\ No newline at end of file
@@ -0,0 +1,30 @@
+-- Your SQL goes here
+CREATE TABLE oauth_codes (
+    id BIGSERIAL PRIMARY KEY,
+    app_id BIGINT NOT NULL REFERENCES apps(id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_hash CHAR(60) NOT NULL,
+    redirect_uri VARCHAR(2048) NOT NULL,
+    scopes VARCHAR[] NOT NULL DEFAULT '{}',
+    nonce VARCHAR(256),
+    code_challenge VARCHAR(128),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ
+);
+
+CREATE INDEX oauth_codes_app_id_idx on oauth_codes (app_id);
+CREATE INDEX oauth_codes_user_id_idx on oauth_codes (user_id);
+
+CREATE TABLE oauth_access_tokens (
+    id BIGSERIAL PRIMARY KEY,
+    app_id BIGINT NOT NULL REFERENCES apps(id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    token_hash CHAR(60) NOT NULL,
+    scopes VARCHAR[] NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX oauth_access_tokens_app_id_idx on oauth_access_tokens (app_id);
+CREATE INDEX oauth_access_tokens_user_id_idx on oauth_access_tokens (user_id);
\ No newline at end of file
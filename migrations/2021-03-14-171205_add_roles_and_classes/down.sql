@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE class_members;
+DROP TABLE classes;
+ALTER TABLE users DROP COLUMN role;
\ No newline at end of file
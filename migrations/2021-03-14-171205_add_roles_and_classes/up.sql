@@ -0,0 +1,22 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN role VARCHAR(16) NOT NULL DEFAULT 'student' CHECK (role IN ('student', 'teacher', 'admin'));
+
+CREATE TABLE classes (
+    id BIGSERIAL PRIMARY KEY,
+    owner_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    title VARCHAR(24) NOT NULL,
+    description VARCHAR(256) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX classes_title_unique_idx on classes (LOWER(title));
+CREATE INDEX classes_owner_id_idx on classes (owner_id);
+
+CREATE TABLE class_members (
+    class_id BIGINT NOT NULL REFERENCES classes(id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    joined_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (class_id, user_id)
+);
+
+CREATE INDEX class_members_user_id_idx on class_members (user_id);
\ No newline at end of file
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE repos DROP COLUMN hidden_at;
+ALTER TABLE apps DROP COLUMN hidden_at;
+
+DROP INDEX users_created_at_idx;
+ALTER TABLE users DROP COLUMN created_at;
+ALTER TABLE users DROP COLUMN suspended_at;
\ No newline at end of file
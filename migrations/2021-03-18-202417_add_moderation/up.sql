@@ -0,0 +1,7 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN suspended_at TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+CREATE INDEX users_created_at_idx on users (created_at);
+
+ALTER TABLE apps ADD COLUMN hidden_at TIMESTAMPTZ;
+ALTER TABLE repos ADD COLUMN hidden_at TIMESTAMPTZ;
\ No newline at end of file
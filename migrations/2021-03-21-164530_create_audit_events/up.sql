@@ -0,0 +1,27 @@
+-- Your SQL goes here
+-- actor_id and user_id aren't foreign keys, so events outlive the accounts they mention
+CREATE TABLE audit_events (
+    id BIGSERIAL PRIMARY KEY,
+    actor_id BIGINT,
+    actor VARCHAR(24) NOT NULL DEFAULT '',
+    user_id BIGINT,
+    action VARCHAR(32) NOT NULL,
+    target VARCHAR(256) NOT NULL DEFAULT '',
+    ip VARCHAR(45),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX audit_events_created_at_idx on audit_events (created_at);
+CREATE INDEX audit_events_user_id_idx on audit_events (user_id, created_at);
+CREATE INDEX audit_events_action_idx on audit_events (action, created_at);
+
+CREATE FUNCTION audit_events_append_only() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_events_no_change BEFORE UPDATE OR DELETE ON audit_events
+    FOR EACH ROW EXECUTE PROCEDURE audit_events_append_only();
+CREATE TRIGGER audit_events_no_truncate BEFORE TRUNCATE ON audit_events
+    FOR EACH STATEMENT EXECUTE PROCEDURE audit_events_append_only();
\ No newline at end of file
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE avatars;
+
+ALTER TABLE users DROP COLUMN bio;
+ALTER TABLE users DROP COLUMN display_name;
\ No newline at end of file
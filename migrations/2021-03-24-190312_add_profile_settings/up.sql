@@ -0,0 +1,9 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN display_name VARCHAR(48) NOT NULL DEFAULT '';
+ALTER TABLE users ADD COLUMN bio VARCHAR(2000) NOT NULL DEFAULT '';
+
+CREATE TABLE avatars (
+    user_id BIGINT PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    image BYTEA NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
\ No newline at end of file
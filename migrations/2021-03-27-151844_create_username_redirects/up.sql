@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE username_redirects (
+    id BIGSERIAL PRIMARY KEY,
+    old_username VARCHAR(24) NOT NULL,
+    user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE UNIQUE INDEX username_redirects_old_username_unique_idx on username_redirects (LOWER(old_username));
+CREATE INDEX username_redirects_user_id_idx on username_redirects (user_id, created_at);
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users DROP COLUMN indexed;
+ALTER TABLE users DROP COLUMN searchable;
+ALTER TABLE users DROP COLUMN email_visible;
\ No newline at end of file
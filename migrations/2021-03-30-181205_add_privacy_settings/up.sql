@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN email_visible BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE users ADD COLUMN searchable BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE users ADD COLUMN indexed BOOLEAN NOT NULL DEFAULT TRUE;
\ No newline at end of file
@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX users_username_trgm_idx;
+DROP INDEX repos_title_trgm_idx;
+DROP INDEX apps_title_trgm_idx;
+
+DROP INDEX users_search_idx;
+DROP INDEX repos_search_idx;
+DROP INDEX apps_search_idx;
+
+DROP EXTENSION pg_trgm;
\ No newline at end of file
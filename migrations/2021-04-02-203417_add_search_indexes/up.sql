@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+-- These have to match the expressions in `search.rs` exactly to be used
+CREATE INDEX apps_search_idx on apps USING GIN (to_tsvector('english', title || ' ' || description));
+CREATE INDEX repos_search_idx on repos USING GIN (to_tsvector('english', title || ' ' || description));
+CREATE INDEX users_search_idx on users USING GIN (to_tsvector('simple', username || ' ' || display_name || ' ' || bio));
+
+CREATE INDEX apps_title_trgm_idx on apps USING GIN (LOWER(title) gin_trgm_ops);
+CREATE INDEX repos_title_trgm_idx on repos USING GIN (LOWER(title) gin_trgm_ops);
+CREATE INDEX users_username_trgm_idx on users USING GIN (LOWER(username) gin_trgm_ops);
\ No newline at end of file
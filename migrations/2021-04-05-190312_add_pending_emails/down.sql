@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE email_verifications DROP COLUMN replaces_email;
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+-- Set when the verification is for changing the email, to the email the account had at the time.
+-- The account keeps that email until the link is opened.
+ALTER TABLE email_verifications ADD COLUMN replaces_email VARCHAR(254);
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM class_members WHERE accepted_at IS NULL;
+ALTER TABLE class_members DROP COLUMN accepted_at;
\ No newline at end of file
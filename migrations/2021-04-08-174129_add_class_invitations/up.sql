@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- Adding someone to a class only invites them, and they aren't a member until they accept.
+-- Everyone already in a class was added before that, so they count as having accepted.
+ALTER TABLE class_members ADD COLUMN accepted_at TIMESTAMPTZ;
+UPDATE class_members SET accepted_at = joined_at;
\ No newline at end of file
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+-- Someone deleting their account can offer their apps and repos to another user, and the account is only deleted once they accept.
+CREATE TABLE ownership_transfers (
+    sender_id BIGINT PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    recipient_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ownership_transfers_recipient_id_idx on ownership_transfers (recipient_id);
\ No newline at end of file
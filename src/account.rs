@@ -0,0 +1,728 @@
+use chrono::{
+    DateTime,
+    Utc,
+};
+
+use diesel::{
+    prelude::*,
+    PgConnection,
+};
+
+use rocket::{
+    http::{
+        Cookies,
+        Status,
+    },
+    request::{
+        LenientForm,
+        Request,
+    },
+    response::{
+        self,
+        Redirect,
+        Responder,
+        Response,
+        status,
+    },
+    State,
+    uri,
+};
+
+use rocket_contrib::{
+    json::Json,
+    templates::Template,
+};
+
+use serde::Serialize;
+
+use super::{
+    api,
+    apps,
+    audit,
+    avatars,
+    common::*,
+    crypt_eq::{
+        crypt,
+        CryptExpressionMethods,
+        gen_salt,
+    },
+    DbConn,
+    email_verifications,
+    login_throttle,
+    mail,
+    repos,
+    schema::{
+        self,
+        ownership_transfers,
+    },
+    sessions,
+    signed_in_context,
+    users,
+};
+
+use validator::validate_email;
+
+const MAX_DISPLAY_NAME_LENGTH: usize = 48;
+const MAX_BIO_LENGTH: usize = 2000;
+
+#[derive(Serialize)]
+pub struct ExportProfile {
+    pub id: i64,
+    pub username: String,
+    pub email: String,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    pub display_name: String,
+    pub bio: String,
+    pub role: String,
+    pub two_factor_enabled: bool,
+    pub email_visible: bool,
+    pub searchable: bool,
+    pub indexed: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct ExportSession {
+    pub user_agent: String,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct ExportAuditEvent {
+    pub actor: String,
+    pub action: String,
+    pub target: String,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Everything stored about a user that they're allowed to see. Never any password hashes, two-factor secrets or tokens.
+#[derive(Serialize)]
+pub struct Export {
+    pub exported_at: DateTime<Utc>,
+    pub profile: ExportProfile,
+    pub apps: Vec<apps::PublicApp>,
+    pub repos: Vec<api::ApiRepoWithApps>,
+    pub sessions: Vec<ExportSession>,
+    pub audit_events: Vec<ExportAuditEvent>,
+}
+
+impl<'r> Responder<'r> for Export {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let disposition = format!("attachment; filename=\"school-things-{}.json\"", self.profile.username);
+        Response::build_from(Json(self).respond_to(request)?)
+            .raw_header("Content-Disposition", disposition)
+            .raw_header("Cache-Control", "no-store")
+            .ok()
+    }
+}
+
+pub fn export(pg_conn: &PgConnection, user: &users::User) -> Result<Export, String> {
+    let mut repos = Vec::new();
+    for repo in repos::get_by_owner(pg_conn, user.id)? {
+        repos.push(api::repo_with_apps(pg_conn, &repo, true).map_err(|e| e.message)?);
+    }
+    Ok(Export {
+        exported_at: Utc::now(),
+        profile: ExportProfile {
+            id: user.id,
+            username: user.username.clone(),
+            email: user.email.clone(),
+            email_verified_at: user.email_verified_at,
+            display_name: user.display_name.clone(),
+            bio: user.bio.clone(),
+            role: user.role.clone(),
+            two_factor_enabled: user.totp_enabled_at.is_some(),
+            email_visible: user.email_visible,
+            searchable: user.searchable,
+            indexed: user.indexed,
+            created_at: user.created_at,
+        },
+        apps: apps::PublicApp::from_vec(&apps::get_by_owner(pg_conn, user.id)?),
+        repos,
+        sessions: sessions::get_by_user(pg_conn, user.id)?.into_iter().map(|session| ExportSession {
+            user_agent: session.user_agent,
+            ip: session.ip,
+            created_at: session.created_at,
+            last_seen_at: session.last_seen_at,
+        }).collect(),
+        audit_events: audit::get_all_by_user(pg_conn, user.id)?.into_iter().map(|event| ExportAuditEvent {
+            ip: event.own_ip(),
+            actor: event.actor,
+            action: event.action,
+            target: event.target,
+            created_at: event.created_at,
+        }).collect(),
+    })
+}
+
+/// What happens to the apps and repos of a deleted account.
+pub enum Owned<'a> {
+    Delete,
+    /// Only once the recipient has accepted, see `accept_transfer`.
+    Transfer(&'a users::User),
+}
+
+/// Deletes the user, and their apps and repos or hands them to someone else. Their classes are always deleted.
+pub fn delete(pg_conn: &PgConnection, user: &users::User, owned: &Owned) -> Result<(), String> {
+    let owned_apps = apps::get_by_owner(pg_conn, user.id)?;
+    let owned_repos = repos::get_by_owner(pg_conn, user.id)?;
+    let result = pg_conn.transaction::<_, diesel::result::Error, _>(|| {
+        match owned {
+            Owned::Delete => {
+                for app in &owned_apps {
+                    apps::delete(pg_conn, app).map_err(|_| diesel::result::Error::RollbackTransaction)?;
+                }
+                for repo in &owned_repos {
+                    repos::delete(pg_conn, repo).map_err(|_| diesel::result::Error::RollbackTransaction)?;
+                }
+            },
+            Owned::Transfer(recipient) => {
+                diesel::update(schema::apps::table.filter(schema::apps::owner_id.eq(user.id))).set(schema::apps::owner_id.eq(recipient.id)).execute(pg_conn)?;
+                diesel::update(schema::repos::table.filter(schema::repos::owner_id.eq(user.id))).set(schema::repos::owner_id.eq(recipient.id)).execute(pg_conn)?;
+            }
+        }
+        sessions::delete_by_user(pg_conn, user.id)?;
+        diesel::delete(schema::users::table.find(user.id)).execute(pg_conn)?;
+        Ok(())
+    });
+    result.map_err(|e| format!("Failed to delete user {}: {}", user.id, e))
+}
+
+/// Offers the user's apps and repos to `recipient`, replacing any earlier offer. Nothing changes hands until they accept.
+pub fn offer_transfer(pg_conn: &PgConnection, user: &users::User, recipient: &users::User) -> Result<(), String> {
+    match diesel::insert_into(ownership_transfers::table).values((
+        ownership_transfers::sender_id.eq(user.id),
+        ownership_transfers::recipient_id.eq(recipient.id),
+    )).on_conflict(ownership_transfers::sender_id).do_update().set((
+        ownership_transfers::recipient_id.eq(recipient.id),
+        ownership_transfers::created_at.eq(Utc::now()),
+    )).execute(pg_conn) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to offer transfer from user {} to user {}: {}", user.id, recipient.id, e))
+    }
+}
+
+/// Who the user has offered their apps and repos to, if anyone.
+pub fn get_offered_to(pg_conn: &PgConnection, user_id: i64) -> Result<Option<users::User>, String> {
+    match ownership_transfers::table
+        .inner_join(schema::users::table.on(schema::users::id.eq(ownership_transfers::recipient_id)))
+        .filter(ownership_transfers::sender_id.eq(user_id))
+        .select(schema::users::all_columns)
+        .first::<users::User>(pg_conn)
+        .optional() {
+        Ok(recipient) => Ok(recipient),
+        Err(e) => Err(format!("Failed to get transfer offered by user {}: {}", user_id, e))
+    }
+}
+
+/// Everyone offering their apps and repos to the user.
+pub fn get_offers(pg_conn: &PgConnection, user_id: i64) -> Result<Vec<users::User>, String> {
+    match ownership_transfers::table
+        .inner_join(schema::users::table.on(schema::users::id.eq(ownership_transfers::sender_id)))
+        .filter(ownership_transfers::recipient_id.eq(user_id))
+        .order(ownership_transfers::created_at.asc())
+        .select(schema::users::all_columns)
+        .load::<users::User>(pg_conn) {
+        Ok(senders) => Ok(senders),
+        Err(e) => Err(format!("Failed to get transfers offered to user {}: {}", user_id, e))
+    }
+}
+
+/// Returns whether the user had an offer to take back.
+pub fn cancel_transfer(pg_conn: &PgConnection, user_id: i64) -> Result<bool, String> {
+    match diesel::delete(ownership_transfers::table.find(user_id)).execute(pg_conn) {
+        Ok(cancelled) => Ok(cancelled > 0),
+        Err(e) => Err(format!("Failed to cancel transfer offered by user {}: {}", user_id, e))
+    }
+}
+
+/// Returns whether `sender` had offered anything to `recipient`.
+pub fn decline_transfer(pg_conn: &PgConnection, sender: &users::User, recipient: &users::User) -> Result<bool, String> {
+    match diesel::delete(ownership_transfers::table.find(sender.id).filter(ownership_transfers::recipient_id.eq(recipient.id))).execute(pg_conn) {
+        Ok(declined) => Ok(declined > 0),
+        Err(e) => Err(format!("Failed to decline transfer from user {} to user {}: {}", sender.id, recipient.id, e))
+    }
+}
+
+/// Hands everything `sender` owns to `recipient` and deletes the sender's account. Returns whether there was an offer to accept.
+pub fn accept_transfer(pg_conn: &PgConnection, sender: &users::User, recipient: &users::User) -> Result<bool, String> {
+    let result = pg_conn.transaction::<_, diesel::result::Error, _>(|| {
+        let offered = diesel::delete(ownership_transfers::table.find(sender.id).filter(ownership_transfers::recipient_id.eq(recipient.id))).execute(pg_conn)?;
+        if offered == 0 {
+            return Ok(false)
+        }
+        delete(pg_conn, sender, &Owned::Transfer(recipient)).map_err(|_| diesel::result::Error::RollbackTransaction)?;
+        Ok(true)
+    });
+    result.map_err(|e| format!("Failed to accept transfer from user {} to user {}: {}", sender.id, recipient.id, e))
+}
+
+#[derive(FromForm)]
+pub struct ProfileForm {
+    pub display_name: String,
+    pub bio: String,
+}
+
+/// Unchecked boxes aren't sent at all, so each missing field means false.
+#[derive(FromForm)]
+pub struct PrivacyForm {
+    pub email_visible: bool,
+    pub searchable: bool,
+    pub indexed: bool,
+}
+
+#[derive(FromForm)]
+pub struct UsernameForm {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(FromForm)]
+pub struct EmailForm {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(FromForm)]
+pub struct PasswordForm {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(FromForm)]
+pub struct DeleteAccountForm {
+    pub username: String,
+    pub password: String,
+    pub code: Option<String>,
+    /// `delete` or `transfer`.
+    pub owned: String,
+    pub transfer_to: String,
+}
+
+pub fn validate_profile(display_name: &str, bio: &str) -> Result<(), &'static str> {
+    if display_name.chars().count() > MAX_DISPLAY_NAME_LENGTH {return Err("Display name is too long - max 48 characters")}
+    if display_name.chars().any(char::is_control) {return Err("Display name can't contain control characters")}
+    if bio.chars().count() > MAX_BIO_LENGTH {return Err("Bio is too long - max 2000 characters")}
+    Ok(())
+}
+
+fn signed_in_user(pg_conn: &PgConnection, cookies: &mut Cookies) -> Result<users::User, status::Custom<&'static str>> {
+    sessions::get_from_cookie_jar(pg_conn, cookies)
+        .and_then(|session| users::get_from_session(pg_conn, &session))
+        .map_err(|_| status::Custom(Status::Forbidden, "Must be signed in"))
+}
+
+/// Counts towards the login throttle like signing in does, so a stolen session can't be used to guess the password.
+fn check_password(pg_conn: &PgConnection, throttle: &login_throttle::Throttle, client_info: &sessions::ClientInfo, user: &users::User, password: &str) -> Result<(), status::Custom<&'static str>> {
+    if let Err(e) = throttle.check(pg_conn, &user.username, client_info) {
+        eprintln!("Refused password check for user {}: {}", user.id, e);
+        return Err(status::Custom(Status::TooManyRequests, "Too many wrong passwords, try again later"))
+    }
+    match schema::users::table.find(user.id).filter(schema::users::password_hash.crypt_eq(&password.to_string())).select(schema::users::id).first::<i64>(pg_conn) {
+        Ok(_) => {
+            throttle.succeeded(pg_conn, &user.username, client_info);
+            Ok(())
+        },
+        Err(_) => {
+            throttle.failed(pg_conn, &user.username, client_info);
+            Err(status::Custom(Status::Forbidden, "Your current password is wrong"))
+        }
+    }
+}
+
+#[get("/settings")]
+pub fn settings(db_conn: DbConn, cookies: Cookies) -> Template {
+    let (mut context, _, user) = signed_in_context(&*db_conn, cookies);
+    if let Some(user) = user {
+        context.insert("bio", &Cleaned::new(&user.bio));
+        context.insert("bio_html", &render_markdown(&user.bio));
+        match avatars::exists(&*db_conn, user.id) {
+            Ok(has_avatar) => context.insert("has_avatar", &has_avatar),
+            Err(e) => eprintln!("{}", e)
+        }
+        match email_verifications::get_pending_change(&*db_conn, &user) {
+            Ok(Some(pending)) => context.insert("pending_email", &Cleaned::new(&pending.email)),
+            Ok(None) => {},
+            Err(e) => eprintln!("{}", e)
+        }
+        match get_offers(&*db_conn, user.id) {
+            Ok(senders) => context.insert("transfer_offers", &senders.iter().map(|sender| Cleaned::new(&sender.username)).collect::<Vec<Cleaned>>()),
+            Err(e) => eprintln!("{}", e)
+        }
+    }
+    Template::render("settings", &context)
+}
+
+#[post("/settings/profile", data = "<profile_form>")]
+pub fn submit_profile(profile_form: LenientForm<ProfileForm>, db_conn: DbConn, mut cookies: Cookies) -> Result<Redirect, status::Custom<&'static str>> {
+    let user = signed_in_user(&*db_conn, &mut cookies)?;
+    let display_name = profile_form.display_name.trim();
+    validate_profile(display_name, &profile_form.bio).map_err(|e| status::Custom(Status::BadRequest, e))?;
+    match diesel::update(schema::users::table.find(user.id)).set((
+        schema::users::display_name.eq(display_name),
+        schema::users::bio.eq(&profile_form.bio),
+    )).execute(&*db_conn) {
+        Ok(_) => Ok(Redirect::to(uri!(settings))),
+        Err(e) => {
+            eprintln!("Failed to update profile of user {}: {}", user.id, e);
+            Err(status::Custom(Status::InternalServerError, "Failed to update profile"))
+        }
+    }
+}
+
+#[post("/settings/privacy", data = "<privacy_form>")]
+pub fn submit_privacy(privacy_form: LenientForm<PrivacyForm>, db_conn: DbConn, mut cookies: Cookies) -> Result<Redirect, status::Custom<&'static str>> {
+    let user = signed_in_user(&*db_conn, &mut cookies)?;
+    match diesel::update(schema::users::table.find(user.id)).set((
+        schema::users::email_visible.eq(privacy_form.email_visible),
+        schema::users::searchable.eq(privacy_form.searchable),
+        schema::users::indexed.eq(privacy_form.indexed),
+    )).execute(&*db_conn) {
+        Ok(_) => Ok(Redirect::to(uri!(settings))),
+        Err(e) => {
+            eprintln!("Failed to update privacy settings of user {}: {}", user.id, e);
+            Err(status::Custom(Status::InternalServerError, "Failed to update privacy settings"))
+        }
+    }
+}
+
+/// The old username keeps redirecting here for a while, and nobody else can sign up with it until then.
+#[post("/settings/username", data = "<username_form>")]
+pub fn submit_username(username_form: LenientForm<UsernameForm>, db_conn: DbConn, mut cookies: Cookies, client_info: sessions::ClientInfo, throttle: State<login_throttle::Throttle>) -> Result<Redirect, status::Custom<&'static str>> {
+    let user = signed_in_user(&*db_conn, &mut cookies)?;
+    check_password(&*db_conn, &throttle, &client_info, &user, &username_form.password)?;
+    let renamed = users::rename(&*db_conn, &user, username_form.username.trim())?;
+    audit::record(&*db_conn, Some(&renamed), Some(user.id), audit::Action::UsernameChanged, &format!("{} to {}", user.username, renamed.username), &client_info);
+    Ok(Redirect::to(uri!(settings)))
+}
+
+/// The email only changes once the link sent to the new one is opened, so nobody can take an address they don't own.
+#[post("/settings/email", data = "<email_form>")]
+pub fn submit_email(email_form: LenientForm<EmailForm>, db_conn: DbConn, mut cookies: Cookies, mail: State<mail::Mail>, client_info: sessions::ClientInfo, throttle: State<login_throttle::Throttle>) -> Result<Redirect, status::Custom<&'static str>> {
+    let user = signed_in_user(&*db_conn, &mut cookies)?;
+    check_password(&*db_conn, &throttle, &client_info, &user, &email_form.password)?;
+    let email = email_form.email.trim();
+    if !validate_email(email) {
+        return Err(status::Custom(Status::BadRequest, "Invalid email"))
+    }
+    if email == user.email {
+        return Err(status::Custom(Status::BadRequest, "That's already your email"))
+    }
+    match schema::users::table.filter(lower(schema::users::email).eq(email.to_lowercase())).count().get_result::<i64>(&*db_conn) {
+        Ok(0) => {},
+        Ok(_) => return Err(status::Custom(Status::BadRequest, "Duplicate email")),
+        Err(e) => {
+            eprintln!("Failed to check email: {}", e);
+            return Err(status::Custom(Status::InternalServerError, "Failed to change email"))
+        }
+    }
+
+    match email_verifications::send(&*db_conn, &mail, user.id, email, Some(&user.email)) {
+        Ok(_) => {},
+        Err(e) => {
+            eprintln!("{}", e);
+            return Err(status::Custom(Status::InternalServerError, "Failed to send verification email"))
+        }
+    }
+    // Lets the owner of the current address know, in case someone else asked for the change
+    match mail.send(&user.email, "Your School Things email is changing", format!(
+        "Someone asked to change the email on your School Things account {} to {}. It will change once the link sent there is opened.\n\nIf you didn't do this, reset your password and contact an admin.",
+        user.username,
+        email,
+    )) {
+        Ok(_) => {},
+        Err(e) => eprintln!("{}", e)
+    }
+    Ok(Redirect::to(uri!(settings)))
+}
+
+/// Changing the password signs out every other session, and swaps this one for a fresh one.
+#[post("/settings/password", data = "<password_form>")]
+pub fn submit_password(password_form: LenientForm<PasswordForm>, db_conn: DbConn, mut cookies: Cookies, client_info: sessions::ClientInfo, throttle: State<login_throttle::Throttle>) -> Result<Redirect, status::Custom<&'static str>> {
+    let user = signed_in_user(&*db_conn, &mut cookies)?;
+    check_password(&*db_conn, &throttle, &client_info, &user, &password_form.current_password)?;
+    if password_form.new_password.is_empty() || password_form.new_password.len() > 72 {
+        return Err(status::Custom(Status::BadRequest, "Invalid password"))
+    }
+
+    let result = db_conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::update(schema::users::table.find(user.id))
+            .set(schema::users::password_hash.eq(crypt(password_form.new_password.as_str(), gen_salt("bf"))))
+            .execute(&*db_conn)?;
+        sessions::delete_by_user(&*db_conn, user.id)
+    });
+    match result {
+        Ok(_) => {},
+        Err(e) => {
+            eprintln!("Failed to change password of user {}: {}", user.id, e);
+            return Err(status::Custom(Status::InternalServerError, "Failed to change password"))
+        }
+    }
+    audit::record(&*db_conn, Some(&user), Some(user.id), audit::Action::PasswordChanged, "", &client_info);
+
+    match sessions::start(&*db_conn, &mut cookies, user.id, &client_info) {
+        Ok(_) => Ok(Redirect::to(uri!(settings))),
+        Err(e) => {
+            eprintln!("{}", e);
+            Ok(Redirect::to(uri!(users::login: "Your password changed, please sign in again".to_string(), user.username)))
+        }
+    }
+}
+
+#[get("/exportData")]
+pub fn export_data(db_conn: DbConn, cookies: Cookies) -> Result<Export, status::Custom<&'static str>> {
+    let user = users::get_from_cookies(&*db_conn, cookies).map_err(|_| status::Custom(Status::Forbidden, "Must be signed in"))?;
+    export(&*db_conn, &user).map_err(|e| {
+        eprintln!("{}", e);
+        status::Custom(Status::InternalServerError, "Failed to export your data")
+    })
+}
+
+#[get("/deleteAccount")]
+pub fn delete_account(db_conn: DbConn, cookies: Cookies) -> Template {
+    let (mut context, _, user) = signed_in_context(&*db_conn, cookies);
+    if let Some(user) = user {
+        match (apps::get_by_owner(&*db_conn, user.id), repos::get_by_owner(&*db_conn, user.id)) {
+            (Ok(owned_apps), Ok(owned_repos)) => {
+                context.insert("owned_apps", &owned_apps.len());
+                context.insert("owned_repos", &owned_repos.len());
+            },
+            (Err(e), _) | (_, Err(e)) => eprintln!("{}", e)
+        }
+        match get_offered_to(&*db_conn, user.id) {
+            Ok(Some(recipient)) => context.insert("offered_to", &Cleaned::new(&recipient.username)),
+            Ok(None) => {},
+            Err(e) => eprintln!("{}", e)
+        }
+    }
+    Template::render("delete_account", &context)
+}
+
+#[post("/deleteAccount", data = "<delete_form>")]
+pub fn submit_delete_account(delete_form: LenientForm<DeleteAccountForm>, db_conn: DbConn, mut cookies: Cookies, client_info: sessions::ClientInfo, throttle: State<login_throttle::Throttle>) -> Result<Redirect, status::Custom<String>> {
+    let signed_in = signed_in_user(&*db_conn, &mut cookies).map_err(|e| status::Custom(e.0, e.1.to_string()))?;
+    let delete_form = delete_form.into_inner();
+    let user = users::confirm(&*db_conn, &throttle, &client_info, &users::ConfirmUser {
+        username: delete_form.username,
+        password: delete_form.password,
+        code: delete_form.code,
+    }).map_err(|e| status::Custom(Status::Forbidden, e))?;
+    if user.id != signed_in.id {
+        return Err(status::Custom(Status::Forbidden, "You can only delete your own account".to_string()))
+    }
+
+    match &*delete_form.owned {
+        "delete" => {
+            match delete(&*db_conn, &user, &Owned::Delete) {
+                Ok(_) => {
+                    audit::record(&*db_conn, Some(&user), Some(user.id), audit::Action::DeleteAccount, "apps and repos deleted", &client_info);
+                    match sessions::end(&*db_conn, &mut cookies) {
+                        Ok(_) => {},
+                        Err(e) => eprintln!("{}", e)
+                    }
+                    Ok(Redirect::to(uri!(super::home)))
+                },
+                Err(e) => {
+                    eprintln!("{}", e);
+                    Err(status::Custom(Status::InternalServerError, "Failed to delete account".to_string()))
+                }
+            }
+        },
+        "transfer" => {
+            let recipient = users::get_by_username(&*db_conn, delete_form.transfer_to).map_err(|_| status::Custom(Status::NotFound, "Couldn't find who to transfer to".to_string()))?;
+            recipient.check_active().map_err(|_| status::Custom(Status::BadRequest, "Can't transfer to a suspended user".to_string()))?;
+            if recipient.id == user.id {
+                return Err(status::Custom(Status::BadRequest, "Transfer to someone else".to_string()))
+            }
+            match offer_transfer(&*db_conn, &user, &recipient) {
+                Ok(_) => Ok(Redirect::to(uri!(delete_account))),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    Err(status::Custom(Status::InternalServerError, "Failed to offer transfer".to_string()))
+                }
+            }
+        },
+        _ => Err(status::Custom(Status::BadRequest, "Choose what happens to your apps and repos".to_string()))
+    }
+}
+
+#[post("/deleteAccount/cancelTransfer")]
+pub fn cancel_transfer_offer(db_conn: DbConn, mut cookies: Cookies) -> Result<Redirect, status::Custom<&'static str>> {
+    let user = signed_in_user(&*db_conn, &mut cookies)?;
+    match cancel_transfer(&*db_conn, user.id) {
+        Ok(_) => Ok(Redirect::to(uri!(delete_account))),
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(status::Custom(Status::InternalServerError, "Failed to cancel transfer"))
+        }
+    }
+}
+
+/// Accepting deletes the sender's account, which they already confirmed with their password when they offered.
+#[post("/transfers/<username>/accept")]
+pub fn accept_transfer_offer(username: String, db_conn: DbConn, mut cookies: Cookies, client_info: sessions::ClientInfo) -> Result<Redirect, status::Custom<&'static str>> {
+    let user = signed_in_user(&*db_conn, &mut cookies)?;
+    let sender = users::get_by_username(&*db_conn, username).map_err(|_| status::Custom(Status::NotFound, "Transfer not found"))?;
+    match accept_transfer(&*db_conn, &sender, &user) {
+        Ok(true) => {
+            audit::record(&*db_conn, Some(&user), Some(sender.id), audit::Action::DeleteAccount, &format!("apps and repos transferred to {}", user.username), &client_info);
+            audit::record(&*db_conn, Some(&user), Some(user.id), audit::Action::ReceiveOwned, &sender.username, &client_info);
+            Ok(Redirect::to(uri!(settings)))
+        },
+        Ok(false) => Err(status::Custom(Status::NotFound, "Transfer not found")),
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(status::Custom(Status::InternalServerError, "Failed to accept transfer"))
+        }
+    }
+}
+
+#[post("/transfers/<username>/decline")]
+pub fn decline_transfer_offer(username: String, db_conn: DbConn, mut cookies: Cookies) -> Result<Redirect, status::Custom<&'static str>> {
+    let user = signed_in_user(&*db_conn, &mut cookies)?;
+    let sender = users::get_by_username(&*db_conn, username).map_err(|_| status::Custom(Status::NotFound, "Transfer not found"))?;
+    match decline_transfer(&*db_conn, &sender, &user) {
+        Ok(true) => Ok(Redirect::to(uri!(settings))),
+        Ok(false) => Err(status::Custom(Status::NotFound, "Transfer not found")),
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(status::Custom(Status::InternalServerError, "Failed to decline transfer"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api_tokens,
+        connections,
+        test_db,
+    };
+
+    #[test]
+    fn password_checks_are_throttled() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "guessed");
+        let throttle = login_throttle::Throttle::new(Box::new(login_throttle::MemoryLimiter::new()));
+        let client_info = test_db::client_info("192.0.2.1");
+
+        assert!(check_password(&pg_conn, &throttle, &client_info, &user, "password").is_ok());
+        for _ in 0..5 {
+            assert_eq!(check_password(&pg_conn, &throttle, &client_info, &user, "wrong").map_err(|e| e.0), Err(Status::Forbidden));
+        }
+        // Even the right password is refused until the delay is up
+        assert_eq!(check_password(&pg_conn, &throttle, &client_info, &user, "password").map_err(|e| e.0), Err(Status::TooManyRequests));
+    }
+    fn add_owned(pg_conn: &PgConnection, owner: &users::User) -> (apps::App, String, repos::Repo) {
+        let (app, token) = apps::insert(pg_conn, &apps::NewApp {
+            owner_id: owner.id,
+            title: format!("{}_app", owner.username),
+            description: String::new(),
+            domain: "https://app.example.com".to_string(),
+        }, &connections::DomainRules {
+            insecure_hosts: Vec::new(),
+        }).map_err(|e| e.1).unwrap();
+        let repo = repos::insert(pg_conn, &repos::NewRepo {
+            owner_id: owner.id,
+            title: format!("{}_repo", owner.username),
+            description: String::new(),
+        }).map_err(|e| e.1).unwrap();
+        (app, token, repo)
+    }
+
+    #[test]
+    fn exports_leave_out_secrets() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "exported");
+        diesel::update(schema::users::table.find(user.id)).set(schema::users::totp_secret.eq("JBSWY3DPEHPK3PXP")).execute(&pg_conn).unwrap();
+        let user = users::get(&pg_conn, user.id).unwrap();
+        let (app, app_token, repo) = add_owned(&pg_conn, &user);
+        let (_, api_token) = api_tokens::create(&pg_conn, user.id, "export", &vec!["read".to_string()], None).unwrap();
+
+        let exported = export(&pg_conn, &user).unwrap();
+        assert_eq!(exported.profile.username, "exported");
+        assert_eq!(exported.apps.len(), 1);
+        assert_eq!(exported.repos.len(), 1);
+        let json = serde_json::to_string(&exported).unwrap();
+        assert!(json.contains(&app.title));
+        assert!(json.contains(&repo.title));
+        for secret in &[&user.password_hash, user.totp_secret.as_ref().unwrap(), &app.token_hash, &app_token, &api_token] {
+            assert!(!json.contains(secret.as_str()));
+        }
+        assert!(!json.contains("password_hash"));
+        assert!(!json.contains("totp_secret"));
+    }
+
+    #[test]
+    fn deleting_takes_apps_and_repos_with_it() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "leaving");
+        let (app, _, repo) = add_owned(&pg_conn, &user);
+
+        delete(&pg_conn, &user, &Owned::Delete).unwrap();
+        assert!(users::get(&pg_conn, user.id).is_err());
+        assert!(apps::get_by_title(&pg_conn, &app.title).is_err());
+        assert!(repos::get_by_title(&pg_conn, &repo.title).is_err());
+    }
+
+    #[test]
+    fn transfers_wait_for_the_recipient() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let sender = test_db::create_user(&pg_conn, "giving");
+        let recipient = test_db::create_user(&pg_conn, "taking");
+        let someone_else = test_db::create_user(&pg_conn, "bystander");
+        let (app, _, repo) = add_owned(&pg_conn, &sender);
+
+        offer_transfer(&pg_conn, &sender, &recipient).unwrap();
+        assert_eq!(get_offered_to(&pg_conn, sender.id).unwrap().map(|offered_to| offered_to.id), Some(recipient.id));
+        assert_eq!(get_offers(&pg_conn, recipient.id).unwrap().len(), 1);
+        // Nothing has changed hands yet
+        assert!(users::get(&pg_conn, sender.id).is_ok());
+        assert_eq!(apps::get_by_title(&pg_conn, &app.title).unwrap().owner_id, sender.id);
+
+        assert!(!accept_transfer(&pg_conn, &sender, &someone_else).unwrap());
+        assert!(!decline_transfer(&pg_conn, &sender, &someone_else).unwrap());
+        assert!(decline_transfer(&pg_conn, &sender, &recipient).unwrap());
+        assert!(!accept_transfer(&pg_conn, &sender, &recipient).unwrap());
+        assert!(users::get(&pg_conn, sender.id).is_ok());
+
+        offer_transfer(&pg_conn, &sender, &recipient).unwrap();
+        assert!(accept_transfer(&pg_conn, &sender, &recipient).unwrap());
+        assert!(users::get(&pg_conn, sender.id).is_err());
+        assert_eq!(apps::get_by_title(&pg_conn, &app.title).unwrap().owner_id, recipient.id);
+        assert_eq!(repos::get_by_title(&pg_conn, &repo.title).unwrap().owner_id, recipient.id);
+        assert!(get_offers(&pg_conn, recipient.id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn cancelled_transfers_cant_be_accepted() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let sender = test_db::create_user(&pg_conn, "undecided");
+        let recipient = test_db::create_user(&pg_conn, "hopeful");
+
+        offer_transfer(&pg_conn, &sender, &recipient).unwrap();
+        assert!(cancel_transfer(&pg_conn, sender.id).unwrap());
+        assert!(get_offered_to(&pg_conn, sender.id).unwrap().is_none());
+        assert!(!accept_transfer(&pg_conn, &sender, &recipient).unwrap());
+        assert!(users::get(&pg_conn, sender.id).is_ok());
+    }
+}
\ No newline at end of file
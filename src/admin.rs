@@ -0,0 +1,417 @@
+use diesel::PgConnection;
+
+use rocket::{
+    http::{
+        Cookies,
+        Status,
+    },
+    response::{
+        Redirect,
+        status,
+    },
+    State,
+    uri,
+};
+
+use rocket_contrib::templates::Template;
+use serde::Serialize;
+
+use super::{
+    apps,
+    audit,
+    common::*,
+    connections,
+    DbConn,
+    policy::{
+        Admin,
+        Role,
+    },
+    repos,
+    sessions::ClientInfo,
+    signed_in_context,
+    users,
+};
+
+const RECENT_SIGNUPS: i64 = 10;
+
+#[derive(Serialize)]
+pub struct AdminUserRow {
+    pub id: i64,
+    pub username: Cleaned,
+    pub email: Cleaned,
+    pub role: String,
+    pub suspended: bool,
+    pub created_at: String,
+}
+
+impl AdminUserRow {
+    pub fn from_user(user: &users::User) -> AdminUserRow {
+        AdminUserRow {
+            id: user.id,
+            username: Cleaned::new(&user.username),
+            email: Cleaned::new(&user.email),
+            role: user.role.clone(),
+            suspended: user.suspended_at.is_some(),
+            created_at: user.created_at.format("%Y-%m-%d %H:%M").to_string(),
+        }
+    }
+
+    pub fn from_vec(users: &[users::User]) -> Vec<AdminUserRow> {
+        users.iter().map(AdminUserRow::from_user).collect()
+    }
+}
+
+#[derive(Serialize)]
+pub struct AdminAppRow {
+    pub id: i64,
+    pub title: Cleaned,
+    pub domain: Cleaned,
+    pub connected: bool,
+    pub connected_error: Cleaned,
+    pub hidden: bool,
+}
+
+impl AdminAppRow {
+    pub fn from_app(app: &apps::App) -> AdminAppRow {
+        AdminAppRow {
+            id: app.id,
+            title: Cleaned::new(&app.title),
+            domain: Cleaned::new(&app.domain),
+            connected: app.connected,
+            connected_error: Cleaned::new(&app.connected_error),
+            hidden: app.hidden_at.is_some(),
+        }
+    }
+
+    pub fn from_vec(apps: &[apps::App]) -> Vec<AdminAppRow> {
+        apps.iter().map(AdminAppRow::from_app).collect()
+    }
+}
+
+#[derive(Serialize)]
+pub struct AdminRepoRow {
+    pub id: i64,
+    pub title: Cleaned,
+    pub description: Cleaned,
+    pub hidden: bool,
+}
+
+impl AdminRepoRow {
+    pub fn from_repo(repo: &repos::Repo) -> AdminRepoRow {
+        AdminRepoRow {
+            id: repo.id,
+            title: Cleaned::new(&repo.title),
+            description: Cleaned::new(&repo.description),
+            hidden: repo.hidden_at.is_some(),
+        }
+    }
+
+    pub fn from_vec(repos: &[repos::Repo]) -> Vec<AdminRepoRow> {
+        repos.iter().map(AdminRepoRow::from_repo).collect()
+    }
+}
+
+fn internal_error(e: String, message: &'static str) -> status::Custom<&'static str> {
+    eprintln!("{}", e);
+    status::Custom(Status::InternalServerError, message)
+}
+
+fn find_user(pg_conn: &PgConnection, user_id: i64) -> Result<users::User, status::Custom<&'static str>> {
+    users::get(pg_conn, user_id).map_err(|_| status::Custom(Status::NotFound, "User not found"))
+}
+
+fn find_app(pg_conn: &PgConnection, app_id: i64) -> Result<apps::App, status::Custom<&'static str>> {
+    apps::get(pg_conn, app_id).map_err(|_| status::Custom(Status::NotFound, "App not found"))
+}
+
+fn find_repo(pg_conn: &PgConnection, repo_id: i64) -> Result<repos::Repo, status::Custom<&'static str>> {
+    repos::get(pg_conn, repo_id).map_err(|_| status::Custom(Status::NotFound, "Repo not found"))
+}
+
+#[get("/admin")]
+pub fn dashboard(_admin: Admin, db_conn: DbConn, cookies: Cookies) -> Template {
+    let (mut context, _, _) = signed_in_context(&*db_conn, cookies);
+    match users::get_recent(&*db_conn, RECENT_SIGNUPS) {
+        Ok(users) => context.insert("recent_signups", &AdminUserRow::from_vec(&users)),
+        Err(e) => eprintln!("{}", e)
+    }
+    Template::render("admin", &context)
+}
+
+#[get("/admin/users?<search>&<page>")]
+pub fn admin_users(search: Option<String>, page: Option<i64>, _admin: Admin, db_conn: DbConn, cookies: Cookies) -> Template {
+    let (mut context, _, _) = signed_in_context(&*db_conn, cookies);
+    let search = search.unwrap_or_default();
+    let page = page_number(page);
+    match users::get_page(&*db_conn, &search, page) {
+        Ok((users, total)) => {
+            context.insert("rows", &AdminUserRow::from_vec(&users));
+            context.insert("pagination", &Pagination::new(page, total, &search));
+        },
+        Err(e) => eprintln!("{}", e)
+    }
+    Template::render("admin_users", &context)
+}
+
+#[get("/admin/apps?<search>&<page>")]
+pub fn admin_apps(search: Option<String>, page: Option<i64>, _admin: Admin, db_conn: DbConn, cookies: Cookies) -> Template {
+    let (mut context, _, _) = signed_in_context(&*db_conn, cookies);
+    let search = search.unwrap_or_default();
+    let page = page_number(page);
+    match apps::get_page(&*db_conn, &search, page) {
+        Ok((apps, total)) => {
+            context.insert("rows", &AdminAppRow::from_vec(&apps));
+            context.insert("pagination", &Pagination::new(page, total, &search));
+        },
+        Err(e) => eprintln!("{}", e)
+    }
+    Template::render("admin_apps", &context)
+}
+
+#[get("/admin/repos?<search>&<page>")]
+pub fn admin_repos(search: Option<String>, page: Option<i64>, _admin: Admin, db_conn: DbConn, cookies: Cookies) -> Template {
+    let (mut context, _, _) = signed_in_context(&*db_conn, cookies);
+    let search = search.unwrap_or_default();
+    let page = page_number(page);
+    match repos::get_page(&*db_conn, &search, page) {
+        Ok((repos, total)) => {
+            context.insert("rows", &AdminRepoRow::from_vec(&repos));
+            context.insert("pagination", &Pagination::new(page, total, &search));
+        },
+        Err(e) => eprintln!("{}", e)
+    }
+    Template::render("admin_repos", &context)
+}
+
+#[get("/admin/audit?<action>&<actor>&<page>")]
+pub fn admin_audit(action: Option<String>, actor: Option<String>, page: Option<i64>, _admin: Admin, db_conn: DbConn, cookies: Cookies) -> Template {
+    let (mut context, _, _) = signed_in_context(&*db_conn, cookies);
+    let action = action.and_then(|action| action.parse::<audit::Action>().ok());
+    let actor = actor.unwrap_or_default();
+    let page = page_number(page);
+    match audit::get_page(&*db_conn, action, &actor, page) {
+        Ok((events, total)) => {
+            context.insert("events", &audit::CleanAuditEvent::from_vec(&events));
+            context.insert("pagination", &Pagination::new(page, total, &actor));
+        },
+        Err(e) => eprintln!("{}", e)
+    }
+    context.insert("actions", &audit::Action::ALL.iter().map(|action| action.as_str()).collect::<Vec<&str>>());
+    context.insert("action", &action.map_or("", |action| action.as_str()));
+    Template::render("admin_audit", &context)
+}
+
+#[post("/admin/users/<user_id>/suspend")]
+pub fn suspend_user(user_id: i64, admin: Admin, db_conn: DbConn, client_info: ClientInfo) -> Result<Redirect, status::Custom<&'static str>> {
+    if user_id == admin.0.id {
+        return Err(status::Custom(Status::BadRequest, "You can't suspend yourself"))
+    }
+    let user = find_user(&*db_conn, user_id)?;
+    if user.role() == Role::Admin {
+        return Err(status::Custom(Status::BadRequest, "Admins can't be suspended, change their role first"))
+    }
+    match users::set_suspended(&*db_conn, user.id, true) {
+        Ok(_) => {
+            audit::record(&*db_conn, Some(&admin.0), Some(user.id), audit::Action::SuspendUser, &user.username, &client_info);
+            Ok(Redirect::to(uri!(admin_users: String::new(), 0)))
+        },
+        Err(e) => Err(internal_error(e, "Failed to suspend user"))
+    }
+}
+
+#[post("/admin/users/<user_id>/unsuspend")]
+pub fn unsuspend_user(user_id: i64, admin: Admin, db_conn: DbConn, client_info: ClientInfo) -> Result<Redirect, status::Custom<&'static str>> {
+    let user = find_user(&*db_conn, user_id)?;
+    match users::set_suspended(&*db_conn, user.id, false) {
+        Ok(_) => {
+            audit::record(&*db_conn, Some(&admin.0), Some(user.id), audit::Action::UnsuspendUser, &user.username, &client_info);
+            Ok(Redirect::to(uri!(admin_users: String::new(), 0)))
+        },
+        Err(e) => Err(internal_error(e, "Failed to unsuspend user"))
+    }
+}
+
+fn set_app_hidden(pg_conn: &PgConnection, app_id: i64, hidden: bool, admin: &users::User, client_info: &ClientInfo) -> Result<Redirect, status::Custom<&'static str>> {
+    let app = find_app(pg_conn, app_id)?;
+    match apps::set_hidden(pg_conn, app.id, hidden) {
+        Ok(_) => {
+            let action = if hidden { audit::Action::HideApp } else { audit::Action::UnhideApp };
+            audit::record(pg_conn, Some(admin), Some(app.owner_id), action, &format!("apps/{}", app.title), client_info);
+            Ok(Redirect::to(uri!(admin_apps: String::new(), 0)))
+        },
+        Err(e) => Err(internal_error(e, "Failed to update app"))
+    }
+}
+
+#[post("/admin/apps/<app_id>/hide")]
+pub fn hide_app(app_id: i64, admin: Admin, db_conn: DbConn, client_info: ClientInfo) -> Result<Redirect, status::Custom<&'static str>> {
+    set_app_hidden(&*db_conn, app_id, true, &admin.0, &client_info)
+}
+
+#[post("/admin/apps/<app_id>/unhide")]
+pub fn unhide_app(app_id: i64, admin: Admin, db_conn: DbConn, client_info: ClientInfo) -> Result<Redirect, status::Custom<&'static str>> {
+    set_app_hidden(&*db_conn, app_id, false, &admin.0, &client_info)
+}
+
+#[post("/admin/apps/<app_id>/resetConnection")]
+pub fn reset_app_connection(app_id: i64, _admin: Admin, db_conn: DbConn, verifier: State<connections::Verifier>) -> Result<Redirect, status::Custom<&'static str>> {
+    let app = find_app(&*db_conn, app_id)?;
+    match apps::reset_connection(&*db_conn, app.id) {
+        Ok(_) => {
+            verifier.enqueue(app.id);
+            Ok(Redirect::to(uri!(admin_apps: String::new(), 0)))
+        },
+        Err(e) => Err(internal_error(e, "Failed to reset connection"))
+    }
+}
+
+#[post("/admin/apps/<app_id>/delete")]
+pub fn delete_app(app_id: i64, admin: Admin, db_conn: DbConn, client_info: ClientInfo) -> Result<Redirect, status::Custom<&'static str>> {
+    let app = find_app(&*db_conn, app_id)?;
+    match apps::delete(&*db_conn, &app) {
+        Ok(_) => {
+            audit::record(&*db_conn, Some(&admin.0), Some(app.owner_id), audit::Action::DeleteApp, &format!("apps/{}", app.title), &client_info);
+            Ok(Redirect::to(uri!(admin_apps: String::new(), 0)))
+        },
+        Err(e) => Err(internal_error(e, "Failed to delete app"))
+    }
+}
+
+fn set_repo_hidden(pg_conn: &PgConnection, repo_id: i64, hidden: bool, admin: &users::User, client_info: &ClientInfo) -> Result<Redirect, status::Custom<&'static str>> {
+    let repo = find_repo(pg_conn, repo_id)?;
+    match repos::set_hidden(pg_conn, repo.id, hidden) {
+        Ok(_) => {
+            let action = if hidden { audit::Action::HideRepo } else { audit::Action::UnhideRepo };
+            audit::record(pg_conn, Some(admin), Some(repo.owner_id), action, &format!("repos/{}", repo.title), client_info);
+            Ok(Redirect::to(uri!(admin_repos: String::new(), 0)))
+        },
+        Err(e) => Err(internal_error(e, "Failed to update repo"))
+    }
+}
+
+#[post("/admin/repos/<repo_id>/hide")]
+pub fn hide_repo(repo_id: i64, admin: Admin, db_conn: DbConn, client_info: ClientInfo) -> Result<Redirect, status::Custom<&'static str>> {
+    set_repo_hidden(&*db_conn, repo_id, true, &admin.0, &client_info)
+}
+
+#[post("/admin/repos/<repo_id>/unhide")]
+pub fn unhide_repo(repo_id: i64, admin: Admin, db_conn: DbConn, client_info: ClientInfo) -> Result<Redirect, status::Custom<&'static str>> {
+    set_repo_hidden(&*db_conn, repo_id, false, &admin.0, &client_info)
+}
+
+#[post("/admin/repos/<repo_id>/delete")]
+pub fn delete_repo(repo_id: i64, admin: Admin, db_conn: DbConn, client_info: ClientInfo) -> Result<Redirect, status::Custom<&'static str>> {
+    let repo = find_repo(&*db_conn, repo_id)?;
+    match repos::delete(&*db_conn, &repo) {
+        Ok(_) => {
+            audit::record(&*db_conn, Some(&admin.0), Some(repo.owner_id), audit::Action::DeleteRepo, &format!("repos/{}", repo.title), &client_info);
+            Ok(Redirect::to(uri!(admin_repos: String::new(), 0)))
+        },
+        Err(e) => Err(internal_error(e, "Failed to delete repo"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db;
+
+    use diesel::prelude::*;
+
+    use crate::schema::sessions as sessions_table;
+    use crate::sessions::{
+        self,
+        NewSession,
+    };
+
+    fn make_admin(pg_conn: &PgConnection, username: &str) -> users::User {
+        let user = test_db::create_user(pg_conn, username);
+        users::change_role(pg_conn, user.id, Role::Admin).unwrap();
+        users::get(pg_conn, user.id).unwrap()
+    }
+
+    #[test]
+    fn only_signed_in_admins_get_through() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let admin = make_admin(&pg_conn, "admin");
+        let student = test_db::create_user(&pg_conn, "student");
+
+        assert!(Admin::from_user(users::get_active(&pg_conn, admin.id)).is_success());
+        assert!(Admin::from_user(users::get_active(&pg_conn, student.id)).is_failure());
+        assert!(Admin::from_user(Err("Session is not logged in".to_string())).is_failure());
+    }
+
+    #[test]
+    fn page_numbers_are_clamped() {
+        assert_eq!(page_number(None), 0);
+        assert_eq!(page_number(Some(-3)), 0);
+        assert_eq!(page_number(Some(2)), 2);
+        assert_eq!(page_number(Some(i64::MAX)), page_number(Some(i64::MAX - 1)));
+
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        test_db::create_user(&pg_conn, "student");
+        let (users, total) = users::get_page(&pg_conn, "", page_number(Some(i64::MAX))).unwrap();
+        assert!(users.is_empty());
+        assert!(total >= 1);
+    }
+
+    #[test]
+    fn suspending_signs_the_user_out() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let student = test_db::create_user(&pg_conn, "student");
+        diesel::insert_into(sessions_table::table).values(&NewSession::new(student.id, &test_db::client_info("192.0.2.1"))).execute(&pg_conn).unwrap();
+
+        users::set_suspended(&pg_conn, student.id, true).unwrap();
+        assert!(users::get_active(&pg_conn, student.id).is_err());
+        assert!(sessions::get_by_user(&pg_conn, student.id).unwrap().is_empty());
+
+        users::set_suspended(&pg_conn, student.id, false).unwrap();
+        assert!(users::get_active(&pg_conn, student.id).is_ok());
+    }
+
+    #[test]
+    fn hidden_apps_and_repos_leave_public_lists() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let admin = make_admin(&pg_conn, "admin");
+        let owner = test_db::create_user(&pg_conn, "owner");
+        let client_info = test_db::client_info("192.0.2.1");
+        let (app, _) = apps::insert(&pg_conn, &apps::NewApp {
+            owner_id: owner.id,
+            title: "hideable".to_string(),
+            description: String::new(),
+            domain: "https://hideable.example.com".to_string(),
+        }, &connections::DomainRules {
+            insecure_hosts: Vec::new(),
+        }).map_err(|e| e.1).unwrap();
+        let repo = repos::insert(&pg_conn, &repos::NewRepo {
+            owner_id: owner.id,
+            title: "hideable".to_string(),
+            description: String::new(),
+        }).map_err(|e| e.1).unwrap();
+
+        set_app_hidden(&pg_conn, app.id, true, &admin, &client_info).map_err(|e| e.1).unwrap();
+        set_repo_hidden(&pg_conn, repo.id, true, &admin, &client_info).map_err(|e| e.1).unwrap();
+        assert!(!apps::get_visible(&pg_conn).unwrap().iter().any(|visible| visible.id == app.id));
+        assert!(apps::get_visible_by_owner(&pg_conn, owner.id).unwrap().is_empty());
+        assert!(!repos::get_visible(&pg_conn).unwrap().iter().any(|visible| visible.id == repo.id));
+        assert!(repos::get_visible_by_owner(&pg_conn, owner.id).unwrap().is_empty());
+        let actions: Vec<String> = audit::get_by_user(&pg_conn, owner.id).unwrap().into_iter().map(|event| event.action).collect();
+        assert!(actions.contains(&audit::Action::HideApp.as_str().to_string()));
+        assert!(actions.contains(&audit::Action::HideRepo.as_str().to_string()));
+
+        set_app_hidden(&pg_conn, app.id, false, &admin, &client_info).map_err(|e| e.1).unwrap();
+        assert!(apps::get_visible(&pg_conn).unwrap().iter().any(|visible| visible.id == app.id));
+    }
+}
\ No newline at end of file
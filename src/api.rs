@@ -0,0 +1,625 @@
+use std::collections::HashMap;
+
+use rocket::{
+    http::Status,
+    Outcome,
+    request::{
+        self,
+        FromRequest,
+        Request,
+    },
+    response::{
+        self,
+        Responder,
+        Response,
+        status,
+    },
+    State,
+};
+
+use chrono::{
+    DateTime,
+    Utc,
+};
+
+use diesel::PgConnection;
+
+use rocket_contrib::json::Json;
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use super::{
+    api_tokens,
+    apps,
+    audit,
+    common::*,
+    connections,
+    DbConn,
+    email_verifications,
+    policy::{
+        self,
+        Action,
+    },
+    repos,
+    search,
+    sessions,
+    users,
+};
+
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: Status,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new<S: Into<String>>(status: Status, message: S) -> ApiError {
+        ApiError {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<status::Custom<&'static str>> for ApiError {
+    fn from(custom: status::Custom<&'static str>) -> ApiError {
+        ApiError::new(custom.0, custom.1)
+    }
+}
+
+impl<'r> Responder<'r> for ApiError {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let body = json!({
+            "error": {
+                "status": self.status.code,
+                "message": self.message,
+            }
+        });
+        Response::build_from(body.respond_to(request)?).status(self.status).ok()
+    }
+}
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+#[derive(Serialize)]
+pub struct ApiUser {
+    pub id: i64,
+    pub username: String,
+}
+
+impl ApiUser {
+    pub fn from_user(user: &users::User) -> ApiUser {
+        ApiUser {
+            id: user.id,
+            username: user.username.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ApiSearchResult {
+    pub kind: String,
+    pub title: String,
+    pub snippet: String,
+    /// The snippet as escaped html, with matches in `<mark>` tags.
+    pub highlighted: String,
+    pub rank: f32,
+}
+
+#[derive(Serialize)]
+pub struct ApiSearchResults {
+    pub results: Vec<ApiSearchResult>,
+    pub total: i64,
+    pub page: i64,
+    /// Whether nothing matched, so these are results with similar titles instead.
+    pub similar: bool,
+}
+
+/// The token is only ever included in the response that created or rotated it.
+#[derive(Serialize)]
+pub struct ApiAppWithToken {
+    #[serde(flatten)]
+    pub app: apps::PublicApp,
+    pub token: String,
+}
+
+#[derive(Serialize)]
+pub struct ApiRepo {
+    pub id: i64,
+    pub owner_id: i64,
+    pub title: String,
+    pub description: String,
+    /// In the order they appear in the repo.
+    pub app_ids: Vec<i64>,
+}
+
+impl ApiRepo {
+    pub fn from_repo(repo: &repos::Repo, app_ids: Vec<i64>) -> ApiRepo {
+        ApiRepo {
+            id: repo.id,
+            owner_id: repo.owner_id,
+            title: repo.title.clone(),
+            description: repo.description.clone(),
+            app_ids,
+        }
+    }
+
+    /// Looks up the repo's app ids. Hidden apps are only included if `include_hidden`, see `repos::get_entries`.
+    pub fn load(pg_conn: &PgConnection, repo: &repos::Repo, include_hidden: bool) -> ApiResult<ApiRepo> {
+        let mut app_ids = get_app_ids(pg_conn, &[repo.id], include_hidden)?;
+        Ok(ApiRepo::from_repo(repo, app_ids.remove(&repo.id).unwrap_or_default()))
+    }
+
+    /// Looks up the app ids of every repo at once, leaving out hidden apps.
+    pub fn load_vec(pg_conn: &PgConnection, repos: &[repos::Repo]) -> ApiResult<Vec<ApiRepo>> {
+        let mut app_ids = get_app_ids(pg_conn, &repos.iter().map(|repo| repo.id).collect::<Vec<i64>>(), false)?;
+        Ok(repos.iter().map(|repo| ApiRepo::from_repo(repo, app_ids.remove(&repo.id).unwrap_or_default())).collect())
+    }
+}
+
+fn get_app_ids(pg_conn: &PgConnection, repo_ids: &[i64], include_hidden: bool) -> ApiResult<HashMap<i64, Vec<i64>>> {
+    repos::get_app_ids(pg_conn, repo_ids, include_hidden).map_err(|e| {
+        eprintln!("{}", e);
+        ApiError::new(Status::InternalServerError, "Failed to get repo apps")
+    })
+}
+
+#[derive(Serialize)]
+pub struct ApiRepoApp {
+    #[serde(flatten)]
+    pub app: apps::PublicApp,
+    pub position: i32,
+    pub note: String,
+    pub added_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct ApiRepoWithApps {
+    #[serde(flatten)]
+    pub repo: ApiRepo,
+    pub apps: Vec<ApiRepoApp>,
+}
+
+#[derive(Deserialize)]
+pub struct ApiNewApp {
+    pub title: String,
+    pub description: String,
+    pub domain: String,
+}
+
+#[derive(Deserialize)]
+pub struct ApiNewRepo {
+    pub title: String,
+    pub description: String,
+}
+
+#[derive(Deserialize)]
+pub struct ApiNewRepoApp {
+    pub title: String,
+    pub note: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ApiRepoAppOrder {
+    /// Every app title in the repo, in their new order.
+    pub apps: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ApiRepoAppNote {
+    pub note: String,
+}
+
+/// A user authenticated by an `Authorization: Bearer` api token, or by their session cookie.
+/// Session authentication isn't limited by scopes.
+pub struct ApiAuth {
+    pub user: users::User,
+    pub scopes: Option<Vec<String>>,
+}
+
+impl ApiAuth {
+    pub fn require_scope(&self, scope: &str) -> ApiResult<()> {
+        match &self.scopes {
+            Some(scopes) if !scopes.iter().any(|test_scope| test_scope == scope) => Err(ApiError::new(Status::Forbidden, format!("Api token is missing the {} scope", scope))),
+            _ => Ok(())
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ApiAuth {
+    type Error = ApiError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<ApiAuth, ApiError> {
+        let db_conn = match request.guard::<DbConn>() {
+            Outcome::Success(db_conn) => db_conn,
+            _ => return Outcome::Failure((Status::ServiceUnavailable, ApiError::new(Status::ServiceUnavailable, "Failed to connect to database")))
+        };
+
+        match request.headers().get_one("Authorization") {
+            Some(authorization) => {
+                let value = match authorization.strip_prefix("Bearer ") {
+                    Some(value) => value.trim(),
+                    None => return Outcome::Failure((Status::Unauthorized, ApiError::new(Status::Unauthorized, "Authorization must be a bearer token")))
+                };
+                match api_tokens::authenticate(&*db_conn, value) {
+                    Ok(api_token) => {
+                        match users::get(&*db_conn, api_token.user_id) {
+                            Ok(user) => {
+                                match user.check_active() {
+                                    Ok(_) => Outcome::Success(ApiAuth {
+                                        user,
+                                        scopes: Some(api_token.scopes),
+                                    }),
+                                    Err(e) => Outcome::Failure((Status::Forbidden, ApiError::new(Status::Forbidden, e)))
+                                }
+                            },
+                            Err(_) => Outcome::Failure((Status::Unauthorized, ApiError::new(Status::Unauthorized, "Api token's user no longer exists")))
+                        }
+                    },
+                    Err(e) => Outcome::Failure((Status::Unauthorized, ApiError::new(Status::Unauthorized, e)))
+                }
+            },
+            None => {
+                match users::get_from_cookies(&*db_conn, request.cookies()) {
+                    Ok(user) => Outcome::Success(ApiAuth {
+                        user,
+                        scopes: None,
+                    }),
+                    Err(_) => Outcome::Failure((Status::Unauthorized, ApiError::new(Status::Unauthorized, "Must be signed in")))
+                }
+            }
+        }
+    }
+}
+
+/// Request guard failures never reach a route's responder, so routes take
+/// `Result<ApiAuth, ApiError>` and unwrap it here to keep errors as JSON.
+fn authenticate(auth: Result<ApiAuth, ApiError>, scope: &str) -> ApiResult<ApiAuth> {
+    let auth = auth?;
+    auth.require_scope(scope)?;
+    Ok(auth)
+}
+
+fn find_app(pg_conn: &PgConnection, title: &str) -> ApiResult<apps::App> {
+    apps::get_by_title(pg_conn, title)
+        .or_else(|_| apps::get_by_old_title(pg_conn, title))
+        .map_err(|_| ApiError::new(Status::NotFound, "App not found"))
+}
+
+fn find_authorized_app(pg_conn: &PgConnection, title: &str, user: &users::User, action: Action) -> ApiResult<apps::App> {
+    let app = find_app(pg_conn, title)?;
+    policy::authorize(user, action, &app)?;
+    Ok(app)
+}
+
+fn find_repo(pg_conn: &PgConnection, title: &str) -> ApiResult<repos::Repo> {
+    repos::get_by_title(pg_conn, title)
+        .or_else(|_| repos::get_by_old_title(pg_conn, title))
+        .map_err(|_| ApiError::new(Status::NotFound, "Repo not found"))
+}
+
+fn find_authorized_repo(pg_conn: &PgConnection, title: &str, user: &users::User, action: Action) -> ApiResult<repos::Repo> {
+    let repo = find_repo(pg_conn, title)?;
+    policy::authorize(user, action, &repo)?;
+    Ok(repo)
+}
+
+#[get("/users/<username>")]
+pub fn get_user(username: String, db_conn: DbConn) -> ApiResult<Json<ApiUser>> {
+    match users::get_by_username(&*db_conn, username) {
+        Ok(user) => Ok(Json(ApiUser::from_user(&user))),
+        Err(_) => Err(ApiError::new(Status::NotFound, "User not found"))
+    }
+}
+
+#[get("/search?<q>&<kind>&<page>")]
+pub fn search(q: Option<String>, kind: Option<String>, page: Option<i64>, db_conn: DbConn) -> ApiResult<Json<ApiSearchResults>> {
+    let q = q.unwrap_or_default();
+    if q.trim().is_empty() {
+        return Err(ApiError::new(Status::BadRequest, "Search can't be empty"))
+    }
+    let kinds = search::Kind::parse_filter(&kind).map_err(|e| ApiError::new(Status::BadRequest, e))?;
+    let page = page_number(page);
+    match search::search(&*db_conn, &q, &kinds, page) {
+        Ok(found) => Ok(Json(ApiSearchResults {
+            results: found.results.iter().map(|result| ApiSearchResult {
+                kind: result.kind.clone(),
+                title: result.title.clone(),
+                snippet: result.plain_snippet(),
+                highlighted: result.highlighted_snippet(),
+                rank: result.rank,
+            }).collect(),
+            total: found.total,
+            page,
+            similar: found.similar,
+        })),
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(ApiError::new(Status::InternalServerError, "Failed to search"))
+        }
+    }
+}
+
+#[get("/apps")]
+pub fn list_apps(db_conn: DbConn) -> ApiResult<Json<Vec<apps::PublicApp>>> {
+    match apps::get_visible(&*db_conn) {
+        Ok(apps) => Ok(Json(apps::PublicApp::from_vec(&apps))),
+        Err(_) => Err(ApiError::new(Status::InternalServerError, "Failed to get apps"))
+    }
+}
+
+#[get("/apps/<title>")]
+pub fn get_app(title: String, db_conn: DbConn) -> ApiResult<Json<apps::PublicApp>> {
+    let app = find_app(&*db_conn, &title)?;
+    if app.hidden_at.is_some() {
+        return Err(ApiError::new(Status::NotFound, "App not found"))
+    }
+    Ok(Json(apps::PublicApp::from_app(&app)))
+}
+
+#[post("/apps", format = "json", data = "<new_app>")]
+pub fn create_app(new_app: Json<ApiNewApp>, db_conn: DbConn, auth: Result<ApiAuth, ApiError>, verifier: State<connections::Verifier>, requirement: State<email_verifications::Requirement>, rules: State<connections::DomainRules>) -> ApiResult<status::Created<Json<ApiAppWithToken>>> {
+    let user = authenticate(auth, "apps")?.user;
+    requirement.check(&user)?;
+    let new_app = new_app.into_inner();
+    let (app, token) = apps::insert(&*db_conn, &apps::NewApp {
+        owner_id: user.id,
+        title: new_app.title,
+        description: new_app.description,
+        domain: new_app.domain,
+    }, &rules)?;
+    verifier.enqueue(app.id);
+    Ok(status::Created(uri!("/api/v1", get_app: &app.title).to_string(), Some(Json(ApiAppWithToken {
+        app: apps::PublicApp::from_app(&app),
+        token,
+    }))))
+}
+
+#[put("/apps/<title>", format = "json", data = "<changes>")]
+pub fn update_app(title: String, changes: Json<apps::AppChanges>, db_conn: DbConn, auth: Result<ApiAuth, ApiError>, verifier: State<connections::Verifier>, rules: State<connections::DomainRules>) -> ApiResult<Json<apps::PublicApp>> {
+    let user = authenticate(auth, "apps")?.user;
+    let app = find_authorized_app(&*db_conn, &title, &user, Action::Edit)?;
+    let updated = apps::update(&*db_conn, &app, &changes, &rules)?;
+    if updated.domain != app.domain {
+        verifier.enqueue(updated.id);
+    }
+    Ok(Json(apps::PublicApp::from_app(&updated)))
+}
+
+#[delete("/apps/<title>")]
+pub fn delete_app(title: String, db_conn: DbConn, auth: Result<ApiAuth, ApiError>, client_info: sessions::ClientInfo) -> ApiResult<status::NoContent> {
+    let user = authenticate(auth, "apps")?.user;
+    let app = find_authorized_app(&*db_conn, &title, &user, Action::Delete)?;
+    match apps::delete(&*db_conn, &app) {
+        Ok(_) => {
+            audit::record(&*db_conn, Some(&user), Some(app.owner_id), audit::Action::DeleteApp, &format!("apps/{}", app.title), &client_info);
+            Ok(status::NoContent)
+        },
+        Err(e) => Err(ApiError::new(Status::InternalServerError, e))
+    }
+}
+
+#[post("/apps/<title>/token")]
+pub fn rotate_app_token(title: String, db_conn: DbConn, auth: Result<ApiAuth, ApiError>) -> ApiResult<Json<ApiAppWithToken>> {
+    let user = authenticate(auth, "apps")?.user;
+    let app = find_authorized_app(&*db_conn, &title, &user, Action::ManageCredentials)?;
+    match apps::rotate_token(&*db_conn, &app) {
+        Ok(token) => {
+            match apps::get(&*db_conn, app.id) {
+                Ok(app) => Ok(Json(ApiAppWithToken {
+                    app: apps::PublicApp::from_app(&app),
+                    token,
+                })),
+                Err(e) => Err(ApiError::new(Status::InternalServerError, e))
+            }
+        },
+        Err(e) => Err(ApiError::new(Status::InternalServerError, e))
+    }
+}
+
+#[get("/repos")]
+pub fn list_repos(db_conn: DbConn) -> ApiResult<Json<Vec<ApiRepo>>> {
+    match repos::get_visible(&*db_conn) {
+        Ok(repos) => Ok(Json(ApiRepo::load_vec(&*db_conn, &repos)?)),
+        Err(_) => Err(ApiError::new(Status::InternalServerError, "Failed to get repos"))
+    }
+}
+
+/// Hidden apps are only included if `include_hidden`, see `repos::get_entries`.
+pub fn repo_with_apps(pg_conn: &PgConnection, repo: &repos::Repo, include_hidden: bool) -> ApiResult<ApiRepoWithApps> {
+    match repos::get_entries(pg_conn, repo, include_hidden) {
+        Ok(entries) => Ok(ApiRepoWithApps {
+            repo: ApiRepo::from_repo(repo, entries.iter().map(|(repo_app, _)| repo_app.app_id).collect()),
+            apps: entries.into_iter().map(|(repo_app, app)| ApiRepoApp {
+                app: apps::PublicApp::from_app(&app),
+                position: repo_app.position,
+                note: repo_app.note,
+                added_at: repo_app.added_at,
+            }).collect(),
+        }),
+        Err(e) => Err(ApiError::new(Status::InternalServerError, e))
+    }
+}
+
+#[get("/repos/<title>")]
+pub fn get_repo(title: String, db_conn: DbConn) -> ApiResult<Json<ApiRepoWithApps>> {
+    let repo = find_repo(&*db_conn, &title)?;
+    if repo.hidden_at.is_some() {
+        return Err(ApiError::new(Status::NotFound, "Repo not found"))
+    }
+    Ok(Json(repo_with_apps(&*db_conn, &repo, false)?))
+}
+
+#[post("/repos", format = "json", data = "<new_repo>")]
+pub fn create_repo(new_repo: Json<ApiNewRepo>, db_conn: DbConn, auth: Result<ApiAuth, ApiError>, requirement: State<email_verifications::Requirement>) -> ApiResult<status::Created<Json<ApiRepo>>> {
+    let user = authenticate(auth, "repos")?.user;
+    requirement.check(&user)?;
+    let new_repo = new_repo.into_inner();
+    let repo = repos::insert(&*db_conn, &repos::NewRepo {
+        owner_id: user.id,
+        title: new_repo.title,
+        description: new_repo.description,
+    })?;
+    Ok(status::Created(uri!("/api/v1", get_repo: &repo.title).to_string(), Some(Json(ApiRepo::from_repo(&repo, Vec::new())))))
+}
+
+#[put("/repos/<title>", format = "json", data = "<changes>")]
+pub fn update_repo(title: String, changes: Json<repos::RepoChanges>, db_conn: DbConn, auth: Result<ApiAuth, ApiError>) -> ApiResult<Json<ApiRepo>> {
+    let user = authenticate(auth, "repos")?.user;
+    let repo = find_authorized_repo(&*db_conn, &title, &user, Action::Edit)?;
+    let updated = repos::update(&*db_conn, &repo, &changes)?;
+    Ok(Json(ApiRepo::load(&*db_conn, &updated, true)?))
+}
+
+#[delete("/repos/<title>")]
+pub fn delete_repo(title: String, db_conn: DbConn, auth: Result<ApiAuth, ApiError>, client_info: sessions::ClientInfo) -> ApiResult<status::NoContent> {
+    let user = authenticate(auth, "repos")?.user;
+    let repo = find_authorized_repo(&*db_conn, &title, &user, Action::Delete)?;
+    match repos::delete(&*db_conn, &repo) {
+        Ok(_) => {
+            audit::record(&*db_conn, Some(&user), Some(repo.owner_id), audit::Action::DeleteRepo, &format!("repos/{}", repo.title), &client_info);
+            Ok(status::NoContent)
+        },
+        Err(e) => Err(ApiError::new(Status::InternalServerError, e))
+    }
+}
+
+#[post("/repos/<title>/apps", format = "json", data = "<new_repo_app>")]
+pub fn add_repo_app(title: String, new_repo_app: Json<ApiNewRepoApp>, db_conn: DbConn, auth: Result<ApiAuth, ApiError>, client_info: sessions::ClientInfo) -> ApiResult<Json<ApiRepoWithApps>> {
+    let user = authenticate(auth, "repos")?.user;
+    let repo = find_authorized_repo(&*db_conn, &title, &user, Action::Edit)?;
+    let app = find_app(&*db_conn, &new_repo_app.title)?;
+    repo.add_app(&*db_conn, app.id)?;
+    audit::record(&*db_conn, Some(&user), Some(repo.owner_id), audit::Action::AddApp, &format!("apps/{} to repos/{}", app.title, repo.title), &client_info);
+    match &new_repo_app.note {
+        Some(note) => repo.annotate_app(&*db_conn, app.id, note)?,
+        None => {}
+    }
+    Ok(Json(repo_with_apps(&*db_conn, &repo, true)?))
+}
+
+#[put("/repos/<title>/apps", format = "json", data = "<order>")]
+pub fn reorder_repo_apps(title: String, order: Json<ApiRepoAppOrder>, db_conn: DbConn, auth: Result<ApiAuth, ApiError>) -> ApiResult<Json<ApiRepoWithApps>> {
+    let user = authenticate(auth, "repos")?.user;
+    let repo = find_authorized_repo(&*db_conn, &title, &user, Action::Edit)?;
+    let app_ids = repos::app_ids_from_titles(&*db_conn, &order.apps)?;
+    repo.reorder_apps(&*db_conn, &app_ids)?;
+    Ok(Json(repo_with_apps(&*db_conn, &repo, true)?))
+}
+
+#[put("/repos/<title>/apps/<app_title>", format = "json", data = "<note>")]
+pub fn annotate_repo_app(title: String, app_title: String, note: Json<ApiRepoAppNote>, db_conn: DbConn, auth: Result<ApiAuth, ApiError>) -> ApiResult<Json<ApiRepoWithApps>> {
+    let user = authenticate(auth, "repos")?.user;
+    let repo = find_authorized_repo(&*db_conn, &title, &user, Action::Edit)?;
+    let app = find_app(&*db_conn, &app_title)?;
+    repo.annotate_app(&*db_conn, app.id, &note.note)?;
+    Ok(Json(repo_with_apps(&*db_conn, &repo, true)?))
+}
+
+#[delete("/repos/<title>/apps/<app_title>")]
+pub fn remove_repo_app(title: String, app_title: String, db_conn: DbConn, auth: Result<ApiAuth, ApiError>) -> ApiResult<status::NoContent> {
+    let user = authenticate(auth, "repos")?.user;
+    let repo = find_authorized_repo(&*db_conn, &title, &user, Action::Edit)?;
+    let app = find_app(&*db_conn, &app_title)?;
+    repo.remove_app(&*db_conn, app.id)?;
+    Ok(status::NoContent)
+}
+
+/// Errors Rocket raises before a handler runs, like a body that doesn't parse, get the usual error shape under `/api/`.
+/// Everywhere else they stay plain text.
+fn caught(request: &Request, status: Status, message: &'static str) -> Result<ApiError, status::Custom<&'static str>> {
+    match request.uri().path().starts_with("/api/") {
+        true => Ok(ApiError::new(status, message)),
+        false => Err(status::Custom(status, status.reason))
+    }
+}
+
+#[catch(400)]
+pub fn bad_request(request: &Request) -> Result<ApiError, status::Custom<&'static str>> {
+    caught(request, Status::BadRequest, "The request body couldn't be read")
+}
+
+#[catch(404)]
+pub fn not_found(request: &Request) -> Result<ApiError, status::Custom<&'static str>> {
+    caught(request, Status::NotFound, "Not found")
+}
+
+#[catch(422)]
+pub fn unprocessable_entity(request: &Request) -> Result<ApiError, status::Custom<&'static str>> {
+    caught(request, Status::UnprocessableEntity, "The request body is missing fields or has the wrong types")
+}
+
+#[catch(500)]
+pub fn internal_error(request: &Request) -> Result<ApiError, status::Custom<&'static str>> {
+    caught(request, Status::InternalServerError, "Something went wrong")
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::{
+        http::ContentType,
+        local::Client,
+    };
+
+    use super::*;
+
+    #[post("/apps", format = "json", data = "<new_app>")]
+    fn create(new_app: Json<ApiNewApp>) -> String {
+        new_app.into_inner().title
+    }
+
+    #[get("/fail")]
+    fn fail() -> Result<String, Status> {
+        Err(Status::InternalServerError)
+    }
+
+    fn client() -> Client {
+        let rocket = rocket::ignite()
+            .mount("/api/v1", routes![create, fail])
+            .mount("/", routes![fail])
+            .register(catchers![bad_request, not_found, unprocessable_entity, internal_error]);
+        Client::new(rocket).unwrap()
+    }
+
+    fn error(status: u16, message: &str) -> String {
+        json!({ "error": { "status": status, "message": message } }).to_string()
+    }
+
+    #[test]
+    fn api_errors_are_json() {
+        let client = client();
+
+        let mut response = client.post("/api/v1/apps").header(ContentType::JSON).body("{").dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        assert_eq!(response.body_string(), Some(error(400, "The request body couldn't be read")));
+
+        let mut response = client.post("/api/v1/apps").header(ContentType::JSON).body(r#"{"title": 1}"#).dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        assert_eq!(response.body_string(), Some(error(422, "The request body is missing fields or has the wrong types")));
+
+        let mut response = client.get("/api/v1/nothing").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(response.body_string(), Some(error(404, "Not found")));
+
+        let mut response = client.get("/api/v1/fail").dispatch();
+        assert_eq!(response.status(), Status::InternalServerError);
+        assert_eq!(response.body_string(), Some(error(500, "Something went wrong")));
+    }
+
+    #[test]
+    fn other_errors_are_plain_text() {
+        let client = client();
+
+        let mut response = client.get("/nothing").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(response.content_type(), Some(ContentType::Plain));
+        assert_eq!(response.body_string(), Some("Not Found".to_string()));
+
+        let response = client.get("/fail").dispatch();
+        assert_eq!(response.status(), Status::InternalServerError);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,290 @@
+use chrono::{
+    DateTime,
+    Duration,
+    Utc,
+};
+
+use diesel::{
+    prelude::*,
+    PgConnection,
+};
+
+use rocket::{
+    http::{
+        Cookies,
+        Status,
+    },
+    request::LenientForm,
+    response::{
+        Redirect,
+        status,
+    },
+    uri,
+};
+
+use rocket_contrib::templates::Template;
+use serde::Serialize;
+
+use super::{
+    common::*,
+    crypt_eq::{
+        crypt,
+        CryptExpressionMethods,
+        gen_salt,
+    },
+    DbConn,
+    schema::api_tokens,
+    signed_in_context,
+    users,
+};
+
+/// Every token handed out starts with this so they're easy to spot in scripts and logs.
+pub const TOKEN_PREFIX: &str = "st_";
+
+#[derive(Queryable)]
+pub struct ApiToken {
+    pub id: i64,
+    pub user_id: i64,
+    pub name: String,
+    pub token_hash: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl ApiToken {
+    pub fn is_expired(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= Utc::now(),
+            None => false
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct CleanApiToken {
+    pub id: i64,
+    pub name: Cleaned,
+    pub scopes: Vec<String>,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub last_used_at: Option<String>,
+    pub expired: bool,
+}
+
+impl CleanApiToken {
+    pub fn from_api_token(api_token: &ApiToken) -> CleanApiToken {
+        CleanApiToken {
+            id: api_token.id,
+            name: Cleaned::new(&api_token.name),
+            scopes: api_token.scopes.clone(),
+            created_at: api_token.created_at.format("%Y-%m-%d").to_string(),
+            expires_at: api_token.expires_at.map(|expires_at| expires_at.format("%Y-%m-%d").to_string()),
+            last_used_at: api_token.last_used_at.map(|last_used_at| last_used_at.format("%Y-%m-%d").to_string()),
+            expired: api_token.is_expired(),
+        }
+    }
+
+    pub fn from_vec(api_tokens: &[ApiToken]) -> Vec<CleanApiToken> {
+        api_tokens.iter().map(CleanApiToken::from_api_token).collect()
+    }
+}
+
+#[derive(FromForm)]
+pub struct FormApiToken {
+    pub name: String,
+    pub apps: bool,
+    pub repos: bool,
+    pub expires_in_days: Option<i64>,
+}
+
+pub fn get_by_user(pg_conn: &PgConnection, user_id: i64) -> Result<Vec<ApiToken>, String> {
+    match api_tokens::table.filter(api_tokens::user_id.eq(user_id)).order(api_tokens::created_at.desc()).load::<ApiToken>(pg_conn) {
+        Ok(api_tokens) => Ok(api_tokens),
+        Err(e) => Err(format!("Failed to get api tokens for user {}: {}", user_id, e))
+    }
+}
+
+/// Creates a token and returns it along with the only copy of its plaintext value.
+pub fn create(pg_conn: &PgConnection, user_id: i64, name: &str, scopes: &Vec<String>, expires_at: Option<DateTime<Utc>>) -> Result<(ApiToken, String), String> {
+    let secret = random_string(40);
+    match diesel::insert_into(api_tokens::table).values((
+        api_tokens::user_id.eq(user_id),
+        api_tokens::name.eq(name),
+        api_tokens::token_hash.eq(crypt(secret.as_str(), gen_salt("bf"))),
+        api_tokens::scopes.eq(scopes),
+        api_tokens::expires_at.eq(expires_at),
+    )).get_result::<ApiToken>(pg_conn) {
+        Ok(api_token) => {
+            let value = format!("{}{}_{}", TOKEN_PREFIX, api_token.id, secret);
+            Ok((api_token, value))
+        },
+        Err(e) => Err(format!("Failed to create api token: {}", e))
+    }
+}
+
+fn parse(value: &str) -> Result<(i64, String), String> {
+    match value.strip_prefix(TOKEN_PREFIX) {
+        Some(value) => {
+            let mut parts = value.splitn(2, '_');
+            match (parts.next().map(|id| id.parse::<i64>()), parts.next()) {
+                (Some(Ok(id)), Some(secret)) => Ok((id, secret.to_string())),
+                _ => Err("Malformed api token".to_string())
+            }
+        },
+        None => Err("Malformed api token".to_string())
+    }
+}
+
+pub fn authenticate(pg_conn: &PgConnection, value: &str) -> Result<ApiToken, String> {
+    let (id, secret) = parse(value)?;
+    match api_tokens::table.find(id).filter(api_tokens::token_hash.crypt_eq(&secret)).first::<ApiToken>(pg_conn) {
+        Ok(api_token) => {
+            if api_token.is_expired() {
+                return Err("Api token has expired".to_string())
+            }
+            match diesel::update(api_tokens::table.find(api_token.id)).set(api_tokens::last_used_at.eq(Utc::now())).execute(pg_conn) {
+                Ok(_) => Ok(api_token),
+                Err(e) => Err(format!("Failed to update api token: {}", e))
+            }
+        },
+        Err(_) => Err("Invalid api token".to_string())
+    }
+}
+
+#[post("/tokens", data = "<token_form>")]
+pub fn submit_token(token_form: LenientForm<FormApiToken>, db_conn: DbConn, cookies: Cookies) -> Result<Template, status::Custom<&'static str>> {
+    let (mut context, _, user) = signed_in_context(&*db_conn, cookies);
+    let user = match user {
+        Some(user) => user,
+        None => return Err(status::Custom(Status::BadRequest, "Must be signed in"))
+    };
+
+    if token_form.name.is_empty() || token_form.name.len() > 64 {return Err(status::Custom(Status::BadRequest, "Name must be 1-64 characters"))}
+
+    let mut scopes = Vec::new();
+    if token_form.apps {scopes.push("apps".to_string())}
+    if token_form.repos {scopes.push("repos".to_string())}
+
+    let expires_at = match token_form.expires_in_days {
+        Some(days) if (1..=365).contains(&days) => Some(Utc::now() + Duration::days(days)),
+        Some(_) => return Err(status::Custom(Status::BadRequest, "Tokens must expire in 1-365 days")),
+        None => None
+    };
+
+    match create(&*db_conn, user.id, &token_form.name, &scopes, expires_at) {
+        Ok((api_token, value)) => {
+            context.insert("api_token", &CleanApiToken::from_api_token(&api_token));
+            context.insert("value", &value);
+            Ok(Template::render("api_token", &context))
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(status::Custom(Status::InternalServerError, "Failed to create api token"))
+        }
+    }
+}
+
+/// Returns whether the user had a token with that id to revoke.
+pub fn revoke(pg_conn: &PgConnection, user_id: i64, id: i64) -> Result<bool, String> {
+    match diesel::delete(api_tokens::table.find(id).filter(api_tokens::user_id.eq(user_id))).execute(pg_conn) {
+        Ok(revoked) => Ok(revoked > 0),
+        Err(e) => Err(format!("Failed to revoke api token {}: {}", id, e))
+    }
+}
+
+#[post("/tokens/<id>/revoke")]
+pub fn revoke_token(id: i64, db_conn: DbConn, cookies: Cookies) -> Result<Redirect, status::Custom<&'static str>> {
+    match users::get_from_cookies(&*db_conn, cookies) {
+        Ok(user) => {
+            match revoke(&*db_conn, user.id, id) {
+                Ok(false) => Err(status::Custom(Status::NotFound, "Api token not found")),
+                Ok(true) => Ok(Redirect::to(uri!(users::user_profile: user.username))),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    Err(status::Custom(Status::InternalServerError, "Failed to revoke api token"))
+                }
+            }
+        },
+        Err(_) => Err(status::Custom(Status::Forbidden, "Failed to authenticate user"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        api,
+        test_db,
+    };
+
+    #[test]
+    fn tokens_are_parsed() {
+        assert_eq!(parse("st_12_abc"), Ok((12, "abc".to_string())));
+        assert_eq!(parse("st_12_a_b"), Ok((12, "a_b".to_string())));
+        assert!(parse("12_abc").is_err());
+        assert!(parse("st_x_abc").is_err());
+        assert!(parse("st_12").is_err());
+        assert!(parse("").is_err());
+    }
+
+    #[test]
+    fn tokens_authenticate_until_they_expire() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "scripter");
+        let (api_token, value) = create(&pg_conn, user.id, "current", &vec!["apps".to_string()], Some(Utc::now() + Duration::days(1))).unwrap();
+        assert!(value.starts_with(TOKEN_PREFIX));
+        assert_ne!(api_token.token_hash, value);
+        let authenticated = authenticate(&pg_conn, &value).unwrap();
+        assert_eq!(authenticated.user_id, user.id);
+        assert!(get_by_user(&pg_conn, user.id).unwrap()[0].last_used_at.is_some());
+        assert!(authenticate(&pg_conn, &format!("{}x", value)).is_err());
+
+        let (_, expired) = create(&pg_conn, user.id, "expired", &Vec::new(), Some(Utc::now() - Duration::minutes(1))).unwrap();
+        assert_eq!(authenticate(&pg_conn, &expired).err(), Some("Api token has expired".to_string()));
+    }
+
+    #[test]
+    fn tokens_only_get_their_scopes() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "scripter");
+        let (_, value) = create(&pg_conn, user.id, "apps only", &vec!["apps".to_string()], None).unwrap();
+        let auth = api::ApiAuth {
+            scopes: Some(authenticate(&pg_conn, &value).unwrap().scopes),
+            user,
+        };
+        assert!(auth.require_scope("apps").is_ok());
+        assert_eq!(auth.require_scope("repos").err().map(|e| e.status), Some(Status::Forbidden));
+
+        // Signing in with a session isn't limited by scopes
+        let session_auth = api::ApiAuth {
+            user: auth.user,
+            scopes: None,
+        };
+        assert!(session_auth.require_scope("repos").is_ok());
+    }
+
+    #[test]
+    fn revoked_tokens_stop_working() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "scripter");
+        let someone_else = test_db::create_user(&pg_conn, "meddler");
+        let (api_token, value) = create(&pg_conn, user.id, "revoked", &Vec::new(), None).unwrap();
+
+        assert!(!revoke(&pg_conn, someone_else.id, api_token.id).unwrap());
+        assert!(authenticate(&pg_conn, &value).is_ok());
+        assert!(revoke(&pg_conn, user.id, api_token.id).unwrap());
+        assert!(authenticate(&pg_conn, &value).is_err());
+        assert!(!revoke(&pg_conn, user.id, api_token.id).unwrap());
+    }
+}
\ No newline at end of file
@@ -1,3 +1,8 @@
+use chrono::{
+    DateTime,
+    Utc,
+};
+
 use diesel::{
     prelude::*,
     PgConnection,
@@ -9,38 +14,92 @@ use rocket::{
         Cookies,
         Status,
     },
-    request::Form,
+    request::LenientForm,
     response::{
         Redirect,
         status,
     },
+    State,
     uri,
 };
 
 use rocket_contrib::templates::Template;
-use serde::Serialize;
+use serde::{
+    Deserialize,
+    Serialize,
+};
 
 use super::{
+    audit,
     common::*,
-    crypt_eq::CryptExpressionMethods,
+    connections,
+    crypt_eq::{
+        crypt,
+        CryptExpressionMethods,
+        gen_salt,
+    },
     DbConn,
-    repos,
+    email_verifications,
+    login_throttle,
+    policy::{
+        self,
+        Action,
+    },
     schema,
-    schema::apps,
+    schema::{
+        app_redirects,
+        apps,
+    },
+    sessions,
     signed_in_context,
     users,
 };
 
-#[derive(Queryable, Serialize)]
+const TOKEN_LENGTH: usize = 48;
+
+#[derive(Queryable)]
 pub struct App {
     pub id: i64,
     pub owner_id: i64,
     pub title: String,
     pub description: String,
     pub domain: String,
-    pub token: String,
+    pub token_hash: String,
     pub connected: bool,
     pub connected_error: String,
+    pub hidden_at: Option<DateTime<Utc>>,
+}
+
+/// What gets serialized whenever an app is shown, so the token hash can never end up in a template or response.
+#[derive(Serialize)]
+pub struct PublicApp {
+    pub id: i64,
+    pub owner_id: i64,
+    pub title: String,
+    pub description: String,
+    pub domain: String,
+    pub connected: bool,
+    pub connected_error: String,
+    pub hidden_at: Option<DateTime<Utc>>,
+}
+
+impl PublicApp {
+    pub fn from_app(app: &App) -> PublicApp {
+        PublicApp {
+            id: app.id,
+            owner_id: app.owner_id,
+            title: app.title.clone(),
+            description: app.description.clone(),
+            domain: app.domain.clone(),
+            connected: app.connected,
+            connected_error: app.connected_error.clone(),
+            hidden_at: app.hidden_at,
+        }
+    }
+
+    pub fn from_vec(apps: &[App]) -> Vec<PublicApp> {
+        apps.iter().map(PublicApp::from_app).collect()
+    }
 }
 
 #[derive(Serialize)]
@@ -48,7 +107,6 @@ pub struct CleanApp {
     pub title: Cleaned,
     pub description: Cleaned,
     pub domain: Cleaned,
-    pub token: Cleaned,
     pub connected_error: Cleaned,
 }
 
@@ -58,7 +116,6 @@ impl CleanApp {
             title: Cleaned::new(&app.title),
             description: Cleaned::new(&app.description),
             domain: Cleaned::new(&app.domain),
-            token: Cleaned::new(&app.token),
             connected_error: Cleaned::new(&app.connected_error),
         }
     }
@@ -79,7 +136,6 @@ pub struct FormApp {
     pub title: String,
     pub description: String,
     pub domain: String,
-    pub token: String
 }
 
 impl FormApp {
@@ -89,19 +145,31 @@ impl FormApp {
             title: self.title,
             description: self.description,
             domain: self.domain,
-            token: self.token
         }
     }
 }
 
-#[derive(FromForm, Insertable)]
-#[table_name = "apps"]
+#[derive(FromForm)]
 pub struct NewApp {
     pub owner_id: i64,
     pub title: String,
     pub description: String,
     pub domain: String,
-    pub token: String
+}
+
+/// Fields left out aren't changed.
+#[derive(Deserialize, FromForm)]
+pub struct AppChanges {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub domain: Option<String>,
+}
+
+pub fn get(pg_conn: &PgConnection, app_id: i64) -> Result<App, String> {
+    match apps::table.find(app_id).first::<App>(pg_conn) {
+        Ok(app) => Ok(app),
+        Err(e) => Err(format!("Failed to get app {}: {}", app_id, e))
+    }
 }
 
 pub fn get_by_title(pg_conn: &PgConnection, title: &str) -> Result<App, String> {
@@ -113,6 +181,26 @@ pub fn get_by_title(pg_conn: &PgConnection, title: &str) -> Result<App, String>
     }
 }
 
+/// Gets the app that used to have `title` before it was renamed.
+pub fn get_by_old_title(pg_conn: &PgConnection, title: &str) -> Result<App, String> {
+    match app_redirects::table
+        .inner_join(apps::table)
+        .filter(lower(app_redirects::old_title).eq(title.to_lowercase()))
+        .select(apps::all_columns)
+        .first::<App>(pg_conn) {
+        Ok(app) => Ok(app),
+        Err(e) => Err(format!("Failed to get app by old title {}", e))
+    }
+}
+
+fn add_redirect(pg_conn: &PgConnection, old_title: &str, app_id: i64) -> QueryResult<usize> {
+    diesel::delete(app_redirects::table.filter(lower(app_redirects::old_title).eq(old_title.to_lowercase()))).execute(pg_conn)?;
+    diesel::insert_into(app_redirects::table).values((
+        app_redirects::old_title.eq(old_title),
+        app_redirects::app_id.eq(app_id),
+    )).execute(pg_conn)
+}
+
 pub fn get_all(pg_conn: &PgConnection) -> Result<Vec<App>, String> {
     match apps::table.load::<App>(pg_conn) {
         Ok(apps) => Ok(apps),
@@ -120,19 +208,81 @@ pub fn get_all(pg_conn: &PgConnection) -> Result<Vec<App>, String> {
     }
 }
 
+pub fn get_by_owner(pg_conn: &PgConnection, owner_id: i64) -> Result<Vec<App>, String> {
+    match apps::table.filter(apps::owner_id.eq(owner_id)).load::<App>(pg_conn) {
+        Ok(apps) => Ok(apps),
+        Err(e) => Err(format!("Failed to get apps owned by {}: {}", owner_id, e))
+    }
+}
+
+/// Every app that hasn't been hidden by an admin, for public listings.
+pub fn get_visible(pg_conn: &PgConnection) -> Result<Vec<App>, String> {
+    match apps::table.filter(apps::hidden_at.is_null()).load::<App>(pg_conn) {
+        Ok(apps) => Ok(apps),
+        Err(e) => Err(format!("Failed to get apps {}", e))
+    }
+}
+
+pub fn get_visible_by_owner(pg_conn: &PgConnection, owner_id: i64) -> Result<Vec<App>, String> {
+    match apps::table.filter(apps::owner_id.eq(owner_id)).filter(apps::hidden_at.is_null()).order(apps::title.asc()).load::<App>(pg_conn) {
+        Ok(apps) => Ok(apps),
+        Err(e) => Err(format!("Failed to get apps owned by {}: {}", owner_id, e))
+    }
+}
+
+/// One page of the apps, hidden ones included, whose title or domain contains `search`, along with how many match in total.
+pub fn get_page(pg_conn: &PgConnection, search: &str, page: i64) -> Result<(Vec<App>, i64), String> {
+    let pattern = contains_pattern(search);
+    let total = apps::table
+        .filter(apps::title.ilike(&pattern).or(apps::domain.ilike(&pattern)))
+        .count()
+        .get_result::<i64>(pg_conn);
+    let apps = apps::table
+        .filter(apps::title.ilike(&pattern).or(apps::domain.ilike(&pattern)))
+        .order(apps::id.desc())
+        .limit(PAGE_SIZE)
+        .offset(page.saturating_mul(PAGE_SIZE))
+        .load::<App>(pg_conn);
+    match (apps, total) {
+        (Ok(apps), Ok(total)) => Ok((apps, total)),
+        (Err(e), _) | (_, Err(e)) => Err(format!("Failed to get page {} of apps: {}", page, e))
+    }
+}
+
+/// Hides the app from everyone but its owner and admins, or shows it again.
+pub fn set_hidden(pg_conn: &PgConnection, app_id: i64, hidden: bool) -> Result<(), String> {
+    let hidden_at = if hidden { Some(Utc::now()) } else { None };
+    match diesel::update(apps::table.find(app_id)).set(apps::hidden_at.eq(hidden_at)).execute(pg_conn) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to set app {} hidden: {}", app_id, e))
+    }
+}
+
+/// Forgets whether the app was connected, so it's treated as unconnected until its next check.
+pub fn reset_connection(pg_conn: &PgConnection, app_id: i64) -> Result<(), String> {
+    match diesel::update(apps::table.find(app_id)).set((
+        apps::connected.eq(false),
+        apps::connected_error.eq("Connection attempt queued."),
+    )).execute(pg_conn) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to reset connection of app {}: {}", app_id, e))
+    }
+}
+
 #[get("/apps")]
 pub fn apps(db_conn: DbConn, cookies: Cookies) -> Template {
     let (mut context, _, _) = signed_in_context(&*db_conn, cookies);
-    match get_all(&*db_conn) {
-        Ok(apps) => {
-            context.insert("clean_apps", &CleanApp::from_vec(&apps));
-        },
-        Err(_) => {}
-    };
+    if let Ok(apps) = get_visible(&*db_conn) {
+        context.insert("clean_apps", &CleanApp::from_vec(&apps));
+    }
     Template::render("apps", &context)
 }
 
-fn validate_domain(domain: &str) -> bool {
+pub fn validate_domain(domain: &str, rules: &connections::DomainRules) -> bool {
+    if rules.allows_insecure(domain) {
+        return true
+    }
+
     let valid_formatting = Regex::new(
         r"^https://([a-zA-Z0-9][a-zA-Z0-9-]{1,61}[a-zA-Z0-9].)?[a-zA-Z0-9][a-zA-Z0-9-]{1,61}[a-zA-Z0-9].[a-zA-Z]{2,3}(:[0-9]{1,5})?$"
     ).unwrap().is_match(domain);
@@ -141,7 +291,7 @@ fn validate_domain(domain: &str) -> bool {
         r"[0-9]{1,5}$"
     ).unwrap().find(domain) {
         Some(port_match) => {
-            return port_match.as_str().parse::<i32>().unwrap() < 65536
+            port_match.as_str().parse::<i32>().unwrap() < 65536
         }
         None => true
     };
@@ -149,6 +299,118 @@ fn validate_domain(domain: &str) -> bool {
     return valid_formatting && valid_port
 }
 
+pub fn validate(title: &str, description: &str, domain: &str, rules: &connections::DomainRules) -> Result<(), &'static str> {
+    if !validate_domain(domain, rules) {return Err("Invalid domain")}
+    if !validate_title(title) || title.len() > 24 {return Err("Title must be 3-24 characters")}
+    if description.len() > 256 {return Err("Description is too long - max 256 characters")}
+    Ok(())
+}
+
+fn write_error(e: diesel::result::Error) -> status::Custom<&'static str> {
+    match &*(e.to_string()) {
+        "duplicate key value violates unique constraint \"apps_title_unique_idx\"" => status::Custom(Status::BadRequest, "Duplicate app name"),
+        _ => {
+            eprintln!("Failed to write app to database {}", e);
+            status::Custom(Status::InternalServerError, "Failed to write app to database")
+        }
+    }
+}
+
+/// Inserts the app with a newly generated token.
+/// The returned token is the only copy of it; only its hash is stored.
+pub fn insert(pg_conn: &PgConnection, new_app: &NewApp, rules: &connections::DomainRules) -> Result<(App, String), status::Custom<&'static str>> {
+    match validate(&new_app.title, &new_app.description, &new_app.domain, rules) {
+        Ok(_) => {},
+        Err(e) => return Err(status::Custom(Status::BadRequest, e))
+    }
+
+    let token = random_string(TOKEN_LENGTH);
+    match diesel::insert_into(apps::table).values((
+        apps::owner_id.eq(new_app.owner_id),
+        apps::title.eq(&new_app.title),
+        apps::description.eq(&new_app.description),
+        apps::domain.eq(&new_app.domain),
+        apps::token_hash.eq(crypt(token.as_str(), gen_salt("bf"))),
+    )).get_result::<App>(pg_conn) {
+        Ok(app) => Ok((app, token)),
+        Err(e) => Err(write_error(e))
+    }
+}
+
+/// Replaces the app's token, returning the only copy of the new one.
+/// The app has to be reconnected with the new token afterwards.
+pub fn rotate_token(pg_conn: &PgConnection, app: &App) -> Result<String, String> {
+    let token = random_string(TOKEN_LENGTH);
+    match diesel::update(apps::table.find(app.id)).set((
+        apps::token_hash.eq(crypt(token.as_str(), gen_salt("bf"))),
+        apps::connected.eq(false),
+        apps::connected_error.eq("Token rotated. No connection attempted."),
+    )).execute(pg_conn) {
+        Ok(_) => Ok(token),
+        Err(e) => Err(format!("Failed to rotate token for app {}: {}", app.id, e))
+    }
+}
+
+pub fn token_matches(pg_conn: &PgConnection, app_id: i64, token: &String) -> Result<bool, String> {
+    match apps::table.find(app_id).filter(apps::token_hash.crypt_eq(token)).count().get_result::<i64>(pg_conn) {
+        Ok(count) => Ok(count > 0),
+        Err(e) => Err(format!("Failed to check token for app {}: {}", app_id, e))
+    }
+}
+
+pub fn update(pg_conn: &PgConnection, app: &App, changes: &AppChanges, rules: &connections::DomainRules) -> Result<App, status::Custom<&'static str>> {
+    match validate(
+        changes.title.as_ref().unwrap_or(&app.title),
+        changes.description.as_ref().unwrap_or(&app.description),
+        changes.domain.as_ref().unwrap_or(&app.domain),
+        rules,
+    ) {
+        Ok(_) => {},
+        Err(e) => return Err(status::Custom(Status::BadRequest, e))
+    }
+
+    if changes.title.is_none() && changes.description.is_none() && changes.domain.is_none() {
+        return get(pg_conn, app.id).map_err(|_| status::Custom(Status::InternalServerError, "Failed to get app"))
+    }
+
+    let result = pg_conn.transaction::<_, diesel::result::Error, _>(|| {
+        let query = diesel::update(apps::table.find(app.id));
+        let changed = (
+            changes.title.as_ref().map(|title| apps::title.eq(title)),
+            changes.description.as_ref().map(|description| apps::description.eq(description)),
+            changes.domain.as_ref().map(|domain| apps::domain.eq(domain)),
+        );
+        let updated = match &changes.domain {
+            Some(domain) if domain != &app.domain => query.set((
+                changed,
+                apps::connected.eq(false),
+                apps::connected_error.eq("No connection attempted."),
+            )).get_result::<App>(pg_conn)?,
+            _ => query.set(changed).get_result::<App>(pg_conn)?
+        };
+        if updated.title != app.title {
+            add_redirect(pg_conn, &app.title, app.id)?;
+        }
+        Ok(updated)
+    });
+    result.map_err(write_error)
+}
+
+/// Deletes the app after removing it from every repo that contains it.
+pub fn delete(pg_conn: &PgConnection, app: &App) -> Result<(), String> {
+    let result = pg_conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::delete(schema::repo_apps::table.filter(schema::repo_apps::app_id.eq(app.id))).execute(pg_conn)?;
+        diesel::delete(apps::table.filter(apps::id.eq(app.id))).execute(pg_conn)
+    });
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            eprintln!("Failed to delete app {}: {}", app.id, e);
+            Err("Failed to delete app".to_string())
+        }
+    }
+}
+
 #[get("/createApp")]
 pub fn create_app(db_conn: DbConn, cookies: Cookies) -> Template {
     let (context, _, _) = signed_in_context(&*db_conn, cookies);
@@ -156,13 +418,14 @@ pub fn create_app(db_conn: DbConn, cookies: Cookies) -> Template {
 }
 
 #[post("/createApp", data="<app_form>")]
-pub fn submit_app(app_form: Form<FormApp>, db_conn: DbConn, cookies: Cookies) -> Result<Redirect, status::Custom<&str>> {
-    let (_, _, user) = signed_in_context(&*db_conn, cookies);
+pub fn submit_app(app_form: LenientForm<FormApp>, db_conn: DbConn, cookies: Cookies, verifier: State<connections::Verifier>, requirement: State<email_verifications::Requirement>, rules: State<connections::DomainRules>) -> Result<Template, status::Custom<&'static str>> {
+    let (mut context, _, user) = signed_in_context(&*db_conn, cookies);
     let form_app = app_form.into_inner();
     let new_app;
 
     match user {
         Some(user) => {
+            requirement.check(&user)?;
             new_app = form_app.to_new_app(user.id);
         },
         None => {
@@ -170,77 +433,238 @@ pub fn submit_app(app_form: Form<FormApp>, db_conn: DbConn, cookies: Cookies) ->
         }
     }
 
-    if !validate_domain(&new_app.domain) {return Err(status::Custom(Status::BadRequest, "Invalid domain"))}
-    if !validate_title(&new_app.title) || new_app.title.len() > 24 {return Err(status::Custom(Status::BadRequest, "Title must be 3-24 characters"))}
-    if new_app.description.len() > 256 {return Err(status::Custom(Status::BadRequest, "Description is too long - max 256 characters"))}
-    if new_app.token.len() != 60 {return Err(status::Custom(Status::BadRequest, "Token must be exactly 60 characters"))}
+    match insert(&*db_conn, &new_app, &rules) {
+        Ok((app, token)) => {
+            verifier.enqueue(app.id);
+            context.insert("clean_app", &CleanApp::from_app(&app));
+            context.insert("token", &token);
+            Ok(Template::render("app_token", &context))
+        },
+        Err(e) => Err(e)
+    }
+}
+
+#[get("/apps/<title>")]
+pub fn app(title: String, db_conn: DbConn, cookies: Cookies) -> Page {
+    let (mut context, _, user) = signed_in_context(&*db_conn, cookies);
 
-    match diesel::insert_into(apps::table).values(&new_app).get_result::<App>(&*db_conn) {
+    match get_by_title(&*db_conn, &title) {
         Ok(app) => {
-            Ok(Redirect::to(uri!(app: app.title)))
+            let permissions = policy::Permissions::new(&user, &app);
+            if app.hidden_at.is_some() && !permissions.edit {
+                return Page::NotFound(status::NotFound("App not found".to_owned()))
+            }
+            context.insert("permissions", &permissions);
+            context.insert("app", &PublicApp::from_app(&app));
+            context.insert("clean_app", &CleanApp::from_app(&app));
+            if let Ok(owner) = users::get(&*db_conn, app.owner_id) {
+                context.insert("clean_owner", &users::PublicUser::from_user(&owner));
+            }
+            Page::Found(Template::render("app", &context))
         },
-        Err(e) => {
-            match &*(e.to_string()) {
-                "Failed to add app to database duplicate key value violates unique constraint \"apps_title_unique_idx\"" => Err(status::Custom(Status::BadRequest, "Duplicate app name")),
-                _ => {
-                    eprintln!("Failed to add app to database {}", e);
-                    Err(status::Custom(Status::InternalServerError, "Failed to add app to database"))
-                }
+        Err(_) => {
+            match get_by_old_title(&*db_conn, &title) {
+                Ok(app) => Page::Moved(Redirect::moved(uri!(app: app.title))),
+                Err(_) => Page::NotFound(status::NotFound("App not found".to_owned()))
             }
         }
     }
 }
 
-#[get("/apps/<title>")]
-pub fn app(title: String, db_conn: DbConn, cookies: Cookies) -> Result<Template, status::NotFound<String>> {
-    let (mut context, _, _) = signed_in_context(&*db_conn, cookies);
+#[get("/apps/<title>/edit")]
+pub fn edit_app(title: String, db_conn: DbConn, cookies: Cookies) -> Result<Template, status::Custom<&'static str>> {
+    let (mut context, _, user) = signed_in_context(&*db_conn, cookies);
+    match get_by_title(&*db_conn, &title) {
+        Ok(app) => {
+            match user {
+                Some(user) => {
+                    policy::authorize(&user, Action::Edit, &app)?;
+                    context.insert("app", &PublicApp::from_app(&app));
+                    context.insert("clean_app", &CleanApp::from_app(&app));
+                    Ok(Template::render("edit_app", &context))
+                },
+                None => Err(status::Custom(Status::Forbidden, "Failed to authenticate user"))
+            }
+        },
+        Err(_) => Err(status::Custom(Status::NotFound, "App not found"))
+    }
+}
 
+#[post("/apps/<title>/edit", data = "<changes>")]
+pub fn submit_edit_app(title: String, changes: LenientForm<AppChanges>, db_conn: DbConn, cookies: Cookies, verifier: State<connections::Verifier>, rules: State<connections::DomainRules>) -> Result<Redirect, status::Custom<&'static str>> {
     match get_by_title(&*db_conn, &title) {
         Ok(app) => {
-            context.insert("app", &app);
-            context.insert("clean_app", &CleanApp::from_app(&app));
-            match users::get(&*db_conn, app.id) {
-                Ok(owner) => {
-                    context.insert("owner", &owner);
-                    context.insert("clean_owner", &users::CleanUser::from_user(&owner));
+            match users::get_from_cookies(&*db_conn, cookies) {
+                Ok(user) => {
+                    policy::authorize(&user, Action::Edit, &app)?;
+                    let updated = update(&*db_conn, &app, &changes, &rules)?;
+                    if updated.domain != app.domain {
+                        verifier.enqueue(updated.id);
+                    }
+                    Ok(Redirect::to(uri!(app: updated.title)))
                 },
-                _ => {}
+                Err(_) => Err(status::Custom(Status::Forbidden, "Failed to authenticate user"))
             }
-            Ok(Template::render("app", &context))
         },
-        Err(_) => Err(status::NotFound("App not found".to_owned()))
+        Err(_) => Err(status::Custom(Status::NotFound, "App not found"))
     }
 }
 
-#[post("/apps/<title>/delete", data = "<login_user>")]
-pub fn delete_app(title: String, db_conn: DbConn, login_user: Form<users::LoginUser>) -> Result<Redirect, status::Custom<String>> {
-    match schema::users::table.filter(schema::users::username.eq(&login_user.username)).filter(schema::users::password_hash.crypt_eq(&login_user.password)).first::<users::User>(&*db_conn) {
+#[post("/apps/<title>/delete", data = "<confirm_user>")]
+pub fn delete_app(title: String, db_conn: DbConn, confirm_user: LenientForm<users::ConfirmUser>, client_info: sessions::ClientInfo, throttle: State<login_throttle::Throttle>) -> Result<Redirect, status::Custom<String>> {
+    match users::confirm(&*db_conn, &throttle, &client_info, &confirm_user) {
         Ok(user) => {
             match get_by_title(&*db_conn, &title) {
                 Ok(app) => {
-                    if app.owner_id == user.id {
-                        match schema::repos::table.filter(schema::repos::apps.contains(vec!(app.id))).load::<repos::Repo>(&*db_conn) {
-                            Ok(repos) => {
-                                for repo in repos {
-                                    match repo.remove_app(&*db_conn, app.id) {
-                                        Ok(_) => {},
-                                        Err(_) => return Err(status::Custom(Status::InternalServerError, format!("Failed to remove app from repo: {}.", repo.id)))
-                                    }
-                                }
-                                match diesel::delete(apps::table.filter(apps::id.eq(app.id))).execute(&*db_conn) {
-                                    Ok(_) => Ok(Redirect::to(uri!(super::home))),
-                                    Err(_) => Err(status::Custom(Status::InternalServerError, "Failed to delete app".to_string()))
-                                }
-                            },
-                            Err(_) => Err(status::Custom(Status::InternalServerError, "Couldn't retrieve repos that would be affected by deletion.".to_string()))
-                        }
-                    } else {
-                        return Err(status::Custom(Status::Forbidden, "You don't have permission to delete this app".to_string()))
+                    match policy::authorize(&user, Action::Delete, &app) {
+                        Ok(_) => {},
+                        Err(e) => return Err(status::Custom(e.0, e.1.to_string()))
+                    }
+                    match delete(&*db_conn, &app) {
+                        Ok(_) => {
+                            audit::record(&*db_conn, Some(&user), Some(app.owner_id), audit::Action::DeleteApp, &format!("apps/{}", app.title), &client_info);
+                            Ok(Redirect::to(uri!(super::home)))
+                        },
+                        Err(e) => Err(status::Custom(Status::InternalServerError, e))
                     }
                 },
                 Err(_) => Err(status::Custom(Status::NotFound, "App not found".to_string()))
             }
         },
-        Err(_) => Err(status::Custom(Status::Forbidden, "Failed to authenticate user".to_string()))
+        Err(e) => Err(status::Custom(Status::Forbidden, e))
+    }
+}
+
+#[post("/apps/<title>/retryConnection")]
+pub fn retry_connection(title: String, db_conn: DbConn, cookies: Cookies, verifier: State<connections::Verifier>) -> Result<Redirect, status::Custom<&'static str>> {
+    match get_by_title(&*db_conn, &title) {
+        Ok(app) => {
+            match users::get_from_cookies(&*db_conn, cookies) {
+                Ok(user) => {
+                    policy::authorize(&user, Action::Edit, &app)?;
+                    match diesel::update(apps::table.find(app.id)).set(apps::connected_error.eq("Connection attempt queued.")).execute(&*db_conn) {
+                        Ok(_) => {
+                            verifier.enqueue(app.id);
+                            Ok(Redirect::to(uri!(app: title)))
+                        },
+                        Err(_) => Err(status::Custom(Status::InternalServerError, "Failed to queue connection attempt."))
+                    }
+                },
+                Err(_) => Err(status::Custom(Status::Forbidden, "Failed to authenticate user"))
+            }
+        },
+        Err(_) => Err(status::Custom(Status::NotFound, "App not found"))
+    }
+}
+
+#[post("/apps/<title>/rotateToken")]
+pub fn rotate_app_token(title: String, db_conn: DbConn, cookies: Cookies) -> Result<Template, status::Custom<&'static str>> {
+    let (mut context, _, user) = signed_in_context(&*db_conn, cookies);
+    match get_by_title(&*db_conn, &title) {
+        Ok(app) => {
+            match user {
+                Some(user) => {
+                    policy::authorize(&user, Action::ManageCredentials, &app)?;
+                    match rotate_token(&*db_conn, &app) {
+                        Ok(token) => {
+                            context.insert("clean_app", &CleanApp::from_app(&app));
+                            context.insert("token", &token);
+                            Ok(Template::render("app_token", &context))
+                        },
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            Err(status::Custom(Status::InternalServerError, "Failed to rotate token"))
+                        }
+                    }
+                },
+                None => Err(status::Custom(Status::Forbidden, "Failed to authenticate user"))
+            }
+        },
+        Err(_) => Err(status::Custom(Status::NotFound, "App not found"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db;
+
+    fn add_app(pg_conn: &PgConnection, owner_id: i64) -> (App, String) {
+        insert(pg_conn, &NewApp {
+            owner_id,
+            title: "tokened".to_string(),
+            description: String::new(),
+            domain: "https://tokened.example.com".to_string(),
+        }, &connections::DomainRules {
+            insecure_hosts: Vec::new(),
+        }).map_err(|e| e.1).unwrap()
+    }
+
+    #[test]
+    fn only_a_hash_of_the_token_is_stored() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let owner = test_db::create_user(&pg_conn, "developer");
+        let (app, token) = add_app(&pg_conn, owner.id);
+        assert_eq!(token.len(), TOKEN_LENGTH);
+        assert_ne!(app.token_hash, token);
+        assert!(!app.token_hash.contains(&token));
+        assert!(app.token_hash.starts_with("$2a$"));
+
+        assert!(token_matches(&pg_conn, app.id, &token).unwrap());
+        assert!(!token_matches(&pg_conn, app.id, &app.token_hash).unwrap());
+        assert!(!token_matches(&pg_conn, app.id, &format!("{}x", token)).unwrap());
+        assert!(!token_matches(&pg_conn, app.id + 1, &token).unwrap());
+    }
+
+    #[test]
+    fn rotating_replaces_the_token() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let owner = test_db::create_user(&pg_conn, "developer");
+        let (app, old_token) = add_app(&pg_conn, owner.id);
+        let new_token = rotate_token(&pg_conn, &app).unwrap();
+        assert_ne!(new_token, old_token);
+        assert!(token_matches(&pg_conn, app.id, &new_token).unwrap());
+        assert!(!token_matches(&pg_conn, app.id, &old_token).unwrap());
+
+        let rotated = get(&pg_conn, app.id).unwrap();
+        assert_ne!(rotated.token_hash, app.token_hash);
+        assert!(!rotated.token_hash.contains(&new_token));
+        assert!(!rotated.connected);
+    }
+    #[test]
+    fn updates_only_change_given_fields() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let owner = test_db::create_user(&pg_conn, "developer");
+        let (app, _) = add_app(&pg_conn, owner.id);
+        let rules = connections::DomainRules {
+            insecure_hosts: Vec::new(),
+        };
+        let renamed = update(&pg_conn, &app, &AppChanges {
+            title: Some("retitled".to_string()),
+            description: None,
+            domain: None,
+        }, &rules).map_err(|e| e.1).unwrap();
+        assert_eq!(renamed.title, "retitled");
+        assert_eq!(renamed.domain, app.domain);
+        assert_eq!(renamed.connected, app.connected);
+        assert_eq!(get_by_old_title(&pg_conn, "tokened").unwrap().id, app.id);
+
+        let moved = update(&pg_conn, &renamed, &AppChanges {
+            title: None,
+            description: None,
+            domain: Some("https://moved.example.com".to_string()),
+        }, &rules).map_err(|e| e.1).unwrap();
+        assert_eq!(moved.title, "retitled");
+        assert_eq!(moved.domain, "https://moved.example.com");
+        assert!(!moved.connected);
     }
 }
\ No newline at end of file
@@ -0,0 +1,304 @@
+use std::str::FromStr;
+
+use chrono::{
+    DateTime,
+    Utc,
+};
+
+use diesel::{
+    pg::Pg,
+    prelude::*,
+    PgConnection,
+};
+
+use rocket::http::Cookies;
+use rocket_contrib::templates::Template;
+use serde::Serialize;
+
+use super::{
+    common::*,
+    DbConn,
+    schema::audit_events,
+    sessions::ClientInfo,
+    signed_in_context,
+    users,
+};
+
+/// How many of their own events a user sees.
+const USER_EVENTS: i64 = 50;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    Login,
+    LoginFailed,
+    Signout,
+    PasswordReset,
+    PasswordChanged,
+    EmailChanged,
+    UsernameChanged,
+    TwoFactorEnabled,
+    TwoFactorDisabled,
+    SetRole,
+    AddApp,
+    DeleteApp,
+    DeleteRepo,
+    SuspendUser,
+    UnsuspendUser,
+    HideApp,
+    UnhideApp,
+    HideRepo,
+    UnhideRepo,
+    DeleteAccount,
+    /// This user accepted the apps and repos of someone deleting their account.
+    ReceiveOwned,
+}
+
+impl Action {
+    pub const ALL: [Action; 21] = [
+        Action::Login,
+        Action::LoginFailed,
+        Action::Signout,
+        Action::PasswordReset,
+        Action::PasswordChanged,
+        Action::EmailChanged,
+        Action::UsernameChanged,
+        Action::TwoFactorEnabled,
+        Action::TwoFactorDisabled,
+        Action::SetRole,
+        Action::AddApp,
+        Action::DeleteApp,
+        Action::DeleteRepo,
+        Action::SuspendUser,
+        Action::UnsuspendUser,
+        Action::HideApp,
+        Action::UnhideApp,
+        Action::HideRepo,
+        Action::UnhideRepo,
+        Action::DeleteAccount,
+        Action::ReceiveOwned,
+    ];
+
+    /// How the action is stored in `audit_events.action`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Login => "login",
+            Action::LoginFailed => "login_failed",
+            Action::Signout => "signout",
+            Action::PasswordReset => "password_reset",
+            Action::PasswordChanged => "password_changed",
+            Action::EmailChanged => "email_changed",
+            Action::UsernameChanged => "username_changed",
+            Action::TwoFactorEnabled => "two_factor_enabled",
+            Action::TwoFactorDisabled => "two_factor_disabled",
+            Action::SetRole => "set_role",
+            Action::AddApp => "add_app",
+            Action::DeleteApp => "delete_app",
+            Action::DeleteRepo => "delete_repo",
+            Action::SuspendUser => "suspend_user",
+            Action::UnsuspendUser => "unsuspend_user",
+            Action::HideApp => "hide_app",
+            Action::UnhideApp => "unhide_app",
+            Action::HideRepo => "hide_repo",
+            Action::UnhideRepo => "unhide_repo",
+            Action::DeleteAccount => "delete_account",
+            Action::ReceiveOwned => "receive_owned",
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = ();
+
+    fn from_str(action: &str) -> Result<Action, ()> {
+        Action::ALL.iter().find(|candidate| candidate.as_str() == action).copied().ok_or(())
+    }
+}
+
+#[derive(Queryable)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub actor_id: Option<i64>,
+    /// The actor's username when it happened, empty if no one was signed in.
+    pub actor: String,
+    /// Whose account the event concerns, like the owner of a deleted app.
+    pub user_id: Option<i64>,
+    pub action: String,
+    pub target: String,
+    /// Whoever acted, which might not be the user the event concerns. Only admins see it then.
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl AuditEvent {
+    /// The ip, if the user the event concerns is the one who acted, so moderators and other users don't give away where they are.
+    pub fn own_ip(&self) -> Option<String> {
+        match self.actor_id.is_some() && self.actor_id == self.user_id {
+            true => self.ip.clone(),
+            false => None
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct CleanAuditEvent {
+    pub actor: Cleaned,
+    pub action: String,
+    pub target: Cleaned,
+    pub ip: Option<String>,
+    pub created_at: String,
+}
+
+impl CleanAuditEvent {
+    /// Only admins should get every event's ip, everyone else gets `AuditEvent::own_ip`.
+    pub fn from_event(event: &AuditEvent, ip: Option<String>) -> CleanAuditEvent {
+        CleanAuditEvent {
+            actor: Cleaned::new(&event.actor),
+            action: event.action.clone(),
+            target: Cleaned::new(&event.target),
+            ip,
+            created_at: event.created_at.format("%Y-%m-%d %H:%M").to_string(),
+        }
+    }
+
+    /// For the admin audit log.
+    pub fn from_vec(events: &[AuditEvent]) -> Vec<CleanAuditEvent> {
+        events.iter().map(|event| CleanAuditEvent::from_event(event, event.ip.clone())).collect()
+    }
+
+    /// For a user's own security log.
+    pub fn from_own_vec(events: &[AuditEvent]) -> Vec<CleanAuditEvent> {
+        events.iter().map(|event| CleanAuditEvent::from_event(event, event.own_ip())).collect()
+    }
+}
+
+/// Appends an event to the log. Failing to is logged rather than failing the action it records.
+pub fn record(pg_conn: &PgConnection, actor: Option<&users::User>, user_id: Option<i64>, action: Action, target: &str, client_info: &ClientInfo) {
+    match diesel::insert_into(audit_events::table).values((
+        audit_events::actor_id.eq(actor.map(|actor| actor.id)),
+        audit_events::actor.eq(actor.map_or("", |actor| &actor.username)),
+        audit_events::user_id.eq(user_id),
+        audit_events::action.eq(action.as_str()),
+        audit_events::target.eq(target.chars().take(256).collect::<String>()),
+        audit_events::ip.eq(&client_info.ip),
+    )).execute(pg_conn) {
+        Ok(_) => {},
+        Err(e) => eprintln!("Failed to record audit event {}: {}", action.as_str(), e)
+    }
+}
+
+pub fn get_by_user(pg_conn: &PgConnection, user_id: i64) -> Result<Vec<AuditEvent>, String> {
+    match audit_events::table
+        .filter(audit_events::user_id.eq(user_id))
+        .order(audit_events::id.desc())
+        .limit(USER_EVENTS)
+        .load::<AuditEvent>(pg_conn) {
+        Ok(events) => Ok(events),
+        Err(e) => Err(format!("Failed to get audit events for user {}: {}", user_id, e))
+    }
+}
+
+/// Every event about the user, oldest first, for exporting their data.
+pub fn get_all_by_user(pg_conn: &PgConnection, user_id: i64) -> Result<Vec<AuditEvent>, String> {
+    match audit_events::table
+        .filter(audit_events::user_id.eq(user_id))
+        .order(audit_events::id.asc())
+        .load::<AuditEvent>(pg_conn) {
+        Ok(events) => Ok(events),
+        Err(e) => Err(format!("Failed to get audit events for user {}: {}", user_id, e))
+    }
+}
+
+fn filtered<'a>(action: Option<Action>, actor: &str) -> audit_events::BoxedQuery<'a, Pg> {
+    let mut query = audit_events::table.into_boxed();
+    if let Some(action) = action {
+        query = query.filter(audit_events::action.eq(action.as_str()));
+    }
+    if !actor.is_empty() {
+        query = query.filter(lower(audit_events::actor).eq(actor.to_lowercase()));
+    }
+    query
+}
+
+/// One page of the log, newest first, optionally only one action or one actor's events, along with how many match in total.
+pub fn get_page(pg_conn: &PgConnection, action: Option<Action>, actor: &str, page: i64) -> Result<(Vec<AuditEvent>, i64), String> {
+    let total = filtered(action, actor).count().get_result::<i64>(pg_conn);
+    let events = filtered(action, actor)
+        .order(audit_events::id.desc())
+        .limit(PAGE_SIZE)
+        .offset(page.saturating_mul(PAGE_SIZE))
+        .load::<AuditEvent>(pg_conn);
+    match (events, total) {
+        (Ok(events), Ok(total)) => Ok((events, total)),
+        (Err(e), _) | (_, Err(e)) => Err(format!("Failed to get page {} of audit events: {}", page, e))
+    }
+}
+
+#[get("/securityLog")]
+pub fn security_log(db_conn: DbConn, cookies: Cookies) -> Template {
+    let (mut context, _, user) = signed_in_context(&*db_conn, cookies);
+    if let Some(user) = user {
+        match get_by_user(&*db_conn, user.id) {
+            Ok(events) => context.insert("events", &CleanAuditEvent::from_own_vec(&events)),
+            Err(e) => eprintln!("{}", e)
+        }
+    }
+    Template::render("security_log", &context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db;
+
+    #[test]
+    fn only_the_user_sees_their_own_ip() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "watched");
+        let admin = test_db::create_user(&pg_conn, "watcher");
+        record(&pg_conn, Some(&user), Some(user.id), Action::PasswordChanged, "", &test_db::client_info("192.0.2.1"));
+        record(&pg_conn, Some(&admin), Some(user.id), Action::SuspendUser, "", &test_db::client_info("192.0.2.2"));
+        record(&pg_conn, None, Some(user.id), Action::LoginFailed, "", &test_db::client_info("192.0.2.3"));
+
+        let events = get_all_by_user(&pg_conn, user.id).unwrap();
+        let own: Vec<Option<String>> = CleanAuditEvent::from_own_vec(&events).into_iter().map(|event| event.ip).collect();
+        assert_eq!(own, vec![Some("192.0.2.1".to_string()), None, None]);
+        let all: Vec<Option<String>> = CleanAuditEvent::from_vec(&events).into_iter().map(|event| event.ip).collect();
+        assert_eq!(all, vec![Some("192.0.2.1".to_string()), Some("192.0.2.2".to_string()), Some("192.0.2.3".to_string())]);
+    }
+
+    #[test]
+    fn actions_are_parsed() {
+        for action in Action::ALL.iter() {
+            assert_eq!(action.as_str().parse::<Action>(), Ok(*action));
+        }
+        assert!("drop_table".parse::<Action>().is_err());
+    }
+
+    #[test]
+    fn events_cant_be_changed_or_deleted() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "watched");
+        record(&pg_conn, Some(&user), Some(user.id), Action::PasswordChanged, "", &test_db::client_info("192.0.2.1"));
+        let event = get_all_by_user(&pg_conn, user.id).unwrap().remove(0);
+
+        // Each attempt gets its own savepoint, since the failure aborts whatever transaction it's in
+        let updated = pg_conn.transaction::<_, diesel::result::Error, _>(|| {
+            diesel::update(audit_events::table.find(event.id)).set(audit_events::action.eq(Action::Login.as_str())).execute(&pg_conn)
+        });
+        assert!(updated.is_err());
+        let deleted = pg_conn.transaction::<_, diesel::result::Error, _>(|| {
+            diesel::delete(audit_events::table.find(event.id)).execute(&pg_conn)
+        });
+        assert!(deleted.is_err());
+
+        let events = get_all_by_user(&pg_conn, user.id).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].action, Action::PasswordChanged.as_str());
+    }
+}
\ No newline at end of file
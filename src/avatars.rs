@@ -0,0 +1,165 @@
+use std::path::Path;
+
+use chrono::{
+    DateTime,
+    Utc,
+};
+
+use diesel::{
+    prelude::*,
+    PgConnection,
+};
+
+use image::{
+    imageops::FilterType,
+    io::Reader,
+    ImageOutputFormat,
+};
+
+use rocket::{
+    Data,
+    http::{
+        ContentType,
+        Cookies,
+        Status,
+    },
+    response::{
+        content::Content,
+        Redirect,
+        status,
+    },
+    uri,
+};
+
+use rocket_multipart_form_data::{
+    mime,
+    MultipartFormData,
+    MultipartFormDataField,
+    MultipartFormDataOptions,
+};
+
+use super::{
+    account,
+    DbConn,
+    schema::avatars,
+    users,
+};
+
+/// Uploads bigger than this are refused before they're decoded.
+const MAX_UPLOAD_BYTES: u64 = 2 * 1024 * 1024;
+/// Images with more pixels than this are refused, since decoding them takes memory in proportion.
+const MAX_PIXELS: u64 = 4096 * 4096;
+/// Avatars are stored as squares this many pixels wide.
+const SIZE: u32 = 128;
+
+#[derive(Queryable)]
+pub struct Avatar {
+    pub user_id: i64,
+    /// Always a `SIZE` by `SIZE` png.
+    pub image: Vec<u8>,
+    pub updated_at: DateTime<Utc>,
+}
+
+pub fn get(pg_conn: &PgConnection, user_id: i64) -> Result<Option<Avatar>, String> {
+    match avatars::table.find(user_id).first::<Avatar>(pg_conn).optional() {
+        Ok(avatar) => Ok(avatar),
+        Err(e) => Err(format!("Failed to get avatar of user {}: {}", user_id, e))
+    }
+}
+
+pub fn exists(pg_conn: &PgConnection, user_id: i64) -> Result<bool, String> {
+    match diesel::select(diesel::dsl::exists(avatars::table.find(user_id))).get_result::<bool>(pg_conn) {
+        Ok(exists) => Ok(exists),
+        Err(e) => Err(format!("Failed to check for avatar of user {}: {}", user_id, e))
+    }
+}
+
+/// Crops and scales an uploaded image to a `SIZE` by `SIZE` png, which also drops anything else the file carried.
+pub fn resize(path: &Path) -> Result<Vec<u8>, &'static str> {
+    let unreadable = "That isn't an image we can read";
+    let (width, height) = Reader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|_| unreadable)?
+        .into_dimensions()
+        .map_err(|_| unreadable)?;
+    if width as u64 * height as u64 > MAX_PIXELS {
+        return Err("That image is too big, it can be at most 4096 by 4096 pixels")
+    }
+    let image = Reader::open(path)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(|_| unreadable)?
+        .decode()
+        .map_err(|_| unreadable)?;
+
+    let mut png = Vec::new();
+    match image.resize_to_fill(SIZE, SIZE, FilterType::Lanczos3).write_to(&mut png, ImageOutputFormat::Png) {
+        Ok(_) => Ok(png),
+        Err(_) => Err("Failed to resize the image")
+    }
+}
+
+pub fn save(pg_conn: &PgConnection, user_id: i64, png: &Vec<u8>) -> Result<(), String> {
+    let now = Utc::now();
+    match diesel::insert_into(avatars::table).values((
+        avatars::user_id.eq(user_id),
+        avatars::image.eq(png),
+        avatars::updated_at.eq(now),
+    )).on_conflict(avatars::user_id).do_update().set((
+        avatars::image.eq(png),
+        avatars::updated_at.eq(now),
+    )).execute(pg_conn) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to save avatar of user {}: {}", user_id, e))
+    }
+}
+
+pub fn delete(pg_conn: &PgConnection, user_id: i64) -> Result<(), String> {
+    match diesel::delete(avatars::table.find(user_id)).execute(pg_conn) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to delete avatar of user {}: {}", user_id, e))
+    }
+}
+
+#[get("/users/<username>/avatar")]
+pub fn avatar(username: String, db_conn: DbConn) -> Result<Content<Vec<u8>>, status::NotFound<&'static str>> {
+    let user = users::get_by_username(&*db_conn, username).map_err(|_| status::NotFound("Couldn't find user"))?;
+    match get(&*db_conn, user.id) {
+        Ok(Some(avatar)) => Ok(Content(ContentType::PNG, avatar.image)),
+        Ok(None) => Err(status::NotFound("No avatar")),
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(status::NotFound("Failed to get avatar"))
+        }
+    }
+}
+
+#[post("/settings/avatar", data = "<data>")]
+pub fn upload_avatar(content_type: &ContentType, data: Data, db_conn: DbConn, cookies: Cookies) -> Result<Redirect, status::Custom<&'static str>> {
+    let user = users::get_from_cookies(&*db_conn, cookies).map_err(|_| status::Custom(Status::Forbidden, "Must be signed in"))?;
+
+    let mut options = MultipartFormDataOptions::new();
+    options.allowed_fields.push(MultipartFormDataField::file("avatar").size_limit(MAX_UPLOAD_BYTES).content_type_by_string(Some(mime::IMAGE_STAR)).unwrap());
+    let form = MultipartFormData::parse(content_type, data, options).map_err(|_| status::Custom(Status::BadRequest, "Upload an image of at most 2MB"))?;
+    let file = form.files.get("avatar").and_then(|files| files.first()).ok_or(status::Custom(Status::BadRequest, "Choose an image to upload"))?;
+
+    let png = resize(&file.path).map_err(|e| status::Custom(Status::BadRequest, e))?;
+    match save(&*db_conn, user.id, &png) {
+        Ok(_) => Ok(Redirect::to(uri!(account::settings))),
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(status::Custom(Status::InternalServerError, "Failed to save avatar"))
+        }
+    }
+}
+
+#[post("/settings/avatar/remove")]
+pub fn remove_avatar(db_conn: DbConn, cookies: Cookies) -> Result<Redirect, status::Custom<&'static str>> {
+    let user = users::get_from_cookies(&*db_conn, cookies).map_err(|_| status::Custom(Status::Forbidden, "Must be signed in"))?;
+    match delete(&*db_conn, user.id) {
+        Ok(_) => Ok(Redirect::to(uri!(account::settings))),
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(status::Custom(Status::InternalServerError, "Failed to remove avatar"))
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,459 @@
+use chrono::{
+    DateTime,
+    Utc,
+};
+
+use diesel::{
+    prelude::*,
+    PgConnection,
+};
+
+use rocket::{
+    http::{
+        Cookies,
+        Status,
+    },
+    request::LenientForm,
+    response::{
+        Redirect,
+        status,
+    },
+    State,
+    uri,
+};
+
+use rocket_contrib::templates::Template;
+use serde::Serialize;
+
+use super::{
+    common::*,
+    DbConn,
+    login_throttle,
+    policy::{
+        self,
+        Action,
+        Role,
+    },
+    schema::{
+        class_members,
+        classes,
+        users as users_table,
+    },
+    sessions,
+    signed_in_context,
+    users,
+};
+
+#[derive(Queryable, Serialize)]
+pub struct Class {
+    pub id: i64,
+    pub owner_id: i64,
+    pub title: String,
+    pub description: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A class along with who's in it, since only its teacher and members can see it.
+/// Invited users aren't in it until they accept.
+pub struct ClassRoster {
+    pub class: Class,
+    pub member_ids: Vec<i64>,
+}
+
+#[derive(Serialize)]
+pub struct CleanClass {
+    pub title: Cleaned,
+    pub description: Cleaned,
+}
+
+impl CleanClass {
+    pub fn from_class(class: &Class) -> CleanClass {
+        CleanClass {
+            title: Cleaned::new(&class.title),
+            description: Cleaned::new(&class.description),
+        }
+    }
+
+    pub fn from_vec(classes: &[Class]) -> Vec<CleanClass> {
+        classes.iter().map(CleanClass::from_class).collect()
+    }
+}
+
+pub struct NewClass {
+    pub owner_id: i64,
+    pub title: String,
+    pub description: String,
+}
+
+#[derive(FromForm)]
+pub struct FormClass {
+    pub title: String,
+    pub description: String,
+}
+
+#[derive(FromForm)]
+pub struct MemberForm {
+    pub username: String,
+}
+
+pub fn get_by_title(pg_conn: &PgConnection, title: &str) -> Result<Class, String> {
+    match classes::table.filter(lower(classes::title).eq(title.to_lowercase())).first::<Class>(pg_conn) {
+        Ok(class) => Ok(class),
+        Err(e) => Err(format!("Failed to get class by title {}", e))
+    }
+}
+
+pub fn get_roster(pg_conn: &PgConnection, class: Class) -> Result<ClassRoster, String> {
+    match class_members::table
+        .filter(class_members::class_id.eq(class.id))
+        .filter(class_members::accepted_at.is_not_null())
+        .select(class_members::user_id)
+        .load::<i64>(pg_conn) {
+        Ok(member_ids) => Ok(ClassRoster {
+            class,
+            member_ids,
+        }),
+        Err(e) => Err(format!("Failed to get members of class {}: {}", class.id, e))
+    }
+}
+
+/// Members who have accepted if `accepted`, otherwise users who are still invited.
+pub fn get_members(pg_conn: &PgConnection, class: &Class, accepted: bool) -> Result<Vec<users::User>, String> {
+    match class_members::table
+        .inner_join(users_table::table)
+        .filter(class_members::class_id.eq(class.id))
+        .filter(class_members::accepted_at.is_not_null().eq(accepted))
+        .order(users_table::username.asc())
+        .select(users_table::all_columns)
+        .load::<users::User>(pg_conn) {
+        Ok(members) => Ok(members),
+        Err(e) => Err(format!("Failed to get members of class {}: {}", class.id, e))
+    }
+}
+
+/// Classes the user teaches or is in.
+pub fn get_by_user(pg_conn: &PgConnection, user_id: i64) -> Result<Vec<Class>, String> {
+    let member_of = class_members::table
+        .filter(class_members::user_id.eq(user_id))
+        .filter(class_members::accepted_at.is_not_null())
+        .select(class_members::class_id);
+    match classes::table
+        .filter(classes::owner_id.eq(user_id).or(classes::id.eq_any(member_of)))
+        .order(classes::title.asc())
+        .load::<Class>(pg_conn) {
+        Ok(classes) => Ok(classes),
+        Err(e) => Err(format!("Failed to get classes for user {}: {}", user_id, e))
+    }
+}
+
+/// Classes the user has been invited to but hasn't accepted yet.
+pub fn get_invitations(pg_conn: &PgConnection, user_id: i64) -> Result<Vec<Class>, String> {
+    let invited_to = class_members::table
+        .filter(class_members::user_id.eq(user_id))
+        .filter(class_members::accepted_at.is_null())
+        .select(class_members::class_id);
+    match classes::table
+        .filter(classes::id.eq_any(invited_to))
+        .order(classes::title.asc())
+        .load::<Class>(pg_conn) {
+        Ok(classes) => Ok(classes),
+        Err(e) => Err(format!("Failed to get class invitations for user {}: {}", user_id, e))
+    }
+}
+
+pub fn validate(title: &str, description: &str) -> Result<(), &'static str> {
+    if !validate_title(title) || title.len() > 24 {return Err("Title must be 3-24 characters")}
+    if description.len() > 256 {return Err("Description is too long - max 256 characters")}
+    Ok(())
+}
+
+pub fn insert(pg_conn: &PgConnection, new_class: &NewClass) -> Result<Class, status::Custom<&'static str>> {
+    match validate(&new_class.title, &new_class.description) {
+        Ok(_) => {},
+        Err(e) => return Err(status::Custom(Status::BadRequest, e))
+    }
+
+    match diesel::insert_into(classes::table).values((
+        classes::owner_id.eq(new_class.owner_id),
+        classes::title.eq(&new_class.title),
+        classes::description.eq(&new_class.description),
+    )).get_result::<Class>(pg_conn) {
+        Ok(class) => Ok(class),
+        Err(e) => {
+            match &*e.to_string() {
+                "duplicate key value violates unique constraint \"classes_title_unique_idx\"" => Err(status::Custom(Status::BadRequest, "Duplicate class name")),
+                _ => {
+                    eprintln!("Failed to write class to database {}", e);
+                    Err(status::Custom(Status::InternalServerError, "Failed to write class to database"))
+                }
+            }
+        }
+    }
+}
+
+/// The user only joins once they accept, and inviting someone who's already in the class does nothing.
+pub fn invite(pg_conn: &PgConnection, class: &Class, user_id: i64) -> Result<(), String> {
+    match diesel::insert_into(class_members::table).values((
+        class_members::class_id.eq(class.id),
+        class_members::user_id.eq(user_id),
+    )).on_conflict_do_nothing().execute(pg_conn) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to invite user {} to class {}: {}", user_id, class.id, e))
+    }
+}
+
+/// Returns whether the user had an invitation to accept.
+pub fn accept_invitation(pg_conn: &PgConnection, class: &Class, user_id: i64) -> Result<bool, String> {
+    match diesel::update(class_members::table.find((class.id, user_id)).filter(class_members::accepted_at.is_null()))
+        .set(class_members::accepted_at.eq(Utc::now()))
+        .execute(pg_conn) {
+        Ok(accepted) => Ok(accepted > 0),
+        Err(e) => Err(format!("Failed to accept invitation for user {} to class {}: {}", user_id, class.id, e))
+    }
+}
+
+/// Returns whether the user had an invitation to decline.
+pub fn decline_invitation(pg_conn: &PgConnection, class: &Class, user_id: i64) -> Result<bool, String> {
+    match diesel::delete(class_members::table.find((class.id, user_id)).filter(class_members::accepted_at.is_null())).execute(pg_conn) {
+        Ok(declined) => Ok(declined > 0),
+        Err(e) => Err(format!("Failed to decline invitation for user {} to class {}: {}", user_id, class.id, e))
+    }
+}
+
+pub fn remove_member(pg_conn: &PgConnection, class: &Class, user_id: i64) -> Result<(), String> {
+    match diesel::delete(class_members::table.find((class.id, user_id))).execute(pg_conn) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to remove user {} from class {}: {}", user_id, class.id, e))
+    }
+}
+
+pub fn delete(pg_conn: &PgConnection, class: &Class) -> Result<(), String> {
+    match diesel::delete(classes::table.find(class.id)).execute(pg_conn) {
+        Ok(_) => Ok(()),
+        Err(_) => Err("Failed to delete class".to_string())
+    }
+}
+
+fn get_authorized(pg_conn: &PgConnection, title: &str, user: &users::User, action: Action) -> Result<ClassRoster, status::Custom<&'static str>> {
+    let class = get_by_title(pg_conn, title).map_err(|_| status::Custom(Status::NotFound, "Class not found"))?;
+    let roster = get_roster(pg_conn, class).map_err(|e| {
+        eprintln!("{}", e);
+        status::Custom(Status::InternalServerError, "Failed to get class")
+    })?;
+    policy::authorize(user, action, &roster)?;
+    Ok(roster)
+}
+
+#[get("/classes")]
+pub fn classes(db_conn: DbConn, cookies: Cookies) -> Template {
+    let (mut context, _, user) = signed_in_context(&*db_conn, cookies);
+    if let Some(user) = user {
+        match get_by_user(&*db_conn, user.id) {
+            Ok(classes) => context.insert("clean_classes", &CleanClass::from_vec(&classes)),
+            Err(e) => eprintln!("{}", e)
+        }
+        match get_invitations(&*db_conn, user.id) {
+            Ok(invitations) => context.insert("clean_invitations", &CleanClass::from_vec(&invitations)),
+            Err(e) => eprintln!("{}", e)
+        }
+        context.insert("can_create", &(user.role() >= Role::Teacher));
+    }
+    Template::render("classes", &context)
+}
+
+#[get("/createClass")]
+pub fn create_class(db_conn: DbConn, cookies: Cookies) -> Template {
+    let (mut context, _, user) = signed_in_context(&*db_conn, cookies);
+    context.insert("can_create", &user.map_or(false, |user| user.role() >= Role::Teacher));
+    Template::render("create_class", &context)
+}
+
+#[post("/createClass", data = "<class_form>")]
+pub fn submit_class(class_form: LenientForm<FormClass>, db_conn: DbConn, cookies: Cookies) -> Result<Redirect, status::Custom<&'static str>> {
+    let user = users::get_from_cookies(&*db_conn, cookies).map_err(|_| status::Custom(Status::Forbidden, "Must be signed in"))?;
+    policy::require_role(&user, Role::Teacher)?;
+    let form_class = class_form.into_inner();
+    let class = insert(&*db_conn, &NewClass {
+        owner_id: user.id,
+        title: form_class.title,
+        description: form_class.description,
+    })?;
+    Ok(Redirect::to(uri!(class: class.title)))
+}
+
+#[get("/classes/<title>")]
+pub fn class(title: String, db_conn: DbConn, cookies: Cookies) -> Result<Template, status::Custom<&'static str>> {
+    let (mut context, _, user) = signed_in_context(&*db_conn, cookies);
+    let user = user.ok_or(status::Custom(Status::Forbidden, "Must be signed in"))?;
+    let roster = get_authorized(&*db_conn, &title, &user, Action::View)?;
+
+    let permissions = policy::Permissions::new(&Some(user), &roster);
+    context.insert("class", &roster.class);
+    context.insert("clean_class", &CleanClass::from_class(&roster.class));
+    match users::get(&*db_conn, roster.class.owner_id) {
+        Ok(owner) => context.insert("clean_owner", &users::PublicUser::from_user(&owner)),
+        Err(e) => eprintln!("{}", e)
+    }
+    match get_members(&*db_conn, &roster.class, true) {
+        Ok(members) => context.insert("clean_members", &users::PublicUser::from_vec(&members)),
+        Err(e) => eprintln!("{}", e)
+    }
+    if permissions.edit {
+        match get_members(&*db_conn, &roster.class, false) {
+            Ok(invited) => context.insert("clean_invited", &users::PublicUser::from_vec(&invited)),
+            Err(e) => eprintln!("{}", e)
+        }
+    }
+    context.insert("permissions", &permissions);
+    Ok(Template::render("class", &context))
+}
+
+#[post("/classes/<title>/addMember", data = "<member_form>")]
+pub fn add_class_member(title: String, member_form: LenientForm<MemberForm>, db_conn: DbConn, cookies: Cookies) -> Result<Redirect, status::Custom<&'static str>> {
+    let user = users::get_from_cookies(&*db_conn, cookies).map_err(|_| status::Custom(Status::Forbidden, "Must be signed in"))?;
+    let roster = get_authorized(&*db_conn, &title, &user, Action::Edit)?;
+    let member = users::get_by_username(&*db_conn, member_form.username.clone()).map_err(|_| status::Custom(Status::NotFound, "User not found"))?;
+    match invite(&*db_conn, &roster.class, member.id) {
+        Ok(_) => Ok(Redirect::to(uri!(class: roster.class.title))),
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(status::Custom(Status::InternalServerError, "Failed to invite member"))
+        }
+    }
+}
+
+#[post("/classes/<title>/accept")]
+pub fn accept_class_invitation(title: String, db_conn: DbConn, cookies: Cookies) -> Result<Redirect, status::Custom<&'static str>> {
+    let user = users::get_from_cookies(&*db_conn, cookies).map_err(|_| status::Custom(Status::Forbidden, "Must be signed in"))?;
+    let class = get_by_title(&*db_conn, &title).map_err(|_| status::Custom(Status::NotFound, "Class not found"))?;
+    match accept_invitation(&*db_conn, &class, user.id) {
+        Ok(true) => Ok(Redirect::to(uri!(class: class.title))),
+        Ok(false) => Err(status::Custom(Status::NotFound, "Invitation not found")),
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(status::Custom(Status::InternalServerError, "Failed to accept invitation"))
+        }
+    }
+}
+
+#[post("/classes/<title>/decline")]
+pub fn decline_class_invitation(title: String, db_conn: DbConn, cookies: Cookies) -> Result<Redirect, status::Custom<&'static str>> {
+    let user = users::get_from_cookies(&*db_conn, cookies).map_err(|_| status::Custom(Status::Forbidden, "Must be signed in"))?;
+    let class = get_by_title(&*db_conn, &title).map_err(|_| status::Custom(Status::NotFound, "Class not found"))?;
+    match decline_invitation(&*db_conn, &class, user.id) {
+        Ok(true) => Ok(Redirect::to(uri!(classes))),
+        Ok(false) => Err(status::Custom(Status::NotFound, "Invitation not found")),
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(status::Custom(Status::InternalServerError, "Failed to decline invitation"))
+        }
+    }
+}
+
+/// Teachers can remove anyone from their class or take back an invitation, and members can remove themselves.
+#[post("/classes/<title>/removeMember", data = "<member_form>")]
+pub fn remove_class_member(title: String, member_form: LenientForm<MemberForm>, db_conn: DbConn, cookies: Cookies) -> Result<Redirect, status::Custom<&'static str>> {
+    let user = users::get_from_cookies(&*db_conn, cookies).map_err(|_| status::Custom(Status::Forbidden, "Must be signed in"))?;
+    let leaving = member_form.username == user.username;
+    let roster = get_authorized(&*db_conn, &title, &user, if leaving { Action::View } else { Action::Edit })?;
+    let member = users::get_by_username(&*db_conn, member_form.username.clone()).map_err(|_| status::Custom(Status::NotFound, "User not found"))?;
+    match remove_member(&*db_conn, &roster.class, member.id) {
+        Ok(_) if leaving => Ok(Redirect::to(uri!(classes))),
+        Ok(_) => Ok(Redirect::to(uri!(class: roster.class.title))),
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(status::Custom(Status::InternalServerError, "Failed to remove member"))
+        }
+    }
+}
+
+#[post("/classes/<title>/delete", data = "<confirm_user>")]
+pub fn delete_class(title: String, db_conn: DbConn, confirm_user: LenientForm<users::ConfirmUser>, client_info: sessions::ClientInfo, throttle: State<login_throttle::Throttle>) -> Result<Redirect, status::Custom<String>> {
+    let user = users::confirm(&*db_conn, &throttle, &client_info, &confirm_user).map_err(|e| status::Custom(Status::Forbidden, e))?;
+    let roster = get_authorized(&*db_conn, &title, &user, Action::Delete).map_err(|e| status::Custom(e.0, e.1.to_string()))?;
+    match delete(&*db_conn, &roster.class) {
+        Ok(_) => Ok(Redirect::to(uri!(classes))),
+        Err(_) => Err(status::Custom(Status::InternalServerError, "Failed to delete class".to_string()))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db;
+
+    fn create_class(pg_conn: &PgConnection, teacher: &users::User) -> Class {
+        diesel::update(users_table::table.find(teacher.id)).set(users_table::role.eq(Role::Teacher.as_str())).execute(pg_conn).unwrap();
+        insert(pg_conn, &NewClass {
+            owner_id: teacher.id,
+            title: "biology".to_string(),
+            description: String::new(),
+        }).map_err(|e| e.1).unwrap()
+    }
+
+    #[test]
+    fn invited_users_join_once_they_accept() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let teacher = test_db::create_user(&pg_conn, "teacher");
+        let student = test_db::create_user(&pg_conn, "student");
+        let class = create_class(&pg_conn, &teacher);
+
+        invite(&pg_conn, &class, student.id).unwrap();
+        let roster = get_roster(&pg_conn, get_by_title(&pg_conn, "biology").unwrap()).unwrap();
+        assert!(!policy::can(&student, Action::View, &roster));
+        assert!(get_by_user(&pg_conn, student.id).unwrap().is_empty());
+        assert_eq!(get_invitations(&pg_conn, student.id).unwrap().len(), 1);
+
+        assert!(accept_invitation(&pg_conn, &class, student.id).unwrap());
+        assert!(!accept_invitation(&pg_conn, &class, student.id).unwrap());
+        let roster = get_roster(&pg_conn, get_by_title(&pg_conn, "biology").unwrap()).unwrap();
+        assert!(policy::can(&student, Action::View, &roster));
+        assert!(!policy::can(&student, Action::Edit, &roster));
+        assert_eq!(get_by_user(&pg_conn, student.id).unwrap().len(), 1);
+        assert!(get_invitations(&pg_conn, student.id).unwrap().is_empty());
+
+        // Inviting a member again doesn't take them back out
+        invite(&pg_conn, &class, student.id).unwrap();
+        assert_eq!(get_members(&pg_conn, &class, true).unwrap().len(), 1);
+        assert!(!decline_invitation(&pg_conn, &class, student.id).unwrap());
+    }
+
+    #[test]
+    fn declined_invitations_are_gone() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let teacher = test_db::create_user(&pg_conn, "teacher");
+        let student = test_db::create_user(&pg_conn, "student");
+        let class = create_class(&pg_conn, &teacher);
+
+        invite(&pg_conn, &class, student.id).unwrap();
+        assert!(decline_invitation(&pg_conn, &class, student.id).unwrap());
+        assert!(get_invitations(&pg_conn, student.id).unwrap().is_empty());
+        assert!(!accept_invitation(&pg_conn, &class, student.id).unwrap());
+    }
+
+    #[test]
+    fn students_cannot_manage_classes_they_own() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let teacher = test_db::create_user(&pg_conn, "teacher");
+        let class = create_class(&pg_conn, &teacher);
+        let roster = get_roster(&pg_conn, class).unwrap();
+        let teacher = users::get(&pg_conn, teacher.id).unwrap();
+        assert!(policy::can(&teacher, Action::Edit, &roster));
+
+        diesel::update(users_table::table.find(teacher.id)).set(users_table::role.eq(Role::Student.as_str())).execute(&pg_conn).unwrap();
+        let demoted = users::get(&pg_conn, teacher.id).unwrap();
+        assert!(policy::can(&demoted, Action::View, &roster));
+        assert!(!policy::can(&demoted, Action::Edit, &roster));
+        assert!(policy::can(&demoted, Action::Delete, &roster));
+    }
+}
\ No newline at end of file
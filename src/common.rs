@@ -1,31 +1,78 @@
-use ammonia::clean_text;
-use diesel::PgConnection;
+use ammonia::{
+    clean,
+    clean_text,
+};
+
+use diesel::{
+    PgConnection,
+    sql_types::Text,
+};
 
 use percent_encoding::{
     NON_ALPHANUMERIC,
     percent_encode,
 };
 
+use pulldown_cmark::{
+    html::push_html,
+    Parser,
+};
+
+use rand::{
+    distributions::Alphanumeric,
+    Rng,
+    thread_rng,
+};
+
 use regex::Regex;
 
-use rocket::http::Cookies;
-use rocket_contrib::templates::tera::Context;
+use rocket::{
+    http::Cookies,
+    response::{
+        Redirect,
+        status,
+    },
+};
+
+use rocket_contrib::templates::{
+    tera::Context,
+    Template,
+};
 
 use serde::Serialize;
 
 use super::{
+    csrf,
     sessions,
     users,
 };
 
+sql_function!(fn lower(x: Text) -> Text);
+
+/// How many rows a paginated list shows at once.
+pub const PAGE_SIZE: i64 = 25;
+/// Pages past this are treated as this one. It's far past the end of any list, but leaves templates room to count from it.
+const MAX_PAGE: i64 = 1_000_000;
+
+/// A page that might have moved to a new canonical URL, like an app whose title changed.
+/// Only one is made per request, and Rocket can't respond with a boxed template, so the large variant is fine.
+#[allow(clippy::large_enum_variant)]
+#[derive(Responder)]
+pub enum Page {
+    Found(Template),
+    Moved(Redirect),
+    NotFound(status::NotFound<String>),
+}
+
 pub fn default_context() -> Context {
     let mut context = Context::new();
     context.insert("domain", "https://schoolthings.xyz");
     return context;
 }
 
-pub fn signed_in_context(pg_conn: &PgConnection, cookies: Cookies) -> (Context, Option<sessions::Session>, Option<users::User>) {
+pub fn signed_in_context(pg_conn: &PgConnection, mut cookies: Cookies) -> (Context, Option<sessions::Session>, Option<users::User>) {
     let mut context = default_context();
+    context.insert("csrf_token", &csrf::token(&mut cookies));
     match sessions::get_from_cookies(&pg_conn, cookies) {
         Ok(session) => {
             match users::get_from_session(&pg_conn, &session) {
@@ -41,6 +88,56 @@ pub fn signed_in_context(pg_conn: &PgConnection, cookies: Cookies) -> (Context,
     }
 }
 
+pub fn random_string(length: usize) -> String {
+    thread_rng().sample_iter(&Alphanumeric).take(length).collect()
+}
+
+/// Splits a `<id>_<secret>` token, the format emailed links use, into its parts.
+pub fn split_token(token: &str) -> Result<(i64, String), String> {
+    let mut parts = token.splitn(2, '_');
+    match (parts.next().map(|id| id.parse::<i64>()), parts.next()) {
+        (Some(Ok(id)), Some(secret)) => Ok((id, secret.to_string())),
+        _ => Err("Malformed token".to_string())
+    }
+}
+
+/// An `ilike` pattern matching anything containing `search`, with its wildcards taken literally.
+pub fn contains_pattern(search: &str) -> String {
+    format!("%{}%", search.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"))
+}
+
+/// Where a paginated, searchable list is up to, for templates to link to the pages around it.
+#[derive(Serialize)]
+pub struct Pagination {
+    pub page: i64,
+    pub pages: i64,
+    pub total: i64,
+    pub search: Cleaned,
+}
+
+impl Pagination {
+    pub fn new(page: i64, total: i64, search: &String) -> Pagination {
+        Pagination {
+            page,
+            pages: (total + PAGE_SIZE - 1) / PAGE_SIZE,
+            total,
+            search: Cleaned::new(search),
+        }
+    }
+}
+
+/// The zero-based page a `page` query parameter asks for.
+pub fn page_number(page: Option<i64>) -> i64 {
+    page.unwrap_or(0).max(0).min(MAX_PAGE)
+}
+
+/// Renders user written markdown to html that's safe to put in a template as is.
+pub fn render_markdown(markdown: &str) -> String {
+    let mut html = String::new();
+    push_html(&mut html, Parser::new(markdown));
+    clean(&html)
+}
+
 pub fn validate_title(title: &str) -> bool {
     Regex::new(r"^[0-9A-Za-z][0-9A-Za-z_-]{1,}[0-9A-Za-z]$").unwrap().is_match(title)
 }
@@ -0,0 +1,271 @@
+use std::{
+    sync::{
+        mpsc,
+        Mutex,
+    },
+    thread,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use diesel::{
+    prelude::*,
+    PgConnection,
+};
+
+use rocket::fairing::AdHoc;
+use rocket_contrib::databases::database_config;
+
+use super::{
+    apps,
+    schema,
+};
+
+/// Path on an app's domain that is requested during a handshake.
+/// The app must respond with its token as the body, which is checked against the stored hash.
+pub const HANDSHAKE_PATH: &str = "/school_things/handshake";
+
+const DEFAULT_CHECK_INTERVAL: u64 = 60 * 60;
+const HANDSHAKE_TIMEOUT: u64 = 10;
+
+/// Hosts that apps can use over plain http and without a top level domain, like `localhost`.
+/// Set with `insecure_app_hosts`, which is only meant for trying apps out locally.
+pub struct DomainRules {
+    pub insecure_hosts: Vec<String>,
+}
+
+impl DomainRules {
+    /// Whether `domain` is `http://` or `https://` one of the insecure hosts, with an optional port.
+    pub fn allows_insecure(&self, domain: &str) -> bool {
+        let rest = match domain.strip_prefix("http://").or_else(|| domain.strip_prefix("https://")) {
+            Some(rest) => rest,
+            None => return false
+        };
+        let host = match rest.rsplit_once(':') {
+            Some((host, port)) => {
+                if port.is_empty() || !port.chars().all(|c| c.is_ascii_digit()) || port.parse::<u16>().is_err() {
+                    return false
+                }
+                host
+            },
+            None => rest
+        };
+        self.insecure_hosts.iter().any(|insecure_host| insecure_host == host)
+    }
+}
+
+pub struct Verifier {
+    queue: Mutex<mpsc::Sender<i64>>,
+}
+
+impl Verifier {
+    pub fn enqueue(&self, app_id: i64) {
+        match self.queue.lock() {
+            Ok(queue) => {
+                match queue.send(app_id) {
+                    Ok(_) => {},
+                    Err(e) => eprintln!("Failed to queue connection attempt for app {}: {}", app_id, e)
+                }
+            },
+            Err(e) => eprintln!("Failed to lock connection queue: {}", e)
+        }
+    }
+}
+
+/// Requests the handshake path on `domain` and returns the token the app responded with.
+pub fn handshake(domain: &str) -> Result<String, String> {
+    let url = format!("{}{}", domain.trim_end_matches('/'), HANDSHAKE_PATH);
+    match ureq::get(&url).timeout(Duration::from_secs(HANDSHAKE_TIMEOUT)).call() {
+        Ok(response) => {
+            match response.into_string() {
+                Ok(body) => Ok(body.trim().to_string()),
+                Err(e) => Err(format!("Failed to read handshake response: {}", e))
+            }
+        },
+        Err(ureq::Error::Status(code, _)) => Err(format!("App responded with status {}.", code)),
+        Err(e) => Err(format!("Failed to reach app: {}", e))
+    }
+}
+
+pub fn verify(pg_conn: &PgConnection, app_id: i64) -> Result<bool, String> {
+    let app = apps::get(pg_conn, app_id)?;
+    let (connected, connected_error) = match handshake(&app.domain) {
+        Ok(token) => {
+            match apps::token_matches(pg_conn, app.id, &token)? {
+                true => (true, "Connected.".to_string()),
+                false => (false, "App responded with the wrong token.".to_string())
+            }
+        },
+        Err(e) => (false, e)
+    };
+
+    match diesel::update(schema::apps::table.find(app.id)).set((
+        schema::apps::connected.eq(connected),
+        schema::apps::connected_error.eq(connected_error),
+    )).execute(pg_conn) {
+        Ok(_) => Ok(connected),
+        Err(e) => Err(format!("Failed to record connection state for app {}: {}", app.id, e))
+    }
+}
+
+pub fn verify_all(pg_conn: &PgConnection) -> Result<(), String> {
+    for app in apps::get_all(pg_conn)? {
+        match verify(pg_conn, app.id) {
+            Ok(_) => {},
+            Err(e) => eprintln!("{}", e)
+        }
+    }
+    Ok(())
+}
+
+fn run(database_url: String, queue: mpsc::Receiver<i64>, interval: Duration) {
+    // Queued apps don't push back the next full check
+    let mut next_full_check = Instant::now() + interval;
+    loop {
+        let next = queue.recv_timeout(next_full_check.saturating_duration_since(Instant::now()));
+        match next {
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            Err(mpsc::RecvTimeoutError::Timeout) => next_full_check = Instant::now() + interval,
+            Ok(_) => {}
+        }
+
+        let pg_conn = match PgConnection::establish(&database_url) {
+            Ok(pg_conn) => pg_conn,
+            Err(e) => {
+                eprintln!("Connection verifier failed to connect to database: {}", e);
+                continue;
+            }
+        };
+
+        let result = match next {
+            Ok(app_id) => verify(&pg_conn, app_id).map(|_| ()),
+            Err(_) => verify_all(&pg_conn)
+        };
+        match result {
+            Ok(_) => {},
+            Err(e) => eprintln!("Connection verifier: {}", e)
+        }
+    }
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Connection Verifier", |rocket| {
+        let database_url = database_config("postgres", rocket.config()).map(|config| config.url.to_string());
+        let database_url = match database_url {
+            Ok(database_url) => database_url,
+            Err(e) => {
+                eprintln!("Connection verifier couldn't find database config: {}", e);
+                return Err(rocket);
+            }
+        };
+        let interval = match rocket.config().get_int("connection_check_interval") {
+            Ok(seconds) if seconds > 0 => Duration::from_secs(seconds as u64),
+            _ => Duration::from_secs(DEFAULT_CHECK_INTERVAL)
+        };
+
+        let insecure_hosts = match rocket.config().get_slice("insecure_app_hosts") {
+            Ok(hosts) => hosts.iter().filter_map(|host| host.as_str().map(|host| host.to_string())).collect(),
+            Err(_) => Vec::new()
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || run(database_url, receiver, interval));
+
+        Ok(rocket.manage(Verifier {
+            queue: Mutex::new(sender),
+        }).manage(DomainRules {
+            insecure_hosts,
+        }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{
+            BufRead,
+            BufReader,
+            Write,
+        },
+        net::TcpListener,
+        sync::mpsc,
+        thread,
+    };
+
+    use super::*;
+
+    /// Answers one request on a local port with `status` and `body`, sending back the request line.
+    fn serve_once(status: &'static str, body: &'static str) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let domain = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+            }
+            write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body).unwrap();
+            sender.send(request_line.trim().to_string()).unwrap();
+        });
+        (domain, receiver)
+    }
+
+    #[test]
+    fn handshake_returns_the_token() {
+        let (domain, requests) = serve_once("200 OK", " the-token\n");
+        assert_eq!(handshake(&domain), Ok("the-token".to_string()));
+        assert_eq!(requests.recv().unwrap(), format!("GET {} HTTP/1.1", HANDSHAKE_PATH));
+    }
+
+    #[test]
+    fn handshake_reports_error_statuses() {
+        let (domain, _requests) = serve_once("404 Not Found", "");
+        assert_eq!(handshake(&domain), Err("App responded with status 404.".to_string()));
+    }
+
+    #[test]
+    fn handshake_reports_unreachable_apps() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let domain = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        drop(listener);
+        assert!(handshake(&domain).unwrap_err().starts_with("Failed to reach app"));
+    }
+
+    #[test]
+    fn insecure_hosts_are_only_the_configured_ones() {
+        let rules = DomainRules {
+            insecure_hosts: vec!["localhost".to_string(), "127.0.0.1".to_string()],
+        };
+        assert!(rules.allows_insecure("http://localhost"));
+        assert!(rules.allows_insecure("http://127.0.0.1:8080"));
+        assert!(rules.allows_insecure("https://localhost:443"));
+        assert!(!rules.allows_insecure("http://localhost:99999"));
+        assert!(!rules.allows_insecure("http://localhost:"));
+        assert!(!rules.allows_insecure("ftp://localhost"));
+        assert!(!rules.allows_insecure("http://example.com"));
+        assert!(!rules.allows_insecure("http://localhost.example.com"));
+    }
+
+    #[test]
+    fn domains_need_https_and_a_real_tld_unless_insecure() {
+        let none = DomainRules {
+            insecure_hosts: Vec::new(),
+        };
+        let local = DomainRules {
+            insecure_hosts: vec!["localhost".to_string()],
+        };
+        assert!(apps::validate_domain("https://example.com", &none));
+        assert!(!apps::validate_domain("http://example.com", &none));
+        assert!(!apps::validate_domain("http://localhost:8000", &none));
+        assert!(apps::validate_domain("http://localhost:8000", &local));
+    }
+}
\ No newline at end of file
@@ -84,4 +84,7 @@ where
     }
 }
 
-impl<T: Expression<SqlType = Text>> CryptExpressionMethods for T {}
\ No newline at end of file
+impl<T: Expression<SqlType = Text>> CryptExpressionMethods for T {}
+
+sql_function!(fn crypt(password: Text, salt: Text) -> Text);
+sql_function!(fn gen_salt(kind: Text) -> Text);
\ No newline at end of file
@@ -0,0 +1,144 @@
+use rocket::{
+    Data,
+    fairing::AdHoc,
+    http::{
+        ContentType,
+        Cookie,
+        Cookies,
+        Method,
+        SameSite,
+        Status,
+        uri::Origin,
+    },
+    Request,
+    request::FormItems,
+    response::status,
+};
+
+use super::common::*;
+
+pub const COOKIE_NAME: &str = "csrf_token";
+/// Forms send the token in a hidden field with this name.
+/// It has to be the form's first field, since the fairing only gets to see the start of the body.
+pub const FIELD_NAME: &str = "csrf_token";
+/// Scripts can send the token in this header instead.
+pub const HEADER_NAME: &str = "X-CSRF-Token";
+
+const TOKEN_LENGTH: usize = 32;
+const FAILURE_PATH: &str = "/csrfFailure";
+/// Endpoints other servers call directly, which never carry a browser's cookies.
+const EXEMPT_PATHS: &[&str] = &["/oauth/token"];
+
+/// Gets the browser's csrf token, creating one if it doesn't have one yet.
+pub fn token(cookies: &mut Cookies) -> String {
+    match cookies.get_private(COOKIE_NAME) {
+        Some(cookie) => cookie.value().to_string(),
+        None => rotate(cookies)
+    }
+}
+
+/// Replaces the browser's csrf token, such as when a new session starts.
+pub fn rotate(cookies: &mut Cookies) -> String {
+    let token = random_string(TOKEN_LENGTH);
+    cookies.add_private(Cookie::build(COOKIE_NAME, token.clone()).same_site(SameSite::Lax).finish());
+    token
+}
+
+/// Requests that a third-party site can't forge with only the user's cookies.
+/// Bearer tokens aren't sent automatically, and a plain form can't send JSON.
+fn is_exempt(request: &Request) -> bool {
+    match request.method() {
+        Method::Get | Method::Head | Method::Options => return true,
+        _ => {}
+    }
+    if EXEMPT_PATHS.contains(&request.uri().path()) {
+        return true;
+    }
+    request.headers().get_one("Authorization").is_some() || request.content_type().map_or(false, |content_type| content_type.is_json())
+}
+
+/// Finds the token field at the start of a urlencoded or multipart form body.
+fn token_from_body(content_type: &ContentType, body: &[u8]) -> Option<String> {
+    let body = String::from_utf8_lossy(body);
+    if content_type.is_form() {
+        FormItems::from(&*body)
+            .find(|item| item.key.as_str() == FIELD_NAME)
+            .and_then(|item| item.value.url_decode().ok())
+    } else if content_type.is_form_data() {
+        let field = body.find(&format!("name=\"{}\"", FIELD_NAME))?;
+        let value_start = field + body[field..].find("\r\n\r\n")? + 4;
+        let value_length = body[value_start..].find("\r\n")?;
+        Some(body[value_start..value_start + value_length].to_string())
+    } else {
+        None
+    }
+}
+
+/// Compares every byte, so how long the comparison takes doesn't give away how much of the token was right.
+fn tokens_match(expected: &str, given: &str) -> bool {
+    if expected.len() != given.len() {
+        return false;
+    }
+    expected.bytes().zip(given.bytes()).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+/// Reroutes any state-changing request without a matching csrf token to `csrf_failure`.
+pub fn fairing() -> AdHoc {
+    AdHoc::on_request("CSRF Protection", |request, data: &Data| {
+        if is_exempt(request) {
+            return;
+        }
+
+        let expected = request.cookies().get_private(COOKIE_NAME).map(|cookie| cookie.value().to_string());
+        let given = match request.headers().get_one(HEADER_NAME) {
+            Some(token) => Some(token.to_string()),
+            None => request.content_type().and_then(|content_type| token_from_body(content_type, data.peek()))
+        };
+        match (expected, given) {
+            (Some(expected), Some(given)) if tokens_match(&expected, &given) => {},
+            _ => {
+                request.set_method(Method::Get);
+                request.set_uri(Origin::parse(FAILURE_PATH).unwrap());
+            }
+        }
+    })
+}
+
+#[get("/csrfFailure")]
+pub fn csrf_failure() -> status::Custom<&'static str> {
+    status::Custom(Status::Forbidden, "This form has expired or came from another site. Go back, refresh the page and try again.")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_is_read_from_urlencoded_forms() {
+        assert_eq!(token_from_body(&ContentType::Form, b"csrf_token=abc123&title=My+App"), Some("abc123".to_string()));
+        assert_eq!(token_from_body(&ContentType::Form, b"title=My+App&csrf_token=abc%2F123"), Some("abc/123".to_string()));
+        assert_eq!(token_from_body(&ContentType::Form, b"title=My+App"), None);
+        assert_eq!(token_from_body(&ContentType::Form, b""), None);
+    }
+
+    #[test]
+    fn token_is_read_from_multipart_forms() {
+        let body = b"------boundary\r\nContent-Disposition: form-data; name=\"csrf_token\"\r\n\r\nabc123\r\n------boundary\r\nContent-Disposition: form-data; name=\"avatar\"; filename=\"a.png\"\r\nContent-Type: image/png\r\n\r\n\x89PNG";
+        assert_eq!(token_from_body(&ContentType::FormData, body), Some("abc123".to_string()));
+        assert_eq!(token_from_body(&ContentType::FormData, b"------boundary\r\nContent-Disposition: form-data; name=\"avatar\"\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn token_is_not_read_from_other_bodies() {
+        assert_eq!(token_from_body(&ContentType::Plain, b"csrf_token=abc123"), None);
+    }
+
+    #[test]
+    fn tokens_only_match_exactly() {
+        assert!(tokens_match("abc123", "abc123"));
+        assert!(!tokens_match("abc123", "abc124"));
+        assert!(!tokens_match("abc123", "abc12"));
+        assert!(!tokens_match("abc123", ""));
+    }
+}
\ No newline at end of file
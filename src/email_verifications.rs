@@ -0,0 +1,333 @@
+use chrono::{
+    DateTime,
+    Duration,
+    Utc,
+};
+
+use diesel::{
+    prelude::*,
+    PgConnection,
+};
+
+use rocket::{
+    fairing::AdHoc,
+    http::{
+        Cookies,
+        Status,
+    },
+    response::{
+        Redirect,
+        status,
+    },
+    State,
+    uri,
+};
+
+use rocket_contrib::templates::Template;
+
+use super::{
+    audit,
+    common::*,
+    crypt_eq::{
+        crypt,
+        CryptExpressionMethods,
+        gen_salt,
+    },
+    DbConn,
+    mail,
+    schema,
+    schema::email_verifications,
+    sessions,
+    signed_in_context,
+    users,
+};
+
+/// How long a verification link stays usable after it's sent.
+const EXPIRES_IN_HOURS: i64 = 24;
+
+#[derive(Queryable)]
+pub struct EmailVerification {
+    pub id: i64,
+    pub user_id: i64,
+    pub email: String,
+    pub token_hash: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+    /// The email the account had when a change to `email` was asked for. The change only happens once the link is opened.
+    pub replaces_email: Option<String>,
+}
+
+/// Whether accounts need a verified email before creating apps or repos. Set with `require_verified_email`.
+pub struct Requirement {
+    pub required: bool,
+}
+
+impl Requirement {
+    pub fn check(&self, user: &users::User) -> Result<(), status::Custom<&'static str>> {
+        if self.required && user.email_verified_at.is_none() {
+            return Err(status::Custom(Status::Forbidden, "You must verify your email first"))
+        }
+        Ok(())
+    }
+}
+
+/// Creates a verification for `email` and returns the only copy of its plaintext token.
+/// With `replaces_email`, it's a change from that email instead, and any other pending change is dropped.
+pub fn create(pg_conn: &PgConnection, user_id: i64, email: &str, replaces_email: Option<&str>) -> Result<String, String> {
+    let secret = random_string(40);
+    let result = pg_conn.transaction::<_, diesel::result::Error, _>(|| {
+        if replaces_email.is_some() {
+            diesel::update(email_verifications::table
+                .filter(email_verifications::user_id.eq(user_id))
+                .filter(email_verifications::replaces_email.is_not_null())
+                .filter(email_verifications::used_at.is_null()))
+                .set(email_verifications::used_at.eq(Utc::now()))
+                .execute(pg_conn)?;
+        }
+        diesel::insert_into(email_verifications::table).values((
+            email_verifications::user_id.eq(user_id),
+            email_verifications::email.eq(email),
+            email_verifications::token_hash.eq(crypt(secret.as_str(), gen_salt("bf"))),
+            email_verifications::expires_at.eq(Utc::now() + Duration::hours(EXPIRES_IN_HOURS)),
+            email_verifications::replaces_email.eq(replaces_email),
+        )).get_result::<EmailVerification>(pg_conn)
+    });
+    match result {
+        Ok(email_verification) => Ok(format!("{}_{}", email_verification.id, secret)),
+        Err(e) => Err(format!("Failed to create email verification: {}", e))
+    }
+}
+
+/// The change of email the user is waiting to verify, if there is one.
+pub fn get_pending_change(pg_conn: &PgConnection, user: &users::User) -> Result<Option<EmailVerification>, String> {
+    match email_verifications::table
+        .filter(email_verifications::user_id.eq(user.id))
+        .filter(email_verifications::replaces_email.eq(&user.email))
+        .filter(email_verifications::used_at.is_null())
+        .filter(email_verifications::expires_at.gt(Utc::now()))
+        .order(email_verifications::created_at.desc())
+        .first::<EmailVerification>(pg_conn)
+        .optional() {
+        Ok(email_verification) => Ok(email_verification),
+        Err(e) => Err(format!("Failed to get pending email change for user {}: {}", user.id, e))
+    }
+}
+
+/// Emails a verification link to `email`. Used on signup and whenever a user asks to change their email, in which case `replaces_email` is their current one.
+pub fn send(pg_conn: &PgConnection, mail: &mail::Mail, user_id: i64, email: &str, replaces_email: Option<&str>) -> Result<(), String> {
+    let token = create(pg_conn, user_id, email, replaces_email)?;
+    mail.send(email, "Verify your School Things email", format!(
+        "To confirm this is your email, open this link within {} hours:\n{}\n\nIf you didn't sign up for School Things, you can ignore this email.",
+        EXPIRES_IN_HOURS,
+        mail.url(&format!("/verifyEmail?token={}", token)),
+    ))
+}
+
+/// Marks the user's email as verified, or for a change swaps in the new one, as long as the email hasn't changed since the link was sent.
+pub fn verify(pg_conn: &PgConnection, token: &str) -> Result<EmailVerification, String> {
+    let (id, secret) = split_token(token)?;
+    let email_verification = match email_verifications::table
+        .find(id)
+        .filter(email_verifications::token_hash.crypt_eq(&secret))
+        .filter(email_verifications::used_at.is_null())
+        .filter(email_verifications::expires_at.gt(Utc::now()))
+        .first::<EmailVerification>(pg_conn) {
+        Ok(email_verification) => email_verification,
+        Err(_) => return Err("Verification link is invalid or has expired".to_string())
+    };
+
+    let result = pg_conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::update(email_verifications::table.find(email_verification.id))
+            .set(email_verifications::used_at.eq(Utc::now()))
+            .execute(pg_conn)?;
+        match &email_verification.replaces_email {
+            Some(replaces_email) => diesel::update(schema::users::table
+                .find(email_verification.user_id)
+                .filter(schema::users::email.eq(replaces_email)))
+                .set((
+                    schema::users::email.eq(&email_verification.email),
+                    schema::users::email_verified_at.eq(Utc::now()),
+                ))
+                .execute(pg_conn),
+            None => diesel::update(schema::users::table
+                .find(email_verification.user_id)
+                .filter(schema::users::email.eq(&email_verification.email)))
+                .set(schema::users::email_verified_at.eq(Utc::now()))
+                .execute(pg_conn)
+        }
+    });
+    match result {
+        Ok(0) => Err("Your email has changed since this link was sent".to_string()),
+        Ok(_) => Ok(email_verification),
+        Err(e) => {
+            match &*e.to_string() {
+                "duplicate key value violates unique constraint \"users_email_unique_idx\"" => Err("Another account has taken that email since this link was sent".to_string()),
+                _ => Err(format!("Failed to verify email: {}", e))
+            }
+        }
+    }
+}
+
+#[get("/verifyEmail?<token>")]
+pub fn verify_email(token: String, db_conn: DbConn, mut cookies: Cookies, client_info: sessions::ClientInfo) -> Template {
+    let verified = verify(&*db_conn, &token);
+    if let Ok(email_verification) = &verified {
+        if let Some(replaces_email) = &email_verification.replaces_email {
+            match users::get(&*db_conn, email_verification.user_id) {
+                Ok(user) => audit::record(&*db_conn, Some(&user), Some(user.id), audit::Action::EmailChanged, &format!("from {}", replaces_email), &client_info),
+                Err(e) => eprintln!("{}", e)
+            }
+        }
+    }
+    // Verifying can let the user do more, so their session is swapped for a fresh one
+    match (&verified, sessions::get_from_cookie_jar(&*db_conn, &mut cookies)) {
+        (Ok(email_verification), Ok(session)) if session.logged_in_user == Some(email_verification.user_id) => {
+            match sessions::start(&*db_conn, &mut cookies, email_verification.user_id, &client_info) {
+                Ok(_) => {},
+                Err(e) => eprintln!("{}", e)
+            }
+        },
+        _ => {}
+    }
+
+    let (mut context, _, _) = signed_in_context(&*db_conn, cookies);
+    match verified {
+        Ok(_) => context.insert("verified", &true),
+        Err(e) => context.insert("error", &e)
+    }
+    Template::render("verify_email", &context)
+}
+
+#[post("/verifyEmail/resend")]
+pub fn resend_verification(db_conn: DbConn, cookies: Cookies, mail: State<mail::Mail>) -> Result<Redirect, status::Custom<&'static str>> {
+    match users::get_from_cookies(&*db_conn, cookies) {
+        Ok(user) => {
+            if user.email_verified_at.is_some() {
+                return Err(status::Custom(Status::BadRequest, "Email is already verified"))
+            }
+            match send(&*db_conn, &mail, user.id, &user.email, None) {
+                Ok(_) => Ok(Redirect::to(uri!(users::user_profile: user.username))),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    Err(status::Custom(Status::InternalServerError, "Failed to send verification email"))
+                }
+            }
+        },
+        Err(_) => Err(status::Custom(Status::Forbidden, "Failed to authenticate user"))
+    }
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Email Verification", |rocket| {
+        let required = rocket.config().get_bool("require_verified_email").unwrap_or(false);
+        Ok(rocket.manage(Requirement {
+            required,
+        }))
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db;
+
+    fn token_from(body: &str) -> String {
+        let start = body.find("/verifyEmail?token=").expect("No verification link in email") + "/verifyEmail?token=".len();
+        body[start..].split_whitespace().next().unwrap().to_string()
+    }
+
+    #[test]
+    fn verification_email_links_to_a_working_verification() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "verify_email");
+        let mail = mail::Mail::in_memory("noreply@localhost".to_string(), "http://localhost:8000".to_string());
+
+        send(&pg_conn, &mail, user.id, &user.email, None).unwrap();
+        let sent = mail.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, user.email);
+        assert_eq!(sent[0].subject, "Verify your School Things email");
+
+        let token = token_from(&sent[0].body);
+        assert_eq!(verify(&pg_conn, &token).map(|email_verification| email_verification.user_id), Ok(user.id));
+        assert!(users::get(&pg_conn, user.id).unwrap().email_verified_at.is_some());
+        assert!(verify(&pg_conn, &token).is_err());
+    }
+
+    #[test]
+    fn verification_tokens_have_to_match() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "verify_mismatch");
+        let mail = mail::Mail::in_memory("noreply@localhost".to_string(), "http://localhost:8000".to_string());
+
+        send(&pg_conn, &mail, user.id, &user.email, None).unwrap();
+        let token = token_from(&mail.sent()[0].body);
+        assert!(verify(&pg_conn, &format!("{}x", token)).is_err());
+        assert!(users::get(&pg_conn, user.id).unwrap().email_verified_at.is_none());
+    }
+
+    #[test]
+    fn changed_emails_wait_for_the_link() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "change_email");
+        let mail = mail::Mail::in_memory("noreply@localhost".to_string(), "http://localhost:8000".to_string());
+
+        send(&pg_conn, &mail, user.id, "first@example.com", Some(&user.email)).unwrap();
+        send(&pg_conn, &mail, user.id, "second@example.com", Some(&user.email)).unwrap();
+        let sent = mail.sent();
+        assert_eq!(sent[1].to, "second@example.com");
+        assert_eq!(users::get(&pg_conn, user.id).unwrap().email, user.email);
+        assert_eq!(get_pending_change(&pg_conn, &user).unwrap().map(|pending| pending.email), Some("second@example.com".to_string()));
+
+        // Asking again drops the earlier change
+        assert!(verify(&pg_conn, &token_from(&sent[0].body)).is_err());
+        let verified = verify(&pg_conn, &token_from(&sent[1].body)).unwrap();
+        assert_eq!(verified.replaces_email, Some(user.email.clone()));
+        let changed = users::get(&pg_conn, user.id).unwrap();
+        assert_eq!(changed.email, "second@example.com");
+        assert!(changed.email_verified_at.is_some());
+        assert!(get_pending_change(&pg_conn, &changed).unwrap().is_none());
+    }
+
+    #[test]
+    fn old_links_stop_working_once_the_email_changes() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "stale_link");
+        let mail = mail::Mail::in_memory("noreply@localhost".to_string(), "http://localhost:8000".to_string());
+
+        send(&pg_conn, &mail, user.id, &user.email, None).unwrap();
+        send(&pg_conn, &mail, user.id, "new@example.com", Some(&user.email)).unwrap();
+        let sent = mail.sent();
+        verify(&pg_conn, &token_from(&sent[1].body)).unwrap();
+        assert_eq!(verify(&pg_conn, &token_from(&sent[0].body)).err(), Some("Your email has changed since this link was sent".to_string()));
+    }
+
+    #[test]
+    fn changes_fail_if_someone_else_takes_the_email() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "lost_email");
+        let mail = mail::Mail::in_memory("noreply@localhost".to_string(), "http://localhost:8000".to_string());
+
+        send(&pg_conn, &mail, user.id, "taken@example.com", Some(&user.email)).unwrap();
+        test_db::create_user(&pg_conn, "taken");
+        assert!(verify(&pg_conn, &token_from(&mail.sent()[0].body)).is_err());
+        assert_eq!(users::get(&pg_conn, user.id).unwrap().email, user.email);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,414 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+};
+
+use chrono::{
+    DateTime,
+    Duration,
+    Utc,
+};
+
+use diesel::{
+    prelude::*,
+    PgConnection,
+};
+
+use rocket::fairing::AdHoc;
+use serde::Serialize;
+
+use super::{
+    common::*,
+    schema::{
+        login_attempts,
+        login_failures,
+        users,
+    },
+    sessions::ClientInfo,
+};
+
+/// Failures older than this are forgotten, and a locked out key stays locked this long after its last failure.
+const WINDOW_MINUTES: i64 = 15;
+/// The longest a slowed down key has to wait between attempts.
+const MAX_DELAY_SECONDS: i64 = 60;
+
+/// How many failures within the window a key gets before it's slowed down, then locked out.
+struct Policy {
+    delay_after: usize,
+    lock_after: usize,
+}
+
+static USERNAME_POLICY: Policy = Policy {
+    delay_after: 5,
+    lock_after: 10,
+};
+
+/// Many people can share an IP, like a whole school, so it gets more room than a single username.
+static IP_POLICY: Policy = Policy {
+    delay_after: 20,
+    lock_after: 50,
+};
+
+/// Reset emails asked for one address within the window. Every request counts, whether or not the address has an account.
+static RESET_EMAIL_POLICY: Policy = Policy {
+    delay_after: 3,
+    lock_after: 5,
+};
+
+static RESET_IP_POLICY: Policy = Policy {
+    delay_after: 10,
+    lock_after: 30,
+};
+
+/// Keeps track of recent login failures. Picked with the `login_throttle` config option.
+pub trait Limiter: Send + Sync {
+    fn record_failure(&self, pg_conn: &PgConnection, key: &str) -> Result<(), String>;
+    /// Failures for `key` since `since`, oldest first.
+    fn failures(&self, pg_conn: &PgConnection, key: &str, since: DateTime<Utc>) -> Result<Vec<DateTime<Utc>>, String>;
+    fn clear(&self, pg_conn: &PgConnection, key: &str) -> Result<(), String>;
+}
+
+/// Only sees logins handled by this process.
+#[derive(Default)]
+pub struct MemoryLimiter {
+    failures: Mutex<HashMap<String, Vec<DateTime<Utc>>>>,
+}
+
+impl MemoryLimiter {
+    pub fn new() -> MemoryLimiter {
+        MemoryLimiter {
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Limiter for MemoryLimiter {
+    fn record_failure(&self, _pg_conn: &PgConnection, key: &str) -> Result<(), String> {
+        match self.failures.lock() {
+            Ok(mut failures) => {
+                let now = Utc::now();
+                let since = now - Duration::minutes(WINDOW_MINUTES);
+                failures.retain(|_, times| times.last().map_or(false, |last| *last > since));
+                failures.entry(key.to_string()).or_insert_with(Vec::new).push(now);
+                Ok(())
+            },
+            Err(e) => Err(format!("Failed to lock login failures: {}", e))
+        }
+    }
+
+    fn failures(&self, _pg_conn: &PgConnection, key: &str, since: DateTime<Utc>) -> Result<Vec<DateTime<Utc>>, String> {
+        match self.failures.lock() {
+            Ok(failures) => Ok(failures.get(key).map_or(Vec::new(), |times| times.iter().filter(|time| **time > since).cloned().collect())),
+            Err(e) => Err(format!("Failed to lock login failures: {}", e))
+        }
+    }
+
+    fn clear(&self, _pg_conn: &PgConnection, key: &str) -> Result<(), String> {
+        match self.failures.lock() {
+            Ok(mut failures) => {
+                failures.remove(key);
+                Ok(())
+            },
+            Err(e) => Err(format!("Failed to lock login failures: {}", e))
+        }
+    }
+}
+
+/// Shares failures between every instance using the same database.
+pub struct PostgresLimiter;
+
+impl Limiter for PostgresLimiter {
+    fn record_failure(&self, pg_conn: &PgConnection, key: &str) -> Result<(), String> {
+        let since = Utc::now() - Duration::minutes(WINDOW_MINUTES);
+        let result = pg_conn.transaction::<_, diesel::result::Error, _>(|| {
+            diesel::delete(login_failures::table.filter(login_failures::key.eq(key)).filter(login_failures::failed_at.le(since))).execute(pg_conn)?;
+            diesel::insert_into(login_failures::table).values(login_failures::key.eq(key)).execute(pg_conn)
+        });
+        result.map(|_| ()).map_err(|e| format!("Failed to record login failure: {}", e))
+    }
+
+    fn failures(&self, pg_conn: &PgConnection, key: &str, since: DateTime<Utc>) -> Result<Vec<DateTime<Utc>>, String> {
+        match login_failures::table
+            .filter(login_failures::key.eq(key))
+            .filter(login_failures::failed_at.gt(since))
+            .order(login_failures::failed_at.asc())
+            .select(login_failures::failed_at)
+            .load::<DateTime<Utc>>(pg_conn) {
+            Ok(failures) => Ok(failures),
+            Err(e) => Err(format!("Failed to get login failures: {}", e))
+        }
+    }
+
+    fn clear(&self, pg_conn: &PgConnection, key: &str) -> Result<(), String> {
+        match diesel::delete(login_failures::table.filter(login_failures::key.eq(key))).execute(pg_conn) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Failed to clear login failures: {}", e))
+        }
+    }
+}
+
+#[derive(Queryable)]
+pub struct LoginAttempt {
+    pub id: i64,
+    pub user_id: i64,
+    pub ip: Option<String>,
+    pub user_agent: String,
+    pub succeeded: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct CleanLoginAttempt {
+    pub ip: Option<String>,
+    pub user_agent: Cleaned,
+    pub succeeded: bool,
+    pub created_at: String,
+}
+
+impl CleanLoginAttempt {
+    pub fn from_login_attempt(login_attempt: &LoginAttempt) -> CleanLoginAttempt {
+        CleanLoginAttempt {
+            ip: login_attempt.ip.clone(),
+            user_agent: Cleaned::new(&login_attempt.user_agent),
+            succeeded: login_attempt.succeeded,
+            created_at: login_attempt.created_at.format("%Y-%m-%d %H:%M").to_string(),
+        }
+    }
+
+    pub fn from_vec(login_attempts: &[LoginAttempt]) -> Vec<CleanLoginAttempt> {
+        login_attempts.iter().map(CleanLoginAttempt::from_login_attempt).collect()
+    }
+}
+
+pub fn get_recent_attempts(pg_conn: &PgConnection, user_id: i64) -> Result<Vec<LoginAttempt>, String> {
+    match login_attempts::table.filter(login_attempts::user_id.eq(user_id)).order(login_attempts::created_at.desc()).limit(20).load::<LoginAttempt>(pg_conn) {
+        Ok(login_attempts) => Ok(login_attempts),
+        Err(e) => Err(format!("Failed to get login attempts for user {}: {}", user_id, e))
+    }
+}
+
+fn record_attempt(pg_conn: &PgConnection, username: &str, client_info: &ClientInfo, succeeded: bool) -> Result<(), String> {
+    let user_id = match users::table.filter(lower(users::username).eq(username.to_lowercase())).select(users::id).first::<i64>(pg_conn) {
+        Ok(user_id) => user_id,
+        Err(_) => return Ok(())
+    };
+    match diesel::insert_into(login_attempts::table).values((
+        login_attempts::user_id.eq(user_id),
+        login_attempts::ip.eq(&client_info.ip),
+        login_attempts::user_agent.eq(&client_info.user_agent),
+        login_attempts::succeeded.eq(succeeded),
+    )).execute(pg_conn) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to record login attempt: {}", e))
+    }
+}
+
+pub struct Throttle {
+    limiter: Box<dyn Limiter>,
+}
+
+impl Throttle {
+    pub fn new(limiter: Box<dyn Limiter>) -> Throttle {
+        Throttle {
+            limiter,
+        }
+    }
+
+    fn keys(username: &str, client_info: &ClientInfo) -> Vec<(String, &'static Policy)> {
+        let mut keys = vec![(format!("user:{}", username.to_lowercase()), &USERNAME_POLICY)];
+        if let Some(ip) = &client_info.ip {
+            keys.push((format!("ip:{}", ip), &IP_POLICY));
+        }
+        keys
+    }
+
+    /// Returns how long until `key` may try again, if it has to wait at all.
+    fn wait(&self, pg_conn: &PgConnection, key: &str, policy: &Policy) -> Result<Option<(Duration, bool)>, String> {
+        let failures = self.limiter.failures(pg_conn, key, Utc::now() - Duration::minutes(WINDOW_MINUTES))?;
+        let last = match failures.last() {
+            Some(last) => *last,
+            None => return Ok(None)
+        };
+        let (until, locked) = if failures.len() >= policy.lock_after {
+            (last + Duration::minutes(WINDOW_MINUTES), true)
+        } else if failures.len() >= policy.delay_after {
+            let doublings = (failures.len() - policy.delay_after) as u32;
+            (last + Duration::seconds(2i64.saturating_pow(doublings).min(MAX_DELAY_SECONDS)), false)
+        } else {
+            return Ok(None)
+        };
+        let now = Utc::now();
+        match until > now {
+            true => Ok(Some((until - now, locked))),
+            false => Ok(None)
+        }
+    }
+
+    /// Checks whether a login may be attempted, returning a message for the login page if not.
+    pub fn check(&self, pg_conn: &PgConnection, username: &str, client_info: &ClientInfo) -> Result<(), String> {
+        for (key, policy) in Throttle::keys(username, client_info) {
+            match self.wait(pg_conn, &key, policy) {
+                Ok(Some((wait, true))) => return Err(format!("Too many failed logins. Try again in {} minutes.", wait.num_minutes() + 1)),
+                Ok(Some((wait, false))) => return Err(format!("Too many failed logins. Wait {} seconds before trying again.", wait.num_seconds() + 1)),
+                Ok(None) => {},
+                Err(e) => eprintln!("{}", e)
+            }
+        }
+        Ok(())
+    }
+
+    pub fn failed(&self, pg_conn: &PgConnection, username: &str, client_info: &ClientInfo) {
+        for (key, _) in Throttle::keys(username, client_info) {
+            match self.limiter.record_failure(pg_conn, &key) {
+                Ok(_) => {},
+                Err(e) => eprintln!("{}", e)
+            }
+        }
+        match record_attempt(pg_conn, username, client_info, false) {
+            Ok(_) => {},
+            Err(e) => eprintln!("{}", e)
+        }
+    }
+
+    /// Checks whether another reset email may be asked for and counts it if so, returning a message for the page if not.
+    pub fn reset_requested(&self, pg_conn: &PgConnection, email: &str, client_info: &ClientInfo) -> Result<(), String> {
+        let mut keys = vec![(format!("reset_email:{}", email.to_lowercase()), &RESET_EMAIL_POLICY)];
+        if let Some(ip) = &client_info.ip {
+            keys.push((format!("reset_ip:{}", ip), &RESET_IP_POLICY));
+        }
+        for (key, policy) in &keys {
+            match self.wait(pg_conn, key, policy) {
+                Ok(Some((wait, true))) => return Err(format!("Too many reset requests. Try again in {} minutes.", wait.num_minutes() + 1)),
+                Ok(Some((wait, false))) => return Err(format!("Too many reset requests. Wait {} seconds before trying again.", wait.num_seconds() + 1)),
+                Ok(None) => {},
+                Err(e) => eprintln!("{}", e)
+            }
+        }
+        for (key, _) in &keys {
+            match self.limiter.record_failure(pg_conn, key) {
+                Ok(_) => {},
+                Err(e) => eprintln!("{}", e)
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets the username's failures. The IP's are kept so one good account can't reset them.
+    pub fn succeeded(&self, pg_conn: &PgConnection, username: &str, client_info: &ClientInfo) {
+        match self.limiter.clear(pg_conn, &format!("user:{}", username.to_lowercase())) {
+            Ok(_) => {},
+            Err(e) => eprintln!("{}", e)
+        }
+        match record_attempt(pg_conn, username, client_info, true) {
+            Ok(_) => {},
+            Err(e) => eprintln!("{}", e)
+        }
+    }
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Login Throttle", |rocket| {
+        let limiter: Box<dyn Limiter> = match rocket.config().get_str("login_throttle").unwrap_or("memory") {
+            "memory" => Box::new(MemoryLimiter::new()),
+            "postgres" => Box::new(PostgresLimiter),
+            other => {
+                eprintln!("Unknown login throttle {}, expected memory or postgres", other);
+                return Err(rocket);
+            }
+        };
+        Ok(rocket.manage(Throttle::new(limiter)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db;
+
+    fn throttle() -> Throttle {
+        Throttle::new(Box::new(MemoryLimiter::new()))
+    }
+
+    #[test]
+    fn failures_slow_down_then_lock_out() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let throttle = throttle();
+        let client_info = test_db::client_info("192.0.2.1");
+        for _ in 0..USERNAME_POLICY.delay_after - 1 {
+            throttle.failed(&pg_conn, "target", &client_info);
+        }
+        assert!(throttle.check(&pg_conn, "target", &client_info).is_ok());
+
+        throttle.failed(&pg_conn, "target", &client_info);
+        assert!(throttle.check(&pg_conn, "target", &client_info).unwrap_err().starts_with("Too many failed logins. Wait"));
+        // Usernames are throttled separately, and case doesn't matter
+        assert!(throttle.check(&pg_conn, "TARGET", &client_info).is_err());
+        assert!(throttle.check(&pg_conn, "someone_else", &client_info).is_ok());
+
+        for _ in USERNAME_POLICY.delay_after..USERNAME_POLICY.lock_after {
+            throttle.failed(&pg_conn, "target", &client_info);
+        }
+        assert!(throttle.check(&pg_conn, "target", &client_info).unwrap_err().ends_with("minutes."));
+    }
+
+    #[test]
+    fn success_only_clears_the_username() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let throttle = throttle();
+        let client_info = test_db::client_info("192.0.2.1");
+        for i in 0..IP_POLICY.delay_after {
+            throttle.failed(&pg_conn, &format!("user{}", i % 2), &client_info);
+        }
+        throttle.succeeded(&pg_conn, "user0", &client_info);
+        // The ip still has to wait, whichever username it tries
+        assert!(throttle.check(&pg_conn, "user0", &client_info).is_err());
+        assert!(throttle.check(&pg_conn, "anyone", &client_info).is_err());
+        assert!(throttle.check(&pg_conn, "anyone", &test_db::client_info("192.0.2.2")).is_ok());
+    }
+
+    #[test]
+    fn failures_outside_the_window_are_forgotten() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let limiter = MemoryLimiter::new();
+        let old = Utc::now() - Duration::minutes(WINDOW_MINUTES + 1);
+        limiter.failures.lock().unwrap().insert("user:old".to_string(), vec![old; USERNAME_POLICY.lock_after]);
+        assert!(limiter.failures(&pg_conn, "user:old", Utc::now() - Duration::minutes(WINDOW_MINUTES)).unwrap().is_empty());
+
+        // Recording any failure drops keys whose failures have all expired
+        limiter.record_failure(&pg_conn, "user:new").unwrap();
+        assert!(!limiter.failures.lock().unwrap().contains_key("user:old"));
+
+        let throttle = Throttle::new(Box::new(limiter));
+        assert!(throttle.check(&pg_conn, "old", &test_db::client_info("192.0.2.1")).is_ok());
+    }
+
+    #[test]
+    fn reset_requests_are_limited_by_email_and_ip() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let throttle = throttle();
+        let client_info = test_db::client_info("192.0.2.1");
+        for _ in 0..RESET_EMAIL_POLICY.delay_after {
+            assert!(throttle.reset_requested(&pg_conn, "someone@example.com", &client_info).is_ok());
+        }
+        assert!(throttle.reset_requested(&pg_conn, "Someone@example.com", &test_db::client_info("192.0.2.2")).is_err());
+        // Reset requests don't count against logins
+        assert!(throttle.check(&pg_conn, "someone", &client_info).is_ok());
+
+        for i in RESET_EMAIL_POLICY.delay_after..RESET_IP_POLICY.delay_after {
+            assert!(throttle.reset_requested(&pg_conn, &format!("someone{}@example.com", i), &client_info).is_ok());
+        }
+        assert!(throttle.reset_requested(&pg_conn, "anyone@example.com", &client_info).is_err());
+    }
+}
\ No newline at end of file
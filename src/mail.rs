@@ -0,0 +1,282 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{
+        Arc,
+        Mutex,
+    },
+    thread,
+    time::{
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+use lettre::{
+    smtp::authentication::Credentials,
+    SmtpClient,
+    SmtpTransport,
+    Transport,
+};
+use lettre_email::EmailBuilder;
+
+use rocket::fairing::AdHoc;
+
+const DEFAULT_FROM: &str = "noreply@localhost";
+pub const DEFAULT_BASE_URL: &str = "https://schoolthings.xyz";
+const DEFAULT_MAIL_DIR: &str = "mail";
+
+#[derive(Clone, Debug)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Something that can deliver an email. Picked with the `mailer` config option.
+pub trait Mailer: Send + Sync {
+    fn send(&self, from: &str, email: &Email) -> Result<(), String>;
+}
+
+pub struct SmtpMailer {
+    transport: Mutex<SmtpTransport>,
+}
+
+impl SmtpMailer {
+    pub fn new(host: &str, username: Option<String>, password: Option<String>) -> Result<SmtpMailer, String> {
+        let client = match SmtpClient::new_simple(host) {
+            Ok(client) => client,
+            Err(e) => return Err(format!("Failed to create smtp client for {}: {}", host, e))
+        };
+        let client = match (username, password) {
+            (Some(username), Some(password)) => client.credentials(Credentials::new(username, password)),
+            _ => client
+        };
+        Ok(SmtpMailer {
+            transport: Mutex::new(client.transport()),
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, from: &str, email: &Email) -> Result<(), String> {
+        let message = match EmailBuilder::new()
+            .to(email.to.as_str())
+            .from(from)
+            .subject(email.subject.as_str())
+            .text(email.body.as_str())
+            .build() {
+            Ok(message) => message,
+            Err(e) => return Err(format!("Failed to build email: {}", e))
+        };
+        match self.transport.lock() {
+            Ok(mut transport) => {
+                match transport.send(message.into()) {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(format!("Failed to send email: {}", e))
+                }
+            },
+            Err(e) => Err(format!("Failed to lock smtp transport: {}", e))
+        }
+    }
+}
+
+/// Writes every email to its own file in a directory instead of sending it. Handy for development.
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(dir: PathBuf) -> FileMailer {
+        FileMailer {
+            dir,
+        }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, from: &str, email: &Email) -> Result<(), String> {
+        match fs::create_dir_all(&self.dir) {
+            Ok(_) => {},
+            Err(e) => return Err(format!("Failed to create mail directory: {}", e))
+        }
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_nanos()).unwrap_or(0);
+        let path = self.dir.join(format!("{}.eml", timestamp));
+        let contents = format!("From: {}\nTo: {}\nSubject: {}\n\n{}\n", from, email.to, email.subject, email.body);
+        match fs::write(&path, contents) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Failed to write email to {}: {}", path.display(), e))
+        }
+    }
+}
+
+/// Keeps every email in memory so tests can inspect what would have been sent.
+#[derive(Default)]
+pub struct MemoryMailer {
+    sent: Mutex<Vec<Email>>,
+}
+
+impl MemoryMailer {
+    pub fn new() -> MemoryMailer {
+        MemoryMailer {
+            sent: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn sent(&self) -> Vec<Email> {
+        match self.sent.lock() {
+            Ok(sent) => sent.clone(),
+            Err(_) => Vec::new()
+        }
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn send(&self, _from: &str, email: &Email) -> Result<(), String> {
+        match self.sent.lock() {
+            Ok(mut sent) => {
+                sent.push(email.clone());
+                Ok(())
+            },
+            Err(e) => Err(format!("Failed to lock sent mail: {}", e))
+        }
+    }
+}
+
+/// The configured mailer along with what's needed to write links back to the site.
+pub struct Mail {
+    mailer: Arc<dyn Mailer>,
+    /// The same mailer as `mailer` when it's a `MemoryMailer`, so what it sent can be looked at.
+    memory: Option<Arc<MemoryMailer>>,
+    from: String,
+    base_url: String,
+}
+
+impl Mail {
+    pub fn new(mailer: Arc<dyn Mailer>, from: String, base_url: String) -> Mail {
+        Mail {
+            mailer,
+            memory: None,
+            from,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Keeps everything it sends in memory rather than delivering it.
+    pub fn in_memory(from: String, base_url: String) -> Mail {
+        let memory = Arc::new(MemoryMailer::new());
+        Mail {
+            mailer: memory.clone(),
+            memory: Some(memory),
+            from,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Everything sent so far, which is only kept by the memory mailer.
+    pub fn sent(&self) -> Vec<Email> {
+        match &self.memory {
+            Some(memory) => memory.sent(),
+            None => Vec::new()
+        }
+    }
+
+    /// Turns a path like `/resetPassword?token=...` into an absolute url.
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    pub fn send(&self, to: &str, subject: &str, body: String) -> Result<(), String> {
+        self.mailer.send(&self.from, &Email {
+            to: to.to_string(),
+            subject: subject.to_string(),
+            body,
+        })
+    }
+
+    /// Sends from another thread so a slow mail server can't hold up, or be timed through, the request.
+    pub fn send_later(&self, to: &str, subject: &str, body: String) -> thread::JoinHandle<()> {
+        let mailer = self.mailer.clone();
+        let from = self.from.clone();
+        let email = Email {
+            to: to.to_string(),
+            subject: subject.to_string(),
+            body,
+        };
+        thread::spawn(move || {
+            match mailer.send(&from, &email) {
+                Ok(_) => {},
+                Err(e) => eprintln!("{}", e)
+            }
+        })
+    }
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("Mailer", |rocket| {
+        let config = rocket.config();
+        let from = config.get_string("mail_from").unwrap_or_else(|_| DEFAULT_FROM.to_string());
+        let base_url = config.get_string("base_url").unwrap_or_else(|_| DEFAULT_BASE_URL.to_string());
+        let mailer: Arc<dyn Mailer> = match config.get_str("mailer").unwrap_or("file") {
+            "smtp" => {
+                let host = match config.get_str("smtp_host") {
+                    Ok(host) => host,
+                    Err(e) => {
+                        eprintln!("Mailer is smtp but smtp_host isn't set: {}", e);
+                        return Err(rocket);
+                    }
+                };
+                let username = config.get_string("smtp_username").ok();
+                let password = config.get_string("smtp_password").ok();
+                match SmtpMailer::new(host, username, password) {
+                    Ok(mailer) => Arc::new(mailer),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        return Err(rocket);
+                    }
+                }
+            },
+            "file" => Arc::new(FileMailer::new(PathBuf::from(config.get_str("mail_dir").unwrap_or(DEFAULT_MAIL_DIR)))),
+            "memory" => return Ok(rocket.manage(Mail::in_memory(from, base_url))),
+            other => {
+                eprintln!("Unknown mailer {}, expected smtp, file or memory", other);
+                return Err(rocket);
+            }
+        };
+
+        Ok(rocket.manage(Mail::new(mailer, from, base_url)))
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_mail_keeps_what_was_sent() {
+        let mail = Mail::in_memory("noreply@localhost".to_string(), "http://localhost:8000/".to_string());
+        assert_eq!(mail.url("/verifyEmail?token=1_a"), "http://localhost:8000/verifyEmail?token=1_a");
+        mail.send("someone@example.com", "Hello", "Body".to_string()).unwrap();
+        let sent = mail.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "someone@example.com");
+        assert_eq!(sent[0].subject, "Hello");
+        assert_eq!(sent[0].body, "Body");
+    }
+
+    #[test]
+    fn mail_sent_later_arrives() {
+        let mail = Mail::in_memory("noreply@localhost".to_string(), "http://localhost:8000".to_string());
+        mail.send_later("someone@example.com", "Later", "Body".to_string()).join().unwrap();
+        let sent = mail.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].subject, "Later");
+    }
+
+    #[test]
+    fn other_mailers_keep_nothing() {
+        let mail = Mail::new(Arc::new(FileMailer::new(std::env::temp_dir())), "noreply@localhost".to_string(), "http://localhost:8000".to_string());
+        assert!(mail.sent().is_empty());
+    }
+}
\ No newline at end of file
@@ -19,12 +19,32 @@ use rocket_contrib::templates::{
 #[macro_use] extern crate diesel;
 use diesel::PgConnection;
 
+pub mod account;
+pub mod admin;
+pub mod api;
+pub mod api_tokens;
 pub mod apps;
+pub mod audit;
+pub mod avatars;
+pub mod classes;
 pub mod common;
+pub mod connections;
 pub mod crypt_eq;
+pub mod csrf;
+pub mod email_verifications;
+pub mod login_throttle;
+pub mod mail;
+pub mod oauth;
+pub mod oidc;
+pub mod password_resets;
+pub mod policy;
 pub mod repos;
 pub mod schema;
+pub mod search;
 pub mod sessions;
+#[cfg(test)]
+mod test_db;
+pub mod two_factor;
 pub mod users;
 
 use crate::common::*;
@@ -48,14 +68,12 @@ fn favicon() -> Result<NamedFile, status::NotFound<String>> {
 fn sitemap(db_conn: DbConn) -> Template {
     let mut context = default_context();
 
-    match apps::get_all(&*db_conn) {
-        Ok(apps) => context.insert("clean_apps", &apps::CleanApp::from_vec(&apps)),
-        _ => {}
-    };
-    match users::get_all(&*db_conn) {
-        Ok(users) => context.insert("clean_users", &users::CleanUser::from_vec(&users)),
-        _ => {}
-    };
+    if let Ok(apps) = apps::get_visible(&*db_conn) {
+        context.insert("clean_apps", &apps::CleanApp::from_vec(&apps));
+    }
+    if let Ok(users) = users::get_indexed(&*db_conn) {
+        context.insert("public_users", &users::PublicUser::from_vec(&users));
+    }
 
     Template::render("sitemap", &context)
 }
@@ -66,25 +84,137 @@ fn main() {
             home,
             favicon,
             sitemap,
+            csrf::csrf_failure,
             users::login,
             users::signup,
             users::submit_login,
+            oidc::start,
+            oidc::callback,
+            oidc::unlink,
+            oauth::authorize,
+            oauth::submit_authorize,
+            oauth::token,
+            oauth::userinfo,
+            oauth::jwks,
+            oauth::discovery,
             users::submit_signup,
             users::signout,
             users::user_profile,
+            users::set_role,
+            two_factor::login_two_factor,
+            two_factor::submit_login_two_factor,
+            two_factor::two_factor,
+            two_factor::setup_two_factor,
+            two_factor::confirm_two_factor,
+            two_factor::regenerate_recovery_codes,
+            two_factor::disable_two_factor,
+            sessions::active_sessions,
+            sessions::revoke_session,
+            sessions::revoke_all_sessions,
+            audit::security_log,
+            account::settings,
+            account::submit_profile,
+            account::submit_privacy,
+            account::submit_username,
+            account::submit_email,
+            account::submit_password,
+            avatars::avatar,
+            avatars::upload_avatar,
+            avatars::remove_avatar,
+            account::export_data,
+            account::delete_account,
+            account::submit_delete_account,
+            account::cancel_transfer_offer,
+            account::accept_transfer_offer,
+            account::decline_transfer_offer,
+            email_verifications::verify_email,
+            email_verifications::resend_verification,
+            password_resets::forgot_password,
+            password_resets::submit_forgot_password,
+            password_resets::reset_password,
+            password_resets::submit_reset_password,
+            api_tokens::submit_token,
+            api_tokens::revoke_token,
             apps::apps,
             apps::create_app,
             apps::submit_app,
             apps::app,
+            apps::edit_app,
+            apps::submit_edit_app,
             apps::delete_app,
+            apps::retry_connection,
+            apps::rotate_app_token,
             repos::repos,
             repos::create_repo,
             repos::submit_repo,
             repos::repo,
+            repos::edit_repo,
+            repos::submit_edit_repo,
             repos::delete_repo,
             repos::add_app,
+            repos::remove_app,
+            repos::reorder_apps,
+            repos::annotate_app,
+            classes::classes,
+            classes::create_class,
+            classes::submit_class,
+            classes::class,
+            classes::add_class_member,
+            classes::accept_class_invitation,
+            classes::decline_class_invitation,
+            classes::remove_class_member,
+            classes::delete_class,
+            admin::dashboard,
+            admin::admin_users,
+            admin::admin_apps,
+            admin::admin_repos,
+            admin::admin_audit,
+            admin::suspend_user,
+            admin::unsuspend_user,
+            admin::hide_app,
+            admin::unhide_app,
+            admin::reset_app_connection,
+            admin::delete_app,
+            admin::hide_repo,
+            admin::unhide_repo,
+            admin::delete_repo,
+            search::search_page,
         ])
+        .mount("/api/v1", routes![
+            api::get_user,
+            api::search,
+            api::list_apps,
+            api::get_app,
+            api::create_app,
+            api::update_app,
+            api::delete_app,
+            api::rotate_app_token,
+            api::list_repos,
+            api::get_repo,
+            api::create_repo,
+            api::update_repo,
+            api::delete_repo,
+            api::add_repo_app,
+            api::reorder_repo_apps,
+            api::annotate_repo_app,
+            api::remove_repo_app,
+        ])
+        .register(catchers![
+            api::bad_request,
+            api::not_found,
+            api::unprocessable_entity,
+            api::internal_error,
+        ])
+        .attach(csrf::fairing())
         .attach(DbConn::fairing())
+        .attach(connections::fairing())
+        .attach(sessions::proxy_fairing())
+        .attach(sessions::cleanup_fairing())
+        .attach(mail::fairing())
+        .attach(oidc::fairing())
+        .attach(oauth::fairing())
+        .attach(email_verifications::fairing())
+        .attach(login_throttle::fairing())
         .attach(Template::fairing())
         .launch();
 }
\ No newline at end of file
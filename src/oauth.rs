@@ -0,0 +1,888 @@
+use std::fs;
+
+use chrono::{
+    DateTime,
+    Duration,
+    Utc,
+};
+
+use diesel::{
+    prelude::*,
+    PgConnection,
+};
+
+use jsonwebtoken::{
+    Algorithm,
+    encode,
+    EncodingKey,
+    Header,
+};
+
+use percent_encoding::{
+    NON_ALPHANUMERIC,
+    percent_encode,
+};
+
+use rocket::{
+    fairing::AdHoc,
+    http::{
+        Cookies,
+        Status,
+    },
+    Outcome,
+    request::{
+        self,
+        FromRequest,
+        LenientForm,
+        Request,
+    },
+    response::{
+        self,
+        Redirect,
+        Responder,
+        Response,
+        status,
+    },
+    State,
+};
+
+use rocket_contrib::{
+    json::{
+        Json,
+        JsonValue,
+    },
+    templates::Template,
+};
+
+use rsa::{
+    PublicKeyParts,
+    RSAPrivateKey,
+};
+
+use serde::Serialize;
+
+use sha2::{
+    Digest,
+    Sha256,
+};
+
+use super::{
+    apps,
+    common::*,
+    crypt_eq::{
+        crypt,
+        CryptExpressionMethods,
+        gen_salt,
+    },
+    DbConn,
+    mail,
+    repos,
+    schema::{
+        apps as apps_table,
+        oauth_access_tokens,
+        oauth_codes,
+    },
+    signed_in_context,
+    users,
+};
+
+const CODE_MINUTES: i64 = 10;
+const ACCESS_TOKEN_MINUTES: i64 = 60;
+const ID_TOKEN_MINUTES: i64 = 60;
+const SECRET_LENGTH: usize = 40;
+
+/// Every scope an app can ask for, and what the consent screen tells the user about it.
+const SCOPES: &[(&str, &str)] = &[
+    ("openid", "Know who you are on School Things"),
+    ("profile", "See your username and profile link"),
+    ("email", "See your email address and whether it's verified"),
+    ("repos", "See which repos you own"),
+];
+
+/// Signs id tokens with the RSA key from the `oauth_signing_key` config option.
+pub struct Signer {
+    key: EncodingKey,
+    kid: String,
+    n: String,
+    e: String,
+}
+
+impl Signer {
+    /// Reads a PKCS#1 or PKCS#8 PEM encoded RSA private key.
+    pub fn from_pem(pem_bytes: &[u8]) -> Result<Signer, String> {
+        let key = EncodingKey::from_rsa_pem(pem_bytes).map_err(|e| format!("Failed to read signing key: {}", e))?;
+        let parsed = pem::parse(pem_bytes).map_err(|e| format!("Failed to parse signing key: {}", e))?;
+        let private_key = match parsed.tag.as_str() {
+            "RSA PRIVATE KEY" => RSAPrivateKey::from_pkcs1(&parsed.contents),
+            "PRIVATE KEY" => RSAPrivateKey::from_pkcs8(&parsed.contents),
+            other => return Err(format!("Signing key should be an RSA private key, not {}", other))
+        }.map_err(|e| format!("Failed to parse signing key: {}", e))?;
+
+        let n = private_key.n().to_bytes_be();
+        let e = private_key.e().to_bytes_be();
+        Ok(Signer {
+            key,
+            kid: base64::encode_config(&Sha256::digest(&n)[..12], base64::URL_SAFE_NO_PAD),
+            n: base64::encode_config(&n, base64::URL_SAFE_NO_PAD),
+            e: base64::encode_config(&e, base64::URL_SAFE_NO_PAD),
+        })
+    }
+
+    fn sign<T: Serialize>(&self, claims: &T) -> Result<String, String> {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(self.kid.clone());
+        encode(&header, claims, &self.key).map_err(|e| format!("Failed to sign id token: {}", e))
+    }
+}
+
+/// School Things acting as an OpenID Connect provider for its apps. Off unless a signing key is configured.
+pub struct Server {
+    pub issuer: String,
+    pub signer: Option<Signer>,
+}
+
+impl Server {
+    fn signer(&self) -> Result<&Signer, status::Custom<&'static str>> {
+        self.signer.as_ref().ok_or(status::Custom(Status::NotFound, "This server isn't set up to sign in to apps"))
+    }
+}
+
+#[derive(Queryable)]
+pub struct OAuthCode {
+    pub id: i64,
+    pub app_id: i64,
+    pub user_id: i64,
+    pub code_hash: String,
+    pub redirect_uri: String,
+    pub scopes: Vec<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Queryable)]
+pub struct OAuthAccessToken {
+    pub id: i64,
+    pub app_id: i64,
+    pub user_id: i64,
+    pub token_hash: String,
+    pub scopes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// The query an app sends the user to `/oauth/authorize` with.
+/// Lenient, since apps can send parameters like `prompt` that we don't use.
+#[derive(FromForm)]
+pub struct AuthorizeRequest {
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub scope: Option<String>,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+}
+
+/// The consent screen's answer, carrying the original request through hidden fields.
+#[derive(FromForm)]
+pub struct Consent {
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub scope: Option<String>,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+    pub decision: String,
+}
+
+impl Consent {
+    fn request(&self) -> AuthorizeRequest {
+        AuthorizeRequest {
+            response_type: self.response_type.clone(),
+            client_id: self.client_id.clone(),
+            redirect_uri: self.redirect_uri.clone(),
+            scope: self.scope.clone(),
+            state: self.state.clone(),
+            nonce: self.nonce.clone(),
+            code_challenge: self.code_challenge.clone(),
+            code_challenge_method: self.code_challenge_method.clone(),
+        }
+    }
+}
+
+#[derive(FromForm)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    pub code: String,
+    pub redirect_uri: String,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub code_verifier: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct CleanScope {
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+#[derive(Serialize)]
+struct IdClaims {
+    iss: String,
+    sub: String,
+    aud: String,
+    iat: i64,
+    exp: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preferred_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    email_verified: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct TokenResponse {
+    access_token: String,
+    token_type: &'static str,
+    expires_in: i64,
+    scope: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id_token: Option<String>,
+}
+
+/// An error from the token or userinfo endpoints, shaped the way RFC 6749 says clients expect.
+#[derive(Debug)]
+pub struct OAuthError {
+    pub status: Status,
+    pub error: &'static str,
+    pub description: String,
+}
+
+impl OAuthError {
+    pub fn new<S: Into<String>>(status: Status, error: &'static str, description: S) -> OAuthError {
+        OAuthError {
+            status,
+            error,
+            description: description.into(),
+        }
+    }
+
+    fn invalid_grant<S: Into<String>>(description: S) -> OAuthError {
+        OAuthError::new(Status::BadRequest, "invalid_grant", description)
+    }
+
+    fn server_error(e: String) -> OAuthError {
+        eprintln!("{}", e);
+        OAuthError::new(Status::InternalServerError, "server_error", "Something went wrong")
+    }
+}
+
+impl<'r> Responder<'r> for OAuthError {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let body = json!({
+            "error": self.error,
+            "error_description": self.description,
+        });
+        Response::build_from(body.respond_to(request)?).status(self.status).ok()
+    }
+}
+
+/// Token responses hold secrets, so they must never be cached.
+pub struct NoStore<R>(pub R);
+
+impl<'r, R: Responder<'r>> Responder<'r> for NoStore<R> {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        Response::build_from(self.0.respond_to(request)?)
+            .raw_header("Cache-Control", "no-store")
+            .raw_header("Pragma", "no-cache")
+            .ok()
+    }
+}
+
+/// Client credentials sent with HTTP Basic authentication, the way RFC 6749 prefers.
+pub struct BasicCredentials(pub Option<(String, String)>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for BasicCredentials {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<BasicCredentials, ()> {
+        let credentials = request.headers().get_one("Authorization")
+            .and_then(|authorization| authorization.strip_prefix("Basic "))
+            .and_then(|encoded| base64::decode(encoded.trim()).ok())
+            .and_then(|decoded| String::from_utf8(decoded).ok())
+            .and_then(|decoded| {
+                let mut parts = decoded.splitn(2, ':');
+                match (parts.next(), parts.next()) {
+                    (Some(id), Some(secret)) => Some((id.to_string(), secret.to_string())),
+                    _ => None
+                }
+            });
+        Outcome::Success(BasicCredentials(credentials))
+    }
+}
+
+/// The access token from an `Authorization: Bearer` header, if there is one.
+pub struct BearerToken(pub Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for BearerToken {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<BearerToken, ()> {
+        let token = request.headers().get_one("Authorization")
+            .and_then(|authorization| authorization.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_string());
+        Outcome::Success(BearerToken(token))
+    }
+}
+
+pub fn parse_scopes(scope: &Option<String>) -> Result<Vec<String>, String> {
+    let mut scopes = Vec::new();
+    for requested in scope.as_ref().map_or("", |scope| scope.as_str()).split_whitespace() {
+        if !SCOPES.iter().any(|(name, _)| *name == requested) {
+            return Err(format!("Unknown scope {}", requested))
+        }
+        if !scopes.iter().any(|scope| scope == requested) {
+            scopes.push(requested.to_string());
+        }
+    }
+    Ok(scopes)
+}
+
+fn clean_scopes(scopes: &[String]) -> Vec<CleanScope> {
+    SCOPES.iter()
+        .filter(|(name, _)| scopes.iter().any(|scope| scope == name))
+        .map(|(name, description)| CleanScope { name, description })
+        .collect()
+}
+
+/// Redirect uris have to be on the domain the app registered, so a stolen client id can't send codes elsewhere.
+pub fn redirect_uri_allowed(app: &apps::App, redirect_uri: &str) -> bool {
+    let domain = app.domain.trim_end_matches('/');
+    !redirect_uri.contains('#') && (redirect_uri == domain || redirect_uri.starts_with(&format!("{}/", domain)))
+}
+
+fn redirect_with(redirect_uri: &str, params: Vec<(&str, String)>) -> Redirect {
+    let query: Vec<String> = params.iter().map(|(key, value)| format!("{}={}", key, percent_encode(value.as_bytes(), NON_ALPHANUMERIC))).collect();
+    let separator = if redirect_uri.contains('?') { "&" } else { "?" };
+    Redirect::to(format!("{}{}{}", redirect_uri, separator, query.join("&")))
+}
+
+fn redirect_error(request: &AuthorizeRequest, error: &str, description: &str) -> Redirect {
+    let mut params = vec![("error", error.to_string()), ("error_description", description.to_string())];
+    if let Some(state) = &request.state {
+        params.push(("state", state.clone()));
+    }
+    redirect_with(&request.redirect_uri, params)
+}
+
+fn find_app(pg_conn: &PgConnection, client_id: &str) -> Option<apps::App> {
+    client_id.parse::<i64>().ok().and_then(|app_id| apps::get(pg_conn, app_id).ok())
+}
+
+/// The app redeeming a code, as long as an admin hasn't hidden it.
+fn find_client(pg_conn: &PgConnection, client_id: &str) -> Result<apps::App, OAuthError> {
+    match find_app(pg_conn, client_id) {
+        Some(app) if app.hidden_at.is_some() => Err(OAuthError::new(Status::Unauthorized, "invalid_client", "Client has been hidden by an admin")),
+        Some(app) => Ok(app),
+        None => Err(OAuthError::new(Status::Unauthorized, "invalid_client", "Unknown client"))
+    }
+}
+
+/// Why an authorization request was turned down, either sent back to the app or shown to the user.
+type Rejection = Result<Redirect, status::Custom<&'static str>>;
+
+/// Checks an authorization request. Problems that make the redirect uri untrustworthy are shown to the user,
+/// anything else is sent back to the app.
+fn check_request(pg_conn: &PgConnection, request: &AuthorizeRequest) -> Result<(apps::App, Vec<String>), Rejection> {
+    let app = match find_app(pg_conn, &request.client_id) {
+        Some(app) => app,
+        None => return Err(Err(status::Custom(Status::BadRequest, "Unknown app")))
+    };
+    if app.hidden_at.is_some() {
+        return Err(Err(status::Custom(Status::Forbidden, "This app has been hidden by an admin")))
+    }
+    if !redirect_uri_allowed(&app, &request.redirect_uri) {
+        return Err(Err(status::Custom(Status::BadRequest, "The app asked to send you somewhere outside its domain")))
+    }
+    if request.response_type != "code" {
+        return Err(Ok(redirect_error(request, "unsupported_response_type", "Only the code response type is supported")))
+    }
+    match (&request.code_challenge, request.code_challenge_method.as_deref()) {
+        (Some(_), Some("S256")) | (None, None) => {},
+        _ => return Err(Ok(redirect_error(request, "invalid_request", "code_challenge_method must be S256")))
+    }
+    match parse_scopes(&request.scope) {
+        Ok(scopes) => Ok((app, scopes)),
+        Err(e) => Err(Ok(redirect_error(request, "invalid_scope", &e)))
+    }
+}
+
+/// Creates an authorization code and returns the only copy of its value.
+fn create_code(pg_conn: &PgConnection, app: &apps::App, user: &users::User, request: &AuthorizeRequest, scopes: &Vec<String>) -> Result<String, String> {
+    let secret = random_string(SECRET_LENGTH);
+    match diesel::insert_into(oauth_codes::table).values((
+        oauth_codes::app_id.eq(app.id),
+        oauth_codes::user_id.eq(user.id),
+        oauth_codes::code_hash.eq(crypt(secret.as_str(), gen_salt("bf"))),
+        oauth_codes::redirect_uri.eq(&request.redirect_uri),
+        oauth_codes::scopes.eq(scopes),
+        oauth_codes::nonce.eq(&request.nonce),
+        oauth_codes::code_challenge.eq(&request.code_challenge),
+        oauth_codes::expires_at.eq(Utc::now() + Duration::minutes(CODE_MINUTES)),
+    )).get_result::<OAuthCode>(pg_conn) {
+        Ok(code) => Ok(format!("{}_{}", code.id, secret)),
+        Err(e) => Err(format!("Failed to create authorization code: {}", e))
+    }
+}
+
+/// Marks the code used and returns it, or fails if it's wrong, expired or already used.
+fn redeem_code(pg_conn: &PgConnection, app: &apps::App, value: &str) -> Result<OAuthCode, OAuthError> {
+    let (id, secret) = split_token(value).map_err(OAuthError::invalid_grant)?;
+    match diesel::update(oauth_codes::table
+        .find(id)
+        .filter(oauth_codes::app_id.eq(app.id))
+        .filter(oauth_codes::used_at.is_null())
+        .filter(oauth_codes::expires_at.gt(Utc::now()))
+        .filter(oauth_codes::code_hash.crypt_eq(&secret)))
+        .set(oauth_codes::used_at.eq(Utc::now()))
+        .get_result::<OAuthCode>(pg_conn) {
+        Ok(code) => Ok(code),
+        Err(diesel::NotFound) => Err(OAuthError::invalid_grant("Invalid or expired code")),
+        Err(e) => Err(OAuthError::server_error(format!("Failed to redeem authorization code: {}", e)))
+    }
+}
+
+fn create_access_token(pg_conn: &PgConnection, code: &OAuthCode) -> Result<String, String> {
+    let secret = random_string(SECRET_LENGTH);
+    match diesel::insert_into(oauth_access_tokens::table).values((
+        oauth_access_tokens::app_id.eq(code.app_id),
+        oauth_access_tokens::user_id.eq(code.user_id),
+        oauth_access_tokens::token_hash.eq(crypt(secret.as_str(), gen_salt("bf"))),
+        oauth_access_tokens::scopes.eq(&code.scopes),
+        oauth_access_tokens::expires_at.eq(Utc::now() + Duration::minutes(ACCESS_TOKEN_MINUTES)),
+    )).get_result::<OAuthAccessToken>(pg_conn) {
+        Ok(access_token) => Ok(format!("{}_{}", access_token.id, secret)),
+        Err(e) => Err(format!("Failed to create access token: {}", e))
+    }
+}
+
+/// Tokens stop working while their app is hidden.
+pub fn authenticate(pg_conn: &PgConnection, value: &str) -> Result<OAuthAccessToken, String> {
+    let (id, secret) = split_token(value)?;
+    let visible_apps = apps_table::table.filter(apps_table::hidden_at.is_null()).select(apps_table::id);
+    match oauth_access_tokens::table
+        .find(id)
+        .filter(oauth_access_tokens::app_id.eq_any(visible_apps))
+        .filter(oauth_access_tokens::expires_at.gt(Utc::now()))
+        .filter(oauth_access_tokens::token_hash.crypt_eq(&secret))
+        .first::<OAuthAccessToken>(pg_conn) {
+        Ok(access_token) => Ok(access_token),
+        Err(_) => Err("Invalid or expired access token".to_string())
+    }
+}
+
+/// Codes asked for with a challenge need its verifier, and clients that can't prove who they are with a secret have to use PKCE.
+fn check_verifier(code: &OAuthCode, code_verifier: &Option<String>, has_secret: bool) -> Result<(), OAuthError> {
+    match (&code.code_challenge, code_verifier) {
+        (Some(challenge), Some(verifier)) => {
+            match base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD) == *challenge {
+                true => Ok(()),
+                false => Err(OAuthError::invalid_grant("code_verifier doesn't match the code_challenge"))
+            }
+        },
+        (Some(_), None) => Err(OAuthError::invalid_grant("Missing code_verifier")),
+        (None, _) if !has_secret => Err(OAuthError::new(Status::Unauthorized, "invalid_client", "Clients without a secret must use PKCE")),
+        (None, _) => Ok(())
+    }
+}
+
+fn profile_url(mail: &mail::Mail, user: &users::User) -> String {
+    mail.url(&format!("/users/{}", percent_encode(user.username.as_bytes(), NON_ALPHANUMERIC)))
+}
+
+fn id_token(server: &Server, signer: &Signer, mail: &mail::Mail, user: &users::User, code: &OAuthCode) -> Result<String, String> {
+    let has_scope = |scope: &str| code.scopes.iter().any(|test_scope| test_scope == scope);
+    let now = Utc::now();
+    signer.sign(&IdClaims {
+        iss: server.issuer.clone(),
+        sub: user.id.to_string(),
+        aud: code.app_id.to_string(),
+        iat: now.timestamp(),
+        exp: (now + Duration::minutes(ID_TOKEN_MINUTES)).timestamp(),
+        nonce: code.nonce.clone(),
+        preferred_username: if has_scope("profile") { Some(user.username.clone()) } else { None },
+        profile: if has_scope("profile") { Some(profile_url(mail, user)) } else { None },
+        email: if has_scope("email") { Some(user.email.clone()) } else { None },
+        email_verified: if has_scope("email") { Some(user.email_verified_at.is_some()) } else { None },
+    })
+}
+
+#[get("/oauth/authorize?<request..>")]
+pub fn authorize(request: LenientForm<AuthorizeRequest>, db_conn: DbConn, cookies: Cookies, server: State<Server>) -> Result<Result<Template, Redirect>, status::Custom<&'static str>> {
+    server.signer()?;
+    let (app, scopes) = match check_request(&*db_conn, &request) {
+        Ok(checked) => checked,
+        Err(Ok(redirect)) => return Ok(Err(redirect)),
+        Err(Err(e)) => return Err(e)
+    };
+
+    let (mut context, _, signed_in_user) = signed_in_context(&*db_conn, cookies);
+    context.insert("clean_app", &apps::CleanApp::from_app(&app));
+    context.insert("scopes", &clean_scopes(&scopes));
+    context.insert("signed_in", &signed_in_user.is_some());
+    context.insert("response_type", &Cleaned::new(&request.response_type));
+    context.insert("client_id", &Cleaned::new(&request.client_id));
+    context.insert("redirect_uri", &Cleaned::new(&request.redirect_uri));
+    context.insert("scope", &Cleaned::new(&scopes.join(" ")));
+    for (key, value) in [("state", &request.state), ("nonce", &request.nonce), ("code_challenge", &request.code_challenge), ("code_challenge_method", &request.code_challenge_method)] {
+        if let Some(value) = value {
+            context.insert(key, &Cleaned::new(value));
+        }
+    }
+    Ok(Ok(Template::render("oauth_authorize", &context)))
+}
+
+#[post("/oauth/authorize", data = "<consent>")]
+pub fn submit_authorize(consent: LenientForm<Consent>, db_conn: DbConn, cookies: Cookies, server: State<Server>) -> Result<Redirect, status::Custom<&'static str>> {
+    server.signer()?;
+    let user = match users::get_from_cookies(&*db_conn, cookies) {
+        Ok(user) => user,
+        Err(_) => return Err(status::Custom(Status::Forbidden, "Must be signed in"))
+    };
+    let request = consent.request();
+    let (app, scopes) = match check_request(&*db_conn, &request) {
+        Ok(checked) => checked,
+        Err(Ok(redirect)) => return Ok(redirect),
+        Err(Err(e)) => return Err(e)
+    };
+    if consent.decision != "allow" {
+        return Ok(redirect_error(&request, "access_denied", "The user declined"))
+    }
+
+    match create_code(&*db_conn, &app, &user, &request, &scopes) {
+        Ok(code) => {
+            let mut params = vec![("code", code)];
+            if let Some(state) = &request.state {
+                params.push(("state", state.clone()));
+            }
+            Ok(redirect_with(&request.redirect_uri, params))
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            Ok(redirect_error(&request, "server_error", "Failed to create authorization code"))
+        }
+    }
+}
+
+/// Exchanges an authorization code for an access token and, with the openid scope, an id token.
+/// Apps authenticate with their id and token, or with PKCE alone if they can't keep a secret.
+#[post("/oauth/token", data = "<token_request>")]
+pub fn token(token_request: LenientForm<TokenRequest>, basic: BasicCredentials, db_conn: DbConn, server: State<Server>, mail: State<mail::Mail>) -> Result<NoStore<Json<TokenResponse>>, OAuthError> {
+    let signer = server.signer().map_err(|e| OAuthError::new(e.0, "server_error", e.1))?;
+    if token_request.grant_type != "authorization_code" {
+        return Err(OAuthError::new(Status::BadRequest, "unsupported_grant_type", "Only the authorization_code grant is supported"))
+    }
+
+    let (client_id, client_secret) = match (basic.0, &token_request.client_id) {
+        (Some((client_id, client_secret)), _) => (client_id, Some(client_secret)),
+        (None, Some(client_id)) => (client_id.clone(), token_request.client_secret.clone()),
+        (None, None) => return Err(OAuthError::new(Status::Unauthorized, "invalid_client", "Missing client id"))
+    };
+    let app = find_client(&*db_conn, &client_id)?;
+    if let Some(client_secret) = &client_secret {
+        match apps::token_matches(&*db_conn, app.id, client_secret) {
+            Ok(true) => {},
+            Ok(false) => return Err(OAuthError::new(Status::Unauthorized, "invalid_client", "Wrong client secret")),
+            Err(e) => return Err(OAuthError::server_error(e))
+        }
+    }
+
+    let code = redeem_code(&*db_conn, &app, &token_request.code)?;
+    if code.redirect_uri != token_request.redirect_uri {
+        return Err(OAuthError::invalid_grant("redirect_uri doesn't match the authorization request"))
+    }
+    check_verifier(&code, &token_request.code_verifier, client_secret.is_some())?;
+
+    let user = users::get(&*db_conn, code.user_id).map_err(OAuthError::server_error)?;
+    user.check_active().map_err(OAuthError::invalid_grant)?;
+    let access_token = create_access_token(&*db_conn, &code).map_err(OAuthError::server_error)?;
+    let id_token = match code.scopes.iter().any(|scope| scope == "openid") {
+        true => Some(id_token(&server, signer, &mail, &user, &code).map_err(OAuthError::server_error)?),
+        false => None
+    };
+    Ok(NoStore(Json(TokenResponse {
+        access_token,
+        token_type: "Bearer",
+        expires_in: ACCESS_TOKEN_MINUTES * 60,
+        scope: code.scopes.join(" "),
+        id_token,
+    })))
+}
+
+#[get("/oauth/userinfo")]
+pub fn userinfo(bearer: BearerToken, db_conn: DbConn, mail: State<mail::Mail>) -> Result<JsonValue, OAuthError> {
+    let access_token = match &bearer.0 {
+        Some(value) => authenticate(&*db_conn, value).map_err(|e| OAuthError::new(Status::Unauthorized, "invalid_token", e))?,
+        None => return Err(OAuthError::new(Status::Unauthorized, "invalid_token", "Missing bearer token"))
+    };
+    let user = users::get(&*db_conn, access_token.user_id).map_err(OAuthError::server_error)?;
+    user.check_active().map_err(|e| OAuthError::new(Status::Unauthorized, "invalid_token", e))?;
+    user_info(&*db_conn, &mail, &user, &access_token.scopes).map(JsonValue)
+}
+
+/// What the scopes of an access token let its app see about the user.
+fn user_info(pg_conn: &PgConnection, mail: &mail::Mail, user: &users::User, scopes: &[String]) -> Result<serde_json::Value, OAuthError> {
+    let has_scope = |scope: &str| scopes.iter().any(|test_scope| test_scope == scope);
+    let mut info = serde_json::json!({ "sub": user.id.to_string() });
+    if has_scope("profile") {
+        info["preferred_username"] = serde_json::json!(user.username);
+        info["profile"] = serde_json::json!(profile_url(mail, user));
+    }
+    if has_scope("email") {
+        info["email"] = serde_json::json!(user.email);
+        info["email_verified"] = serde_json::json!(user.email_verified_at.is_some());
+    }
+    if has_scope("repos") {
+        let owned = repos::get_by_owner(pg_conn, user.id).map_err(OAuthError::server_error)?;
+        let titles: Vec<&String> = owned.iter().map(|repo| &repo.title).collect();
+        info["repos"] = serde_json::json!(titles);
+    }
+    Ok(info)
+}
+
+#[get("/oauth/jwks")]
+pub fn jwks(server: State<Server>) -> Result<JsonValue, status::Custom<&'static str>> {
+    let signer = server.signer()?;
+    Ok(json!({
+        "keys": [{
+            "kty": "RSA",
+            "use": "sig",
+            "alg": "RS256",
+            "kid": signer.kid,
+            "n": signer.n,
+            "e": signer.e,
+        }]
+    }))
+}
+
+#[get("/.well-known/openid-configuration")]
+pub fn discovery(server: State<Server>) -> Result<JsonValue, status::Custom<&'static str>> {
+    server.signer()?;
+    let scopes: Vec<&str> = SCOPES.iter().map(|(name, _)| *name).collect();
+    Ok(json!({
+        "issuer": server.issuer,
+        "authorization_endpoint": format!("{}/oauth/authorize", server.issuer),
+        "token_endpoint": format!("{}/oauth/token", server.issuer),
+        "userinfo_endpoint": format!("{}/oauth/userinfo", server.issuer),
+        "jwks_uri": format!("{}/oauth/jwks", server.issuer),
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["RS256"],
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+        "code_challenge_methods_supported": ["S256"],
+        "scopes_supported": scopes,
+    }))
+}
+
+pub fn fairing() -> AdHoc {
+    AdHoc::on_attach("OAuth Server", |rocket| {
+        let config = rocket.config();
+        let issuer = config.get_string("base_url").unwrap_or_else(|_| mail::DEFAULT_BASE_URL.to_string()).trim_end_matches('/').to_string();
+        let signer = match config.get_str("oauth_signing_key") {
+            Ok(path) => {
+                match fs::read(path).map_err(|e| format!("Failed to read {}: {}", path, e)).and_then(|pem_bytes| Signer::from_pem(&pem_bytes)) {
+                    Ok(signer) => Some(signer),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        return Err(rocket);
+                    }
+                }
+            },
+            Err(_) => None
+        };
+        Ok(rocket.manage(Server { issuer, signer }))
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connections,
+        test_db,
+    };
+
+    fn add_app(pg_conn: &PgConnection, owner: &users::User) -> (apps::App, String) {
+        let rules = connections::DomainRules {
+            insecure_hosts: Vec::new(),
+        };
+        apps::insert(pg_conn, &apps::NewApp {
+            owner_id: owner.id,
+            title: "client".to_string(),
+            description: String::new(),
+            domain: "https://app.example.com".to_string(),
+        }, &rules).map_err(|e| e.1).unwrap()
+    }
+
+    fn request(app: &apps::App, scope: &str) -> AuthorizeRequest {
+        AuthorizeRequest {
+            response_type: "code".to_string(),
+            client_id: app.id.to_string(),
+            redirect_uri: "https://app.example.com/callback".to_string(),
+            scope: Some(scope.to_string()),
+            state: None,
+            nonce: None,
+            code_challenge: None,
+            code_challenge_method: None,
+        }
+    }
+
+    fn hide(pg_conn: &PgConnection, app: &apps::App) {
+        diesel::update(apps_table::table.find(app.id)).set(apps_table::hidden_at.eq(Utc::now())).execute(pg_conn).unwrap();
+    }
+
+    #[test]
+    fn hidden_apps_cannot_be_authorized() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let owner = test_db::create_user(&pg_conn, "developer");
+        let (app, _) = add_app(&pg_conn, &owner);
+        assert!(check_request(&pg_conn, &request(&app, "openid")).is_ok());
+        assert!(find_client(&pg_conn, &app.id.to_string()).is_ok());
+
+        hide(&pg_conn, &app);
+        match check_request(&pg_conn, &request(&app, "openid")) {
+            Err(Err(e)) => assert_eq!(e.0, Status::Forbidden),
+            _ => panic!("A hidden app was authorized")
+        }
+        assert_eq!(find_client(&pg_conn, &app.id.to_string()).err().map(|e| e.error), Some("invalid_client"));
+    }
+
+    #[test]
+    fn hidden_apps_tokens_stop_working() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let owner = test_db::create_user(&pg_conn, "developer");
+        let (app, _) = add_app(&pg_conn, &owner);
+        let code = create_code(&pg_conn, &app, &owner, &request(&app, "openid"), &vec!["openid".to_string()]).unwrap();
+        let access_token = create_access_token(&pg_conn, &redeem_code(&pg_conn, &app, &code).unwrap()).unwrap();
+        assert!(authenticate(&pg_conn, &access_token).is_ok());
+
+        hide(&pg_conn, &app);
+        assert!(authenticate(&pg_conn, &access_token).is_err());
+    }
+    #[test]
+    fn redirect_uris_stay_on_the_apps_domain() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let owner = test_db::create_user(&pg_conn, "developer");
+        let (app, _) = add_app(&pg_conn, &owner);
+        assert!(redirect_uri_allowed(&app, "https://app.example.com"));
+        assert!(redirect_uri_allowed(&app, "https://app.example.com/callback?from=login"));
+        assert!(!redirect_uri_allowed(&app, "https://app.example.com.evil.com/callback"));
+        assert!(!redirect_uri_allowed(&app, "https://app.example.comevil.com"));
+        assert!(!redirect_uri_allowed(&app, "https://evil.com/https://app.example.com/"));
+        assert!(!redirect_uri_allowed(&app, "http://app.example.com/callback"));
+        assert!(!redirect_uri_allowed(&app, "https://app.example.com/callback#https://evil.com"));
+    }
+
+    #[test]
+    fn codes_only_work_once() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let owner = test_db::create_user(&pg_conn, "developer");
+        let (app, _) = add_app(&pg_conn, &owner);
+        let code = create_code(&pg_conn, &app, &owner, &request(&app, "openid"), &vec!["openid".to_string()]).unwrap();
+        assert!(redeem_code(&pg_conn, &app, &format!("{}x", code)).is_err());
+        assert!(redeem_code(&pg_conn, &app, &code).is_ok());
+        assert_eq!(redeem_code(&pg_conn, &app, &code).err().map(|e| e.error), Some("invalid_grant"));
+    }
+
+    #[test]
+    fn verifiers_have_to_match_the_challenge() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let owner = test_db::create_user(&pg_conn, "developer");
+        let (app, _) = add_app(&pg_conn, &owner);
+        let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk".to_string();
+        let mut pkce_request = request(&app, "openid");
+        pkce_request.code_challenge = Some(base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD));
+        pkce_request.code_challenge_method = Some("S256".to_string());
+        let code = create_code(&pg_conn, &app, &owner, &pkce_request, &vec!["openid".to_string()]).unwrap();
+        let code = redeem_code(&pg_conn, &app, &code).unwrap();
+
+        assert!(check_verifier(&code, &Some(verifier.clone()), false).is_ok());
+        assert_eq!(check_verifier(&code, &Some(format!("{}x", verifier)), false).err().map(|e| e.error), Some("invalid_grant"));
+        // A secret doesn't make up for a missing verifier once a challenge was given
+        assert_eq!(check_verifier(&code, &None, true).err().map(|e| e.error), Some("invalid_grant"));
+    }
+
+    #[test]
+    fn public_clients_need_pkce() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let owner = test_db::create_user(&pg_conn, "developer");
+        let (app, _) = add_app(&pg_conn, &owner);
+        let code = create_code(&pg_conn, &app, &owner, &request(&app, "openid"), &vec!["openid".to_string()]).unwrap();
+        let code = redeem_code(&pg_conn, &app, &code).unwrap();
+
+        assert_eq!(check_verifier(&code, &None, false).err().map(|e| e.error), Some("invalid_client"));
+        assert!(check_verifier(&code, &None, true).is_ok());
+    }
+
+    #[test]
+    fn userinfo_only_shows_granted_scopes() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "signing_in");
+        repos::insert(&pg_conn, &repos::NewRepo {
+            owner_id: user.id,
+            title: "signing_in_repo".to_string(),
+            description: String::new(),
+        }).map_err(|e| e.1).unwrap();
+        let mail = mail::Mail::in_memory("noreply@localhost".to_string(), "http://localhost:8000".to_string());
+        let scopes = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<String>>();
+
+        let info = user_info(&pg_conn, &mail, &user, &scopes(&["openid"])).unwrap();
+        assert_eq!(info, serde_json::json!({ "sub": user.id.to_string() }));
+
+        let info = user_info(&pg_conn, &mail, &user, &scopes(&["openid", "profile"])).unwrap();
+        assert_eq!(info["preferred_username"], "signing_in");
+        assert_eq!(info["profile"], "http://localhost:8000/users/signing%5Fin");
+        assert!(info.get("email").is_none());
+        assert!(info.get("repos").is_none());
+
+        let info = user_info(&pg_conn, &mail, &user, &scopes(&["email", "repos"])).unwrap();
+        assert_eq!(info["email"], "signing_in@example.com");
+        assert_eq!(info["email_verified"], false);
+        assert_eq!(info["repos"], serde_json::json!(["signing_in_repo"]));
+        assert!(info.get("preferred_username").is_none());
+    }
+}
\ No newline at end of file
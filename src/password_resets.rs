@@ -0,0 +1,245 @@
+use std::thread;
+
+use chrono::{
+    DateTime,
+    Duration,
+    Utc,
+};
+
+use diesel::{
+    prelude::*,
+    PgConnection,
+};
+
+use rocket::{
+    http::Cookies,
+    request::LenientForm,
+    State,
+};
+
+use rocket_contrib::templates::Template;
+
+use super::{
+    audit,
+    common::*,
+    crypt_eq::{
+        crypt,
+        CryptExpressionMethods,
+        gen_salt,
+    },
+    DbConn,
+    login_throttle,
+    mail,
+    schema::{
+        password_resets,
+        users,
+    },
+    sessions,
+    signed_in_context,
+};
+
+/// How long a reset link stays usable after it's sent.
+const EXPIRES_IN_MINUTES: i64 = 60;
+
+#[derive(Queryable)]
+pub struct PasswordReset {
+    pub id: i64,
+    pub user_id: i64,
+    pub token_hash: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(FromForm)]
+pub struct ForgotPasswordForm {
+    pub email: String,
+}
+
+#[derive(FromForm)]
+pub struct ResetPasswordForm {
+    pub token: String,
+    pub password: String,
+}
+
+/// Creates a reset for `user_id` and returns the only copy of its plaintext token.
+pub fn create(pg_conn: &PgConnection, user_id: i64) -> Result<String, String> {
+    let secret = random_string(40);
+    match diesel::insert_into(password_resets::table).values((
+        password_resets::user_id.eq(user_id),
+        password_resets::token_hash.eq(crypt(secret.as_str(), gen_salt("bf"))),
+        password_resets::expires_at.eq(Utc::now() + Duration::minutes(EXPIRES_IN_MINUTES)),
+    )).get_result::<PasswordReset>(pg_conn) {
+        Ok(password_reset) => Ok(format!("{}_{}", password_reset.id, secret)),
+        Err(e) => Err(format!("Failed to create password reset: {}", e))
+    }
+}
+
+/// Queues an email with a reset link for `user_id` to `email`.
+pub fn send(pg_conn: &PgConnection, mail: &mail::Mail, user_id: i64, email: &str) -> Result<thread::JoinHandle<()>, String> {
+    let token = create(pg_conn, user_id)?;
+    Ok(mail.send_later(email, "Reset your School Things password", format!(
+        "Someone asked to reset the password for your School Things account.\n\nTo choose a new password, open this link within {} minutes:\n{}\n\nIf this wasn't you, you can ignore this email.",
+        EXPIRES_IN_MINUTES,
+        mail.url(&format!("/resetPassword?token={}", token)),
+    )))
+}
+
+/// Hashes a throwaway token the same way `create` does, so asking for an email without an account takes as long as one with.
+fn pretend_to_create(pg_conn: &PgConnection) {
+    match diesel::select(crypt(random_string(40), gen_salt("bf"))).get_result::<String>(pg_conn) {
+        Ok(_) => {},
+        Err(e) => eprintln!("Failed to hash throwaway reset token: {}", e)
+    }
+}
+
+/// Finds the unused, unexpired reset that `token` belongs to.
+pub fn get_by_token(pg_conn: &PgConnection, token: &str) -> Result<PasswordReset, String> {
+    let (id, secret) = split_token(token)?;
+    match password_resets::table
+        .find(id)
+        .filter(password_resets::token_hash.crypt_eq(&secret))
+        .filter(password_resets::used_at.is_null())
+        .filter(password_resets::expires_at.gt(Utc::now()))
+        .first::<PasswordReset>(pg_conn) {
+        Ok(password_reset) => Ok(password_reset),
+        Err(_) => Err("Reset link is invalid or has expired".to_string())
+    }
+}
+
+/// Sets the new password, uses up every outstanding reset for the user and signs out all of their sessions.
+pub fn reset(pg_conn: &PgConnection, password_reset: &PasswordReset, password: &str) -> Result<(), String> {
+    let result = pg_conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::update(users::table.find(password_reset.user_id))
+            .set(users::password_hash.eq(crypt(password, gen_salt("bf"))))
+            .execute(pg_conn)?;
+        diesel::update(password_resets::table
+            .filter(password_resets::user_id.eq(password_reset.user_id))
+            .filter(password_resets::used_at.is_null()))
+            .set(password_resets::used_at.eq(Utc::now()))
+            .execute(pg_conn)?;
+        sessions::delete_by_user(pg_conn, password_reset.user_id)?;
+        Ok(())
+    });
+    result.map_err(|e| format!("Failed to reset password: {}", e))
+}
+
+#[get("/forgotPassword")]
+pub fn forgot_password(db_conn: DbConn, cookies: Cookies) -> Template {
+    let (context, _, _) = signed_in_context(&*db_conn, cookies);
+    Template::render("forgot_password", &context)
+}
+
+#[post("/forgotPassword", data = "<forgot_password_form>")]
+pub fn submit_forgot_password(forgot_password_form: LenientForm<ForgotPasswordForm>, db_conn: DbConn, cookies: Cookies, client_info: sessions::ClientInfo, mail: State<mail::Mail>, throttle: State<login_throttle::Throttle>) -> Template {
+    let (mut context, _, _) = signed_in_context(&*db_conn, cookies);
+    match throttle.reset_requested(&*db_conn, &forgot_password_form.email, &client_info) {
+        Ok(_) => {},
+        Err(e) => {
+            context.insert("error", &e);
+            return Template::render("forgot_password", &context);
+        }
+    }
+    // Whether or not the email belongs to anyone, the response is the same and takes as long so accounts can't be discovered this way.
+    match users::table.filter(lower(users::email).eq(forgot_password_form.email.to_lowercase())).select((users::id, users::email)).first::<(i64, String)>(&*db_conn) {
+        Ok((user_id, email)) => {
+            match send(&*db_conn, &mail, user_id, &email) {
+                Ok(_) => {},
+                Err(e) => eprintln!("{}", e)
+            }
+        },
+        Err(_) => pretend_to_create(&*db_conn)
+    }
+    context.insert("sent", &true);
+    Template::render("forgot_password", &context)
+}
+
+#[get("/resetPassword?<token>")]
+pub fn reset_password(token: String, db_conn: DbConn, cookies: Cookies) -> Template {
+    let (mut context, _, _) = signed_in_context(&*db_conn, cookies);
+    match get_by_token(&*db_conn, &token) {
+        Ok(_) => context.insert("token", &token),
+        Err(e) => context.insert("error", &e)
+    }
+    Template::render("reset_password", &context)
+}
+
+#[post("/resetPassword", data = "<reset_password_form>")]
+pub fn submit_reset_password(reset_password_form: LenientForm<ResetPasswordForm>, db_conn: DbConn, cookies: Cookies, client_info: sessions::ClientInfo) -> Template {
+    let (mut context, _, _) = signed_in_context(&*db_conn, cookies);
+    match get_by_token(&*db_conn, &reset_password_form.token) {
+        Ok(password_reset) => {
+            match !reset_password_form.password.is_empty() && reset_password_form.password.len() <= 72 {
+                true => {
+                    match reset(&*db_conn, &password_reset, &reset_password_form.password) {
+                        Ok(_) => {
+                            audit::record(&*db_conn, None, Some(password_reset.user_id), audit::Action::PasswordReset, "", &client_info);
+                            context.insert("done", &true);
+                        },
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            context.insert("token", &reset_password_form.token);
+                            context.insert("error", "Failed to reset password");
+                        }
+                    }
+                },
+                false => {
+                    context.insert("token", &reset_password_form.token);
+                    context.insert("error", "Invalid password");
+                }
+            }
+        },
+        Err(e) => context.insert("error", &e)
+    }
+    Template::render("reset_password", &context)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db;
+
+    fn token_from(body: &str) -> String {
+        let start = body.find("/resetPassword?token=").expect("No reset link in email") + "/resetPassword?token=".len();
+        body[start..].split_whitespace().next().unwrap().to_string()
+    }
+
+    #[test]
+    fn reset_email_links_to_a_working_reset() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "reset_email");
+        let mail = mail::Mail::in_memory("noreply@localhost".to_string(), "http://localhost:8000/".to_string());
+
+        send(&pg_conn, &mail, user.id, &user.email).unwrap().join().unwrap();
+        let sent = mail.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, user.email);
+        assert_eq!(sent[0].subject, "Reset your School Things password");
+        assert!(sent[0].body.contains("http://localhost:8000/resetPassword?token="));
+
+        let token = token_from(&sent[0].body);
+        let password_reset = get_by_token(&pg_conn, &token).unwrap();
+        assert_eq!(password_reset.user_id, user.id);
+        reset(&pg_conn, &password_reset, "new password").unwrap();
+        assert!(get_by_token(&pg_conn, &token).is_err());
+    }
+
+    #[test]
+    fn reset_tokens_have_to_match() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "reset_mismatch");
+        let mail = mail::Mail::in_memory("noreply@localhost".to_string(), "http://localhost:8000".to_string());
+
+        send(&pg_conn, &mail, user.id, &user.email).unwrap().join().unwrap();
+        let token = token_from(&mail.sent()[0].body);
+        assert!(get_by_token(&pg_conn, &format!("{}x", token)).is_err());
+        assert!(get_by_token(&pg_conn, "not a token").is_err());
+    }
+}
\ No newline at end of file
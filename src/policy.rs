@@ -0,0 +1,285 @@
+use std::str::FromStr;
+
+use rocket::{
+    http::Status,
+    Outcome,
+    request::{
+        self,
+        FromRequest,
+        Request,
+    },
+    response::status,
+};
+
+use serde::Serialize;
+
+use super::{
+    apps,
+    classes,
+    DbConn,
+    repos,
+    users,
+};
+
+/// What a user is allowed to do beyond their own things. Ordered, so each role can do everything the ones before it can.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Serialize)]
+pub enum Role {
+    Student,
+    Teacher,
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::Student, Role::Teacher, Role::Admin];
+
+    /// How the role is stored in `users.role`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Student => "student",
+            Role::Teacher => "teacher",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = ();
+
+    fn from_str(role: &str) -> Result<Role, ()> {
+        match role {
+            "student" => Ok(Role::Student),
+            "teacher" => Ok(Role::Teacher),
+            "admin" => Ok(Role::Admin),
+            _ => Err(())
+        }
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum Action {
+    View,
+    Edit,
+    Delete,
+    /// See or replace its secrets, like an app's token.
+    ManageCredentials,
+}
+
+/// Something owned by a user, that the policy decides who else can act on.
+pub trait Resource {
+    fn owner_id(&self) -> i64;
+
+    /// Who besides the owner can view it, or `None` if it's public.
+    fn viewer_ids(&self) -> Option<&Vec<i64>> {
+        None
+    }
+
+    /// The role its owner needs to edit it, for things only some roles can run.
+    fn editor_role(&self) -> Role {
+        Role::Student
+    }
+}
+
+impl Resource for apps::App {
+    fn owner_id(&self) -> i64 {
+        self.owner_id
+    }
+}
+
+impl Resource for repos::Repo {
+    fn owner_id(&self) -> i64 {
+        self.owner_id
+    }
+}
+
+impl Resource for classes::ClassRoster {
+    fn owner_id(&self) -> i64 {
+        self.class.owner_id
+    }
+
+    fn viewer_ids(&self) -> Option<&Vec<i64>> {
+        Some(&self.member_ids)
+    }
+
+    fn editor_role(&self) -> Role {
+        Role::Teacher
+    }
+}
+
+/// Owners can do anything to their things, except edit ones their role can't run, like a class after they stop being a teacher.
+/// Admins can moderate anything, but never see someone else's secrets.
+/// Students can own apps and repos and be in classes, but can't create classes or manage their members.
+pub fn can<R: Resource>(user: &users::User, action: Action, resource: &R) -> bool {
+    if resource.owner_id() == user.id {
+        return action != Action::Edit || user.role() >= resource.editor_role()
+    }
+    match action {
+        Action::View => user.role() == Role::Admin || resource.viewer_ids().map_or(true, |viewer_ids| viewer_ids.contains(&user.id)),
+        Action::Edit | Action::Delete => user.role() == Role::Admin,
+        Action::ManageCredentials => false,
+    }
+}
+
+pub fn authorize<R: Resource>(user: &users::User, action: Action, resource: &R) -> Result<(), status::Custom<&'static str>> {
+    match can(user, action, resource) {
+        true => Ok(()),
+        false => Err(status::Custom(Status::Forbidden, "You don't have permission to do that"))
+    }
+}
+
+pub fn require_role(user: &users::User, role: Role) -> Result<(), status::Custom<&'static str>> {
+    match user.role() >= role {
+        true => Ok(()),
+        false => Err(status::Custom(Status::Forbidden, match role {
+            Role::Student => "You must be signed in",
+            Role::Teacher => "Only teachers can do that",
+            Role::Admin => "Only admins can do that",
+        }))
+    }
+}
+
+/// Guards routes only admins can use.
+pub struct Admin(pub users::User);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = &'static str;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Admin, &'static str> {
+        let db_conn = match request.guard::<DbConn>() {
+            Outcome::Success(db_conn) => db_conn,
+            _ => return Outcome::Failure((Status::ServiceUnavailable, "Failed to connect to database"))
+        };
+        Admin::from_user(users::get_from_cookies(&*db_conn, request.cookies()))
+    }
+}
+
+impl Admin {
+    /// Lets the signed in user through if they're an admin.
+    pub fn from_user(user: Result<users::User, String>) -> request::Outcome<Admin, &'static str> {
+        match user {
+            Ok(user) if user.role() == Role::Admin => Outcome::Success(Admin(user)),
+            Ok(_) => Outcome::Failure((Status::Forbidden, "Only admins can do that")),
+            Err(_) => Outcome::Failure((Status::Forbidden, "Must be signed in"))
+        }
+    }
+}
+
+/// What the signed in user can do to a resource, for templates to decide which controls to show.
+#[derive(Serialize)]
+pub struct Permissions {
+    pub owner: bool,
+    pub edit: bool,
+    pub delete: bool,
+    pub manage_credentials: bool,
+}
+
+impl Permissions {
+    pub fn new<R: Resource>(user: &Option<users::User>, resource: &R) -> Permissions {
+        match user {
+            Some(user) => Permissions {
+                owner: resource.owner_id() == user.id,
+                edit: can(user, Action::Edit, resource),
+                delete: can(user, Action::Delete, resource),
+                manage_credentials: can(user, Action::ManageCredentials, resource),
+            },
+            None => Permissions {
+                owner: false,
+                edit: false,
+                delete: false,
+                manage_credentials: false,
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db;
+
+    fn app(owner_id: i64) -> apps::App {
+        apps::App {
+            id: 1,
+            owner_id,
+            title: "app".to_string(),
+            description: String::new(),
+            domain: "https://app.example.com".to_string(),
+            token_hash: String::new(),
+            connected: false,
+            connected_error: String::new(),
+            hidden_at: None,
+        }
+    }
+
+    fn repo(owner_id: i64) -> repos::Repo {
+        repos::Repo {
+            id: 1,
+            owner_id,
+            title: "repo".to_string(),
+            description: String::new(),
+            hidden_at: None,
+        }
+    }
+
+    fn with_role(mut user: users::User, role: Role) -> users::User {
+        user.role = role.as_str().to_string();
+        user
+    }
+
+    #[test]
+    fn roles_are_parsed() {
+        for role in Role::ALL.iter() {
+            assert_eq!(role.as_str().parse::<Role>(), Ok(*role));
+        }
+        assert!("root".parse::<Role>().is_err());
+    }
+
+    #[test]
+    fn owners_can_do_anything_to_their_apps_and_repos() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let owner = test_db::create_user(&pg_conn, "owner");
+
+        for action in [Action::View, Action::Edit, Action::Delete, Action::ManageCredentials] {
+            assert!(can(&owner, action, &app(owner.id)));
+            assert!(can(&owner, action, &repo(owner.id)));
+        }
+    }
+
+    #[test]
+    fn others_can_only_view_apps_and_repos() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let owner = test_db::create_user(&pg_conn, "owner");
+        let teacher = with_role(test_db::create_user(&pg_conn, "teacher"), Role::Teacher);
+
+        assert!(can(&teacher, Action::View, &app(owner.id)));
+        assert!(can(&teacher, Action::View, &repo(owner.id)));
+        for action in [Action::Edit, Action::Delete, Action::ManageCredentials] {
+            assert!(!can(&teacher, action, &app(owner.id)));
+            assert!(!can(&teacher, action, &repo(owner.id)));
+        }
+    }
+
+    #[test]
+    fn admins_moderate_but_never_see_secrets() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let owner = test_db::create_user(&pg_conn, "owner");
+        let admin = with_role(test_db::create_user(&pg_conn, "admin"), Role::Admin);
+
+        for action in [Action::View, Action::Edit, Action::Delete] {
+            assert!(can(&admin, action, &app(owner.id)));
+            assert!(can(&admin, action, &repo(owner.id)));
+        }
+        assert!(!can(&admin, Action::ManageCredentials, &app(owner.id)));
+        assert!(!can(&admin, Action::ManageCredentials, &repo(owner.id)));
+        assert!(!Permissions::new(&Some(admin), &app(owner.id)).manage_credentials);
+    }
+}
\ No newline at end of file
@@ -1,6 +1,17 @@
+use std::collections::HashMap;
+
+use chrono::{
+    DateTime,
+    Utc,
+};
+
 use diesel::{
     prelude::*,
     PgConnection,
+    result::{
+        DatabaseErrorKind,
+        Error::DatabaseError,
+    },
 };
 
 use rocket::{
@@ -8,53 +19,156 @@ use rocket::{
         Cookies,
         Status,
     },
-    request::Form,
+    request::LenientForm,
     response::{
         Redirect,
         status,
     },
+    State,
     uri,
 };
 
 use rocket_contrib::templates::Template;
-use serde::Serialize;
+use serde::{
+    Deserialize,
+    Serialize,
+};
 
 use super::{
     apps,
+    audit,
     common::*,
-    crypt_eq::CryptExpressionMethods,
     DbConn,
+    email_verifications,
+    login_throttle,
+    policy::{
+        self,
+        Action,
+    },
     schema,
-    schema::repos,
+    schema::{
+        repo_apps,
+        repo_redirects,
+        repos,
+    },
+    sessions,
     signed_in_context,
     users,
 };
 
+#[derive(Queryable)]
+pub struct RepoApp {
+    pub repo_id: i64,
+    pub app_id: i64,
+    pub position: i32,
+    pub added_at: DateTime<Utc>,
+    pub note: String,
+}
+
+#[derive(Serialize)]
+pub struct CleanRepoApp {
+    pub app: apps::CleanApp,
+    pub position: i32,
+    pub note: Cleaned,
+}
+
+impl CleanRepoApp {
+    pub fn from_entry(entry: &(RepoApp, apps::App)) -> CleanRepoApp {
+        let (repo_app, app) = entry;
+        CleanRepoApp {
+            app: apps::CleanApp::from_app(app),
+            position: repo_app.position,
+            note: Cleaned::new(&repo_app.note),
+        }
+    }
+
+    pub fn from_vec(entries: &[(RepoApp, apps::App)]) -> Vec<CleanRepoApp> {
+        entries.iter().map(CleanRepoApp::from_entry).collect()
+    }
+}
+
 #[derive(Queryable, Serialize)]
 pub struct Repo {
     pub id: i64,
     pub owner_id: i64,
     pub title: String,
     pub description: String,
-    pub apps: Vec<i64>,
+    pub hidden_at: Option<DateTime<Utc>>,
 }
 
 impl Repo {
-    pub fn remove_app(&self, pg_conn: &PgConnection, app_id: i64) -> Result<(), String> {
-        match repos::table.filter(repos::id.eq(self.id)).first::<Repo>(&*pg_conn) {
-            Ok(mut repo) => {
-                for (i, &test_app_id) in repo.apps.iter().enumerate() {
-                    if test_app_id == app_id {
-                        repo.apps.remove(i);
-                        match diesel::update(repos::table.filter(repos::id.eq(repo.id))).set(repos::apps.eq(repo.apps)).execute(&*pg_conn) {
-                            Ok(_) => return Ok(()),
-                            Err(_) => return Err("Failed to remove app from repo".to_string())
-                        }
-                    }
-                }
-                return Err("Repo does not contain app".to_string())
-            },
-            Err(_) => Err("Failed to get updated repo to delete app from.".to_string())
+    /// Appends the app to the end of the repo.
+    pub fn add_app(&self, pg_conn: &PgConnection, app_id: i64) -> Result<(), status::Custom<&'static str>> {
+        let result = pg_conn.transaction::<_, diesel::result::Error, _>(|| {
+            let last_position = repo_apps::table
+                .filter(repo_apps::repo_id.eq(self.id))
+                .select(diesel::dsl::max(repo_apps::position))
+                .first::<Option<i32>>(pg_conn)?;
+            diesel::insert_into(repo_apps::table).values((
+                repo_apps::repo_id.eq(self.id),
+                repo_apps::app_id.eq(app_id),
+                repo_apps::position.eq(last_position.unwrap_or(0) + 1),
+            )).execute(pg_conn)
+        });
+        match result {
+            Ok(_) => Ok(()),
+            Err(DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => Err(status::Custom(Status::BadRequest, "Repo already contains app")),
+            Err(e) => {
+                eprintln!("Failed to add app {} to repo {}: {}", app_id, self.id, e);
+                Err(status::Custom(Status::InternalServerError, "Failed to add app."))
+            }
+        }
+    }
+
+    pub fn remove_app(&self, pg_conn: &PgConnection, app_id: i64) -> Result<(), status::Custom<&'static str>> {
+        match diesel::delete(repo_apps::table.find((self.id, app_id))).execute(pg_conn) {
+            Ok(0) => Err(status::Custom(Status::NotFound, "Repo does not contain app")),
+            Ok(_) => Ok(()),
+            Err(e) => {
+                eprintln!("Failed to remove app {} from repo {}: {}", app_id, self.id, e);
+                Err(status::Custom(Status::InternalServerError, "Failed to remove app from repo"))
+            }
+        }
+    }
+
+    /// Puts the repo's apps in the order given. `app_ids` must contain every app in the repo exactly once.
+    pub fn reorder_apps(&self, pg_conn: &PgConnection, app_ids: &[i64]) -> Result<(), status::Custom<&'static str>> {
+        let result = pg_conn.transaction::<_, diesel::result::Error, _>(|| {
+            let mut current_ids = repo_apps::table
+                .filter(repo_apps::repo_id.eq(self.id))
+                .select(repo_apps::app_id)
+                .load::<i64>(pg_conn)?;
+            let mut new_ids = app_ids.to_vec();
+            current_ids.sort();
+            new_ids.sort();
+            if current_ids != new_ids {
+                return Ok(false)
+            }
+
+            for (i, &app_id) in app_ids.iter().enumerate() {
+                diesel::update(repo_apps::table.find((self.id, app_id)))
+                    .set(repo_apps::position.eq(i as i32 + 1))
+                    .execute(pg_conn)?;
+            }
+            Ok(true)
+        });
+        match result {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(status::Custom(Status::BadRequest, "New order must contain every app in the repo exactly once")),
+            Err(e) => {
+                eprintln!("Failed to reorder apps in repo {}: {}", self.id, e);
+                Err(status::Custom(Status::InternalServerError, "Failed to reorder apps."))
+            }
+        }
+    }
+
+    pub fn annotate_app(&self, pg_conn: &PgConnection, app_id: i64, note: &str) -> Result<(), status::Custom<&'static str>> {
+        if note.len() > 256 {return Err(status::Custom(Status::BadRequest, "Note is too long - max 256 characters"))}
+
+        match diesel::update(repo_apps::table.find((self.id, app_id))).set(repo_apps::note.eq(note)).execute(pg_conn) {
+            Ok(0) => Err(status::Custom(Status::NotFound, "Repo does not contain app")),
+            Ok(_) => Ok(()),
+            Err(_) => Err(status::Custom(Status::InternalServerError, "Failed to update note."))
         }
     }
 }
@@ -108,6 +222,20 @@ pub struct NewRepo {
     pub description: String,
 }
 
+/// Fields left out aren't changed.
+#[derive(Deserialize, FromForm)]
+pub struct RepoChanges {
+    pub title: Option<String>,
+    pub description: Option<String>,
+}
+
+pub fn get(pg_conn: &PgConnection, repo_id: i64) -> Result<Repo, String> {
+    match repos::table.find(repo_id).first::<Repo>(pg_conn) {
+        Ok(repo) => Ok(repo),
+        Err(e) => Err(format!("Failed to get repo {}: {}", repo_id, e))
+    }
+}
+
 pub fn get_by_title(pg_conn: &PgConnection, title: &str) -> Result<Repo, String> {
     match repos::table.filter(
         repos::title.eq(title)
@@ -117,6 +245,26 @@ pub fn get_by_title(pg_conn: &PgConnection, title: &str) -> Result<Repo, String>
     }
 }
 
+/// Gets the repo that used to have `title` before it was renamed.
+pub fn get_by_old_title(pg_conn: &PgConnection, title: &str) -> Result<Repo, String> {
+    match repo_redirects::table
+        .inner_join(repos::table)
+        .filter(lower(repo_redirects::old_title).eq(title.to_lowercase()))
+        .select(repos::all_columns)
+        .first::<Repo>(pg_conn) {
+        Ok(repo) => Ok(repo),
+        Err(e) => Err(format!("Failed to get repo by old title {}", e))
+    }
+}
+
+fn add_redirect(pg_conn: &PgConnection, old_title: &str, repo_id: i64) -> QueryResult<usize> {
+    diesel::delete(repo_redirects::table.filter(lower(repo_redirects::old_title).eq(old_title.to_lowercase()))).execute(pg_conn)?;
+    diesel::insert_into(repo_redirects::table).values((
+        repo_redirects::old_title.eq(old_title),
+        repo_redirects::repo_id.eq(repo_id),
+    )).execute(pg_conn)
+}
+
 pub fn get_all(pg_conn: &PgConnection) -> Result<Vec<Repo>, String> {
     match repos::table.load::<Repo>(pg_conn) {
         Ok(repos) => Ok(repos),
@@ -124,29 +272,187 @@ pub fn get_all(pg_conn: &PgConnection) -> Result<Vec<Repo>, String> {
     }
 }
 
-pub fn get_apps(pg_conn: &PgConnection, repo: &Repo) -> Result<Vec<apps::App>, String> {
-    let mut apps = Vec::new();
-    for app_id in &repo.apps {
-        match schema::apps::table.filter(schema::apps::id.eq(app_id)).first(&*pg_conn) {
-            Ok(app) => apps.push(app),
-            Err(e) => {
-                eprintln!("Failed to get app for repo. Repo ID: {}, App ID: {}, Error: {}", repo.id, app_id, e);
-                return Err(format!("Failed to get app for repo. Repo ID: {}, App ID: {}", repo.id, app_id))
+/// Every repo that hasn't been hidden by an admin, for public listings.
+pub fn get_visible(pg_conn: &PgConnection) -> Result<Vec<Repo>, String> {
+    match repos::table.filter(repos::hidden_at.is_null()).load::<Repo>(pg_conn) {
+        Ok(repos) => Ok(repos),
+        Err(e) => Err(format!("Failed to get repos {}", e))
+    }
+}
+
+pub fn get_visible_by_owner(pg_conn: &PgConnection, owner_id: i64) -> Result<Vec<Repo>, String> {
+    match repos::table.filter(repos::owner_id.eq(owner_id)).filter(repos::hidden_at.is_null()).order(repos::title.asc()).load::<Repo>(pg_conn) {
+        Ok(repos) => Ok(repos),
+        Err(e) => Err(format!("Failed to get repos owned by {}: {}", owner_id, e))
+    }
+}
+
+/// The apps most recently added to the owner's repos, newest first, leaving out anything hidden.
+pub fn get_recent_additions_by_owner(pg_conn: &PgConnection, owner_id: i64, limit: i64) -> Result<Vec<(Repo, apps::App, DateTime<Utc>)>, String> {
+    match repo_apps::table
+        .inner_join(repos::table)
+        .inner_join(schema::apps::table)
+        .filter(repos::owner_id.eq(owner_id))
+        .filter(repos::hidden_at.is_null())
+        .filter(schema::apps::hidden_at.is_null())
+        .order(repo_apps::added_at.desc())
+        .limit(limit)
+        .select((repos::all_columns, schema::apps::all_columns, repo_apps::added_at))
+        .load::<(Repo, apps::App, DateTime<Utc>)>(pg_conn) {
+        Ok(additions) => Ok(additions),
+        Err(e) => Err(format!("Failed to get recent additions to repos owned by {}: {}", owner_id, e))
+    }
+}
+
+/// One page of the repos, hidden ones included, whose title or description contains `search`, along with how many match in total.
+pub fn get_page(pg_conn: &PgConnection, search: &str, page: i64) -> Result<(Vec<Repo>, i64), String> {
+    let pattern = contains_pattern(search);
+    let total = repos::table
+        .filter(repos::title.ilike(&pattern).or(repos::description.ilike(&pattern)))
+        .count()
+        .get_result::<i64>(pg_conn);
+    let repos = repos::table
+        .filter(repos::title.ilike(&pattern).or(repos::description.ilike(&pattern)))
+        .order(repos::id.desc())
+        .limit(PAGE_SIZE)
+        .offset(page.saturating_mul(PAGE_SIZE))
+        .load::<Repo>(pg_conn);
+    match (repos, total) {
+        (Ok(repos), Ok(total)) => Ok((repos, total)),
+        (Err(e), _) | (_, Err(e)) => Err(format!("Failed to get page {} of repos: {}", page, e))
+    }
+}
+
+/// Hides the repo from everyone but its owner and admins, or shows it again.
+pub fn set_hidden(pg_conn: &PgConnection, repo_id: i64, hidden: bool) -> Result<(), String> {
+    let hidden_at = if hidden { Some(Utc::now()) } else { None };
+    match diesel::update(repos::table.find(repo_id)).set(repos::hidden_at.eq(hidden_at)).execute(pg_conn) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to set repo {} hidden: {}", repo_id, e))
+    }
+}
+
+pub fn get_by_owner(pg_conn: &PgConnection, owner_id: i64) -> Result<Vec<Repo>, String> {
+    match repos::table.filter(repos::owner_id.eq(owner_id)).load::<Repo>(pg_conn) {
+        Ok(repos) => Ok(repos),
+        Err(e) => Err(format!("Failed to get repos owned by {}: {}", owner_id, e))
+    }
+}
+
+/// Gets the repo's apps in order, each with its entry in the repo.
+/// Apps an admin hid are left out unless `include_hidden`, which should only be for the repo's owner and admins.
+pub fn get_entries(pg_conn: &PgConnection, repo: &Repo, include_hidden: bool) -> Result<Vec<(RepoApp, apps::App)>, String> {
+    let mut query = repo_apps::table
+        .inner_join(schema::apps::table)
+        .filter(repo_apps::repo_id.eq(repo.id))
+        .into_boxed();
+    if !include_hidden {
+        query = query.filter(schema::apps::hidden_at.is_null());
+    }
+    match query
+        .order(repo_apps::position.asc())
+        .load::<(RepoApp, apps::App)>(pg_conn) {
+        Ok(entries) => Ok(entries),
+        Err(e) => {
+            eprintln!("Failed to get apps for repo. Repo ID: {}, Error: {}", repo.id, e);
+            Err(format!("Failed to get apps for repo. Repo ID: {}", repo.id))
+        }
+    }
+}
+
+pub fn get_apps(pg_conn: &PgConnection, repo: &Repo, include_hidden: bool) -> Result<Vec<apps::App>, String> {
+    get_entries(pg_conn, repo, include_hidden).map(|entries| entries.into_iter().map(|(_, app)| app).collect())
+}
+
+/// The ids of each repo's apps in order, keyed by repo id. Repos without apps are left out.
+/// Like `get_entries`, hidden apps are only included if `include_hidden`.
+pub fn get_app_ids(pg_conn: &PgConnection, repo_ids: &[i64], include_hidden: bool) -> Result<HashMap<i64, Vec<i64>>, String> {
+    let mut query = repo_apps::table
+        .inner_join(schema::apps::table)
+        .filter(repo_apps::repo_id.eq_any(repo_ids))
+        .into_boxed();
+    if !include_hidden {
+        query = query.filter(schema::apps::hidden_at.is_null());
+    }
+    match query
+        .order((repo_apps::repo_id.asc(), repo_apps::position.asc()))
+        .select((repo_apps::repo_id, repo_apps::app_id))
+        .load::<(i64, i64)>(pg_conn) {
+        Ok(rows) => {
+            let mut app_ids: HashMap<i64, Vec<i64>> = HashMap::new();
+            for (repo_id, app_id) in rows {
+                app_ids.entry(repo_id).or_default().push(app_id);
             }
+            Ok(app_ids)
+        },
+        Err(e) => Err(format!("Failed to get app ids for repos {:?}: {}", repo_ids, e))
+    }
+}
+
+pub fn validate(title: &str, description: &str) -> Result<(), &'static str> {
+    if !validate_title(title) || title.len() > 24 {return Err("Title must be 3-24 characters")}
+    if description.len() > 256 {return Err("Description is too long - max 256 characters")}
+    Ok(())
+}
+
+fn write_error(e: diesel::result::Error) -> status::Custom<&'static str> {
+    match &*(e.to_string()) {
+        "duplicate key value violates unique constraint \"repos_title_unique_idx\"" => status::Custom(Status::BadRequest, "Duplicate repo name"),
+        _ => {
+            eprintln!("Failed to write repo to database {}", e);
+            status::Custom(Status::InternalServerError, "Failed to write repo to database")
         }
     }
-    return Ok(apps);
+}
+
+pub fn insert(pg_conn: &PgConnection, new_repo: &NewRepo) -> Result<Repo, status::Custom<&'static str>> {
+    match validate(&new_repo.title, &new_repo.description) {
+        Ok(_) => {},
+        Err(e) => return Err(status::Custom(Status::BadRequest, e))
+    }
+
+    diesel::insert_into(repos::table).values(new_repo).get_result::<Repo>(pg_conn).map_err(write_error)
+}
+
+pub fn update(pg_conn: &PgConnection, repo: &Repo, changes: &RepoChanges) -> Result<Repo, status::Custom<&'static str>> {
+    match validate(
+        changes.title.as_ref().unwrap_or(&repo.title),
+        changes.description.as_ref().unwrap_or(&repo.description),
+    ) {
+        Ok(_) => {},
+        Err(e) => return Err(status::Custom(Status::BadRequest, e))
+    }
+
+    if changes.title.is_none() && changes.description.is_none() {
+        return repos::table.find(repo.id).first::<Repo>(pg_conn).map_err(|_| status::Custom(Status::InternalServerError, "Failed to get repo"))
+    }
+
+    let result = pg_conn.transaction::<_, diesel::result::Error, _>(|| {
+        let updated = diesel::update(repos::table.find(repo.id)).set((
+            changes.title.as_ref().map(|title| repos::title.eq(title)),
+            changes.description.as_ref().map(|description| repos::description.eq(description)),
+        )).get_result::<Repo>(pg_conn)?;
+        if updated.title != repo.title {
+            add_redirect(pg_conn, &repo.title, repo.id)?;
+        }
+        Ok(updated)
+    });
+    result.map_err(write_error)
+}
+
+pub fn delete(pg_conn: &PgConnection, repo: &Repo) -> Result<(), String> {
+    match diesel::delete(repos::table.filter(repos::id.eq(repo.id))).execute(pg_conn) {
+        Ok(_) => Ok(()),
+        Err(_) => Err("Failed to delete repo".to_string())
+    }
 }
 
 #[get("/repos")]
 pub fn repos(db_conn: DbConn, cookies: Cookies) -> Template {
     let (mut context, _, _) = signed_in_context(&*db_conn, cookies);
-    match get_all(&*db_conn) {
-        Ok(repos) => {
-            context.insert("clean_repos", &CleanRepo::from_vec(&repos));
-        },
-        Err(_) => {}
-    };
+    if let Ok(repos) = get_visible(&*db_conn) {
+        context.insert("clean_repos", &CleanRepo::from_vec(&repos));
+    }
     Template::render("repos", &context)
 }
 
@@ -157,13 +463,14 @@ pub fn create_repo(db_conn: DbConn, cookies: Cookies) -> Template {
 }
 
 #[post("/createRepo", data="<repo_form>")]
-pub fn submit_repo(repo_form: Form<FormRepo>, db_conn: DbConn, cookies: Cookies) -> Result<Redirect, status::Custom<&str>> {
+pub fn submit_repo(repo_form: LenientForm<FormRepo>, db_conn: DbConn, cookies: Cookies, requirement: State<email_verifications::Requirement>) -> Result<Redirect, status::Custom<&'static str>> {
     let (_, _, user) = signed_in_context(&*db_conn, cookies);
     let form_repo = repo_form.into_inner();
     let new_repo;
 
     match user {
         Some(user) => {
+            requirement.check(&user)?;
             new_repo = form_repo.to_new_repo(user.id);
         },
         None => {
@@ -171,72 +478,92 @@ pub fn submit_repo(repo_form: Form<FormRepo>, db_conn: DbConn, cookies: Cookies)
         }
     }
 
-    if !validate_title(&new_repo.title) || new_repo.title.len() > 24 {return Err(status::Custom(Status::BadRequest, "Title must be 3-24 characters"))}
-    if new_repo.description.len() > 256 {return Err(status::Custom(Status::BadRequest, "Description is too long - max 256 characters"))}
-
-    match diesel::insert_into(repos::table).values(&new_repo).get_result::<Repo>(&*db_conn) {
+    match insert(&*db_conn, &new_repo) {
         Ok(repo) => {
             Ok(Redirect::to(uri!(repo: repo.title)))
         },
-        Err(e) => {
-            match &*(e.to_string()) {
-                "Failed to add repo to database duplicate key value violates unique constraint \"repos_title_unique_idx\"" => Err(status::Custom(Status::BadRequest, "Duplicate repo name")),
-                _ => {
-                    eprintln!("Failed to add repo to database {}", e);
-                    Err(status::Custom(Status::InternalServerError, "Failed to add repo to database"))
-                }
-            }
-        }
+        Err(e) => Err(e)
     }
 }
 
 #[get("/repos/<title>")]
-pub fn repo(title: String, db_conn: DbConn, cookies: Cookies) -> Result<Template, status::NotFound<String>> {
-    let (mut context, _, _) = signed_in_context(&*db_conn, cookies);
+pub fn repo(title: String, db_conn: DbConn, cookies: Cookies) -> Page {
+    let (mut context, _, user) = signed_in_context(&*db_conn, cookies);
 
     match get_by_title(&*db_conn, &title) {
         Ok(repo) => {
+            let permissions = policy::Permissions::new(&user, &repo);
+            if repo.hidden_at.is_some() && !permissions.edit {
+                return Page::NotFound(status::NotFound("Repo not found".to_owned()))
+            }
+            context.insert("permissions", &permissions);
             context.insert("repo", &repo);
             context.insert("clean_repo", &CleanRepo::from_repo(&repo));
-            match users::get(&*db_conn, repo.id) {
-                Ok(owner) => {
-                    context.insert("owner", &owner);
-                    context.insert("clean_owner", &users::CleanUser::from_user(&owner));
-                },
-                _ => {}
+            if let Ok(owner) = users::get(&*db_conn, repo.owner_id) {
+                context.insert("clean_owner", &users::PublicUser::from_user(&owner));
+            }
+            if let Ok(entries) = get_entries(&*db_conn, &repo, permissions.edit) {
+                context.insert("clean_entries", &CleanRepoApp::from_vec(&entries));
             }
-            match get_apps(&*db_conn, &repo) {
-                Ok(apps) => {
-                    context.insert("apps", &apps);
-                    context.insert("clean_apps", &apps::CleanApp::from_vec(&apps));
+            Page::Found(Template::render("repo", &context))
+        },
+        Err(_) => {
+            match get_by_old_title(&*db_conn, &title) {
+                Ok(repo) => Page::Moved(Redirect::moved(uri!(repo: repo.title))),
+                Err(_) => Page::NotFound(status::NotFound("Repo not found".to_owned()))
+            }
+        }
+    }
+}
+
+#[get("/repos/<title>/edit")]
+pub fn edit_repo(title: String, db_conn: DbConn, cookies: Cookies) -> Result<Template, status::Custom<&'static str>> {
+    let (mut context, _, user) = signed_in_context(&*db_conn, cookies);
+    match get_by_title(&*db_conn, &title) {
+        Ok(repo) => {
+            match user {
+                Some(user) => {
+                    policy::authorize(&user, Action::Edit, &repo)?;
+                    context.insert("repo", &repo);
+                    context.insert("clean_repo", &CleanRepo::from_repo(&repo));
+                    Ok(Template::render("edit_repo", &context))
                 },
-                _ => {}
+                None => Err(status::Custom(Status::Forbidden, "Failed to authenticate user"))
             }
-            Ok(Template::render("repo", &context))
         },
-        Err(_) => Err(status::NotFound("Repo not found".to_owned()))
+        Err(_) => Err(status::Custom(Status::NotFound, "Repo not found"))
     }
 }
 
-#[post("/repos/<title>/delete", data = "<login_user>")]
-pub fn delete_repo(title: String, db_conn: DbConn, login_user: Form<users::LoginUser>) -> Result<Redirect, status::Custom<&'static str>> {
-    match schema::users::table.filter(schema::users::username.eq(&login_user.username)).filter(schema::users::password_hash.crypt_eq(&login_user.password)).first::<users::User>(&*db_conn) {
+#[post("/repos/<title>/edit", data = "<changes>")]
+pub fn submit_edit_repo(title: String, changes: LenientForm<RepoChanges>, db_conn: DbConn, cookies: Cookies) -> Result<Redirect, status::Custom<&'static str>> {
+    let repo = get_authorized(&*db_conn, &title, cookies, Action::Edit)?;
+    let updated = update(&*db_conn, &repo, &changes)?;
+    Ok(Redirect::to(uri!(repo: updated.title)))
+}
+
+#[post("/repos/<title>/delete", data = "<confirm_user>")]
+pub fn delete_repo(title: String, db_conn: DbConn, confirm_user: LenientForm<users::ConfirmUser>, client_info: sessions::ClientInfo, throttle: State<login_throttle::Throttle>) -> Result<Redirect, status::Custom<String>> {
+    match users::confirm(&*db_conn, &throttle, &client_info, &confirm_user) {
         Ok(user) => {
             match get_by_title(&*db_conn, &title) {
                 Ok(repo) => {
-                    if repo.owner_id == user.id {
-                        match diesel::delete(repos::table.filter(repos::id.eq(repo.id))).execute(&*db_conn) {
-                            Ok(_) => Ok(Redirect::to(uri!(super::home))),
-                            Err(_) => Err(status::Custom(Status::InternalServerError, "Failed to delete repo"))
-                        }
-                    } else {
-                        return Err(status::Custom(Status::Forbidden, "You don't have permission to delete this repo"))
+                    match policy::authorize(&user, Action::Delete, &repo) {
+                        Ok(_) => {},
+                        Err(e) => return Err(status::Custom(e.0, e.1.to_string()))
+                    }
+                    match delete(&*db_conn, &repo) {
+                        Ok(_) => {
+                            audit::record(&*db_conn, Some(&user), Some(repo.owner_id), audit::Action::DeleteRepo, &format!("repos/{}", repo.title), &client_info);
+                            Ok(Redirect::to(uri!(super::home)))
+                        },
+                        Err(_) => Err(status::Custom(Status::InternalServerError, "Failed to delete repo".to_string()))
                     }
                 },
-                Err(_) => Err(status::Custom(Status::NotFound, "Repo not found"))
+                Err(_) => Err(status::Custom(Status::NotFound, "Repo not found".to_string()))
             }
         },
-        Err(_) => Err(status::Custom(Status::Forbidden, "Failed to authenticate user"))
+        Err(e) => Err(status::Custom(Status::Forbidden, e))
     }
 }
 
@@ -246,29 +573,211 @@ pub struct AddAppForum {
 }
 
 #[post("/repos/<title>/addApp", data = "<add_app_forum>")]
-pub fn add_app(title: String, db_conn: DbConn, cookies: Cookies, add_app_forum: Form<AddAppForum>) -> Result<Redirect, status::Custom<&'static str>> {
-    match get_by_title(&*db_conn, &title) {
-        Ok(mut repo) => {
-            match users::get_from_cookies(&*db_conn, cookies) {
-                Ok (user) => {
-                    if user.id == repo.owner_id {
-                        match apps::get_by_title(&*db_conn, &add_app_forum.title) {
-                            Ok(app) => {
-                                repo.apps.push(app.id);
-                                match diesel::update(repos::table.filter(repos::id.eq(repo.id))).set(repos::apps.eq(repo.apps)).execute(&*db_conn) {
-                                    Ok(_) => Ok(Redirect::to(uri!(repo: title))),
-                                    Err(_) => Err(status::Custom(Status::InternalServerError, "Failed to add app."))
-                                }
-                            },
-                            Err(_) => Err(status::Custom(Status::NotFound, "Failed to get app id from title"))
-                        }
-                    } else {
-                        return Err(status::Custom(Status::Forbidden, "You don't have permission to add an app to this repo"))
-                    }
+pub fn add_app(title: String, db_conn: DbConn, cookies: Cookies, add_app_forum: LenientForm<AddAppForum>, client_info: sessions::ClientInfo) -> Result<Redirect, status::Custom<&'static str>> {
+    let user = users::get_from_cookies(&*db_conn, cookies).map_err(|_| status::Custom(Status::Forbidden, "Failed to authenticate user"))?;
+    let repo = get_by_title(&*db_conn, &title).map_err(|_| status::Custom(Status::NotFound, "Repo not found"))?;
+    policy::authorize(&user, Action::Edit, &repo)?;
+    match apps::get_by_title(&*db_conn, &add_app_forum.title) {
+        Ok(app) => {
+            match repo.add_app(&*db_conn, app.id) {
+                Ok(_) => {
+                    audit::record(&*db_conn, Some(&user), Some(repo.owner_id), audit::Action::AddApp, &format!("apps/{} to repos/{}", app.title, repo.title), &client_info);
+                    Ok(Redirect::to(uri!(repo: title)))
+                },
+                Err(e) => Err(e)
+            }
+        },
+        Err(_) => Err(status::Custom(Status::NotFound, "Failed to get app id from title"))
+    }
+}
+
+fn get_authorized(pg_conn: &PgConnection, title: &str, cookies: Cookies, action: Action) -> Result<Repo, status::Custom<&'static str>> {
+    match get_by_title(pg_conn, title) {
+        Ok(repo) => {
+            match users::get_from_cookies(pg_conn, cookies) {
+                Ok(user) => {
+                    policy::authorize(&user, action, &repo)?;
+                    Ok(repo)
                 },
                 Err(_) => Err(status::Custom(Status::Forbidden, "Failed to authenticate user"))
             }
         },
-        Err(_) => Err(status::Custom(Status::Forbidden, "Failed to authenticate user"))
+        Err(_) => Err(status::Custom(Status::NotFound, "Repo not found"))
+    }
+}
+
+#[derive(FromForm)]
+pub struct RemoveAppForm {
+    title: String
+}
+
+#[post("/repos/<title>/removeApp", data = "<remove_app_form>")]
+pub fn remove_app(title: String, db_conn: DbConn, cookies: Cookies, remove_app_form: LenientForm<RemoveAppForm>) -> Result<Redirect, status::Custom<&'static str>> {
+    let repo = get_authorized(&*db_conn, &title, cookies, Action::Edit)?;
+    match apps::get_by_title(&*db_conn, &remove_app_form.title) {
+        Ok(app) => {
+            match repo.remove_app(&*db_conn, app.id) {
+                Ok(_) => Ok(Redirect::to(uri!(repo: title))),
+                Err(e) => Err(e)
+            }
+        },
+        Err(_) => Err(status::Custom(Status::NotFound, "Failed to get app id from title"))
+    }
+}
+
+#[derive(FromForm)]
+pub struct ReorderAppsForm {
+    /// Comma separated app titles, in their new order.
+    order: String
+}
+
+/// Looks up the ids of `titles`, keeping their order.
+pub fn app_ids_from_titles(pg_conn: &PgConnection, titles: &Vec<String>) -> Result<Vec<i64>, status::Custom<&'static str>> {
+    let mut app_ids = Vec::new();
+    for title in titles {
+        match apps::get_by_title(pg_conn, title) {
+            Ok(app) => app_ids.push(app.id),
+            Err(_) => return Err(status::Custom(Status::NotFound, "Failed to get app id from title"))
+        }
+    }
+    Ok(app_ids)
+}
+
+#[post("/repos/<title>/reorderApps", data = "<reorder_apps_form>")]
+pub fn reorder_apps(title: String, db_conn: DbConn, cookies: Cookies, reorder_apps_form: LenientForm<ReorderAppsForm>) -> Result<Redirect, status::Custom<&'static str>> {
+    let repo = get_authorized(&*db_conn, &title, cookies, Action::Edit)?;
+    let titles = reorder_apps_form.order
+        .split(',')
+        .map(|app_title| app_title.trim().to_string())
+        .filter(|app_title| !app_title.is_empty())
+        .collect::<Vec<String>>();
+    let app_ids = app_ids_from_titles(&*db_conn, &titles)?;
+    repo.reorder_apps(&*db_conn, &app_ids)?;
+    Ok(Redirect::to(uri!(repo: title)))
+}
+
+#[derive(FromForm)]
+pub struct AnnotateAppForm {
+    title: String,
+    note: String,
+}
+
+#[post("/repos/<title>/annotateApp", data = "<annotate_app_form>")]
+pub fn annotate_app(title: String, db_conn: DbConn, cookies: Cookies, annotate_app_form: LenientForm<AnnotateAppForm>) -> Result<Redirect, status::Custom<&'static str>> {
+    let repo = get_authorized(&*db_conn, &title, cookies, Action::Edit)?;
+    match apps::get_by_title(&*db_conn, &annotate_app_form.title) {
+        Ok(app) => {
+            repo.annotate_app(&*db_conn, app.id, &annotate_app_form.note)?;
+            Ok(Redirect::to(uri!(repo: title)))
+        },
+        Err(_) => Err(status::Custom(Status::NotFound, "Failed to get app id from title"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connections,
+        test_db,
+    };
+
+    fn add_app(pg_conn: &PgConnection, owner: &users::User, title: &str) -> apps::App {
+        let rules = connections::DomainRules {
+            insecure_hosts: Vec::new(),
+        };
+        apps::insert(pg_conn, &apps::NewApp {
+            owner_id: owner.id,
+            title: title.to_string(),
+            description: String::new(),
+            domain: "https://example.com".to_string(),
+        }, &rules).map_err(|e| e.1).unwrap().0
+    }
+
+    fn add_repo(pg_conn: &PgConnection, owner: &users::User, title: &str) -> Repo {
+        insert(pg_conn, &NewRepo {
+            owner_id: owner.id,
+            title: title.to_string(),
+            description: String::new(),
+        }).map_err(|e| e.1).unwrap()
+    }
+
+    #[test]
+    fn app_ids_are_in_repo_order() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let owner = test_db::create_user(&pg_conn, "collector");
+        let first = add_app(&pg_conn, &owner, "first");
+        let second = add_app(&pg_conn, &owner, "second");
+        let full = add_repo(&pg_conn, &owner, "full");
+        let empty = add_repo(&pg_conn, &owner, "empty");
+        full.add_app(&pg_conn, first.id).map_err(|e| e.1).unwrap();
+        full.add_app(&pg_conn, second.id).map_err(|e| e.1).unwrap();
+        full.reorder_apps(&pg_conn, &[second.id, first.id]).map_err(|e| e.1).unwrap();
+
+        let app_ids = get_app_ids(&pg_conn, &[full.id, empty.id], false).unwrap();
+        assert_eq!(app_ids.get(&full.id), Some(&vec![second.id, first.id]));
+        assert_eq!(app_ids.get(&empty.id), None);
+    }
+
+    #[test]
+    fn hidden_apps_are_left_out() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let owner = test_db::create_user(&pg_conn, "collector");
+        let shown = add_app(&pg_conn, &owner, "shown");
+        let hidden = add_app(&pg_conn, &owner, "hidden");
+        let repo = add_repo(&pg_conn, &owner, "repo");
+        repo.add_app(&pg_conn, shown.id).map_err(|e| e.1).unwrap();
+        repo.add_app(&pg_conn, hidden.id).map_err(|e| e.1).unwrap();
+        diesel::update(schema::apps::table.find(hidden.id)).set(schema::apps::hidden_at.eq(Utc::now())).execute(&pg_conn).unwrap();
+
+        let ids = |entries: Vec<(RepoApp, apps::App)>| entries.into_iter().map(|(_, app)| app.id).collect::<Vec<i64>>();
+        assert_eq!(ids(get_entries(&pg_conn, &repo, false).unwrap()), vec![shown.id]);
+        assert_eq!(ids(get_entries(&pg_conn, &repo, true).unwrap()), vec![shown.id, hidden.id]);
+        assert_eq!(get_app_ids(&pg_conn, &[repo.id], false).unwrap().get(&repo.id), Some(&vec![shown.id]));
+        assert_eq!(get_app_ids(&pg_conn, &[repo.id], true).unwrap().get(&repo.id), Some(&vec![shown.id, hidden.id]));
+    }
+
+    #[test]
+    fn removing_a_missing_app_is_not_found() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let owner = test_db::create_user(&pg_conn, "collector");
+        let app = add_app(&pg_conn, &owner, "only");
+        let repo = add_repo(&pg_conn, &owner, "repo");
+        repo.add_app(&pg_conn, app.id).map_err(|e| e.1).unwrap();
+
+        assert!(repo.remove_app(&pg_conn, app.id).is_ok());
+        assert_eq!(repo.remove_app(&pg_conn, app.id).map_err(|e| e.0), Err(Status::NotFound));
+    }
+    #[test]
+    fn updates_only_change_given_fields() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let owner = test_db::create_user(&pg_conn, "curator");
+        let repo = add_repo(&pg_conn, &owner, "before_rename");
+        let renamed = update(&pg_conn, &repo, &RepoChanges {
+            title: Some("after_rename".to_string()),
+            description: None,
+        }).map_err(|e| e.1).unwrap();
+        assert_eq!(renamed.title, "after_rename");
+        assert_eq!(renamed.description, repo.description);
+        assert_eq!(get_by_old_title(&pg_conn, "before_rename").unwrap().id, repo.id);
+
+        let described = update(&pg_conn, &renamed, &RepoChanges {
+            title: None,
+            description: Some("Described".to_string()),
+        }).map_err(|e| e.1).unwrap();
+        assert_eq!(described.title, "after_rename");
+        assert_eq!(described.description, "Described");
     }
 }
\ No newline at end of file
@@ -1,3 +1,25 @@
+table! {
+    api_tokens (id) {
+        id -> Int8,
+        user_id -> Int8,
+        name -> Varchar,
+        token_hash -> Bpchar,
+        scopes -> Array<Varchar>,
+        created_at -> Timestamptz,
+        expires_at -> Nullable<Timestamptz>,
+        last_used_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    app_redirects (id) {
+        id -> Int8,
+        old_title -> Varchar,
+        app_id -> Int8,
+        created_at -> Timestamptz,
+    }
+}
+
 table! {
     apps (id) {
         id -> Int8,
@@ -5,9 +27,168 @@ table! {
         title -> Varchar,
         description -> Varchar,
         domain -> Varchar,
-        token -> Bpchar,
+        token_hash -> Bpchar,
         connected -> Bool,
         connected_error -> Varchar,
+        hidden_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    audit_events (id) {
+        id -> Int8,
+        actor_id -> Nullable<Int8>,
+        actor -> Varchar,
+        user_id -> Nullable<Int8>,
+        action -> Varchar,
+        target -> Varchar,
+        ip -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    avatars (user_id) {
+        user_id -> Int8,
+        image -> Bytea,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    class_members (class_id, user_id) {
+        class_id -> Int8,
+        user_id -> Int8,
+        joined_at -> Timestamptz,
+        accepted_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    classes (id) {
+        id -> Int8,
+        owner_id -> Int8,
+        title -> Varchar,
+        description -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    email_verifications (id) {
+        id -> Int8,
+        user_id -> Int8,
+        email -> Varchar,
+        token_hash -> Bpchar,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        used_at -> Nullable<Timestamptz>,
+        replaces_email -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    external_identities (id) {
+        id -> Int8,
+        user_id -> Int8,
+        issuer -> Varchar,
+        subject -> Varchar,
+        email -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    login_attempts (id) {
+        id -> Int8,
+        user_id -> Int8,
+        ip -> Nullable<Varchar>,
+        user_agent -> Varchar,
+        succeeded -> Bool,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    login_failures (id) {
+        id -> Int8,
+        key -> Varchar,
+        failed_at -> Timestamptz,
+    }
+}
+
+table! {
+    oauth_access_tokens (id) {
+        id -> Int8,
+        app_id -> Int8,
+        user_id -> Int8,
+        token_hash -> Bpchar,
+        scopes -> Array<Varchar>,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+    }
+}
+
+table! {
+    oauth_codes (id) {
+        id -> Int8,
+        app_id -> Int8,
+        user_id -> Int8,
+        code_hash -> Bpchar,
+        redirect_uri -> Varchar,
+        scopes -> Array<Varchar>,
+        nonce -> Nullable<Varchar>,
+        code_challenge -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        used_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    ownership_transfers (sender_id) {
+        sender_id -> Int8,
+        recipient_id -> Int8,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    password_resets (id) {
+        id -> Int8,
+        user_id -> Int8,
+        token_hash -> Bpchar,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        used_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    recovery_codes (id) {
+        id -> Int8,
+        user_id -> Int8,
+        code_hash -> Bpchar,
+        used_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    repo_apps (repo_id, app_id) {
+        repo_id -> Int8,
+        app_id -> Int8,
+        position -> Int4,
+        added_at -> Timestamptz,
+        note -> Varchar,
+    }
+}
+
+table! {
+    repo_redirects (id) {
+        id -> Int8,
+        old_title -> Varchar,
+        repo_id -> Int8,
+        created_at -> Timestamptz,
     }
 }
 
@@ -17,7 +198,7 @@ table! {
         owner_id -> Int8,
         title -> Varchar,
         description -> Varchar,
-        apps -> Array<Int8>,
+        hidden_at -> Nullable<Timestamptz>,
     }
 }
 
@@ -25,6 +206,20 @@ table! {
     sessions (id) {
         id -> Int8,
         logged_in_user -> Nullable<Int8>,
+        created_at -> Timestamptz,
+        last_seen_at -> Timestamptz,
+        user_agent -> Varchar,
+        ip -> Nullable<Varchar>,
+        key -> Varchar,
+    }
+}
+
+table! {
+    username_redirects (id) {
+        id -> Int8,
+        old_username -> Varchar,
+        user_id -> Int8,
+        created_at -> Timestamptz,
     }
 }
 
@@ -34,12 +229,62 @@ table! {
         username -> Varchar,
         email -> Varchar,
         password_hash -> Bpchar,
+        email_verified_at -> Nullable<Timestamptz>,
+        totp_secret -> Nullable<Varchar>,
+        totp_enabled_at -> Nullable<Timestamptz>,
+        totp_last_counter -> Nullable<Int8>,
+        role -> Varchar,
+        suspended_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+        display_name -> Varchar,
+        bio -> Varchar,
+        email_visible -> Bool,
+        searchable -> Bool,
+        indexed -> Bool,
     }
 }
 
+joinable!(api_tokens -> users (user_id));
+joinable!(app_redirects -> apps (app_id));
+joinable!(avatars -> users (user_id));
+joinable!(class_members -> classes (class_id));
+joinable!(class_members -> users (user_id));
+joinable!(classes -> users (owner_id));
+joinable!(email_verifications -> users (user_id));
+joinable!(external_identities -> users (user_id));
+joinable!(login_attempts -> users (user_id));
+joinable!(oauth_access_tokens -> apps (app_id));
+joinable!(oauth_access_tokens -> users (user_id));
+joinable!(oauth_codes -> apps (app_id));
+joinable!(oauth_codes -> users (user_id));
+joinable!(password_resets -> users (user_id));
+joinable!(recovery_codes -> users (user_id));
+joinable!(repo_apps -> apps (app_id));
+joinable!(repo_apps -> repos (repo_id));
+joinable!(repo_redirects -> repos (repo_id));
+joinable!(username_redirects -> users (user_id));
+
 allow_tables_to_appear_in_same_query!(
+    api_tokens,
+    app_redirects,
     apps,
+    audit_events,
+    avatars,
+    class_members,
+    classes,
+    email_verifications,
+    external_identities,
+    login_attempts,
+    login_failures,
+    oauth_access_tokens,
+    oauth_codes,
+    ownership_transfers,
+    password_resets,
+    recovery_codes,
+    repo_apps,
+    repo_redirects,
     repos,
     sessions,
+    username_redirects,
     users,
 );
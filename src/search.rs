@@ -0,0 +1,301 @@
+use std::str::FromStr;
+
+use ammonia::clean_text;
+
+use diesel::{
+    prelude::*,
+    PgConnection,
+    sql_query,
+    sql_types::{
+        BigInt,
+        Bool,
+        Float4,
+        Text,
+    },
+};
+
+use rocket::http::Cookies;
+use rocket_contrib::templates::Template;
+use serde::Serialize;
+
+use super::{
+    common::*,
+    DbConn,
+    signed_in_context,
+};
+
+/// Wrapped around each match by `ts_headline`, and swapped for `<mark>` tags once the rest of the snippet is escaped.
+/// They're taken out of the text before it's highlighted, so nobody can make their own marks by typing them.
+const START_MATCH: &str = "⟦";
+const STOP_MATCH: &str = "⟧";
+/// Longer searches are cut short.
+const MAX_QUERY_LENGTH: usize = 256;
+
+// The tsvector expressions have to match the indexes in the add_search_indexes migration exactly to use them.
+const FULL_TEXT_SQL: &str = "
+    SELECT kind, title, snippet, rank, COUNT(*) OVER () AS total FROM (
+        SELECT 'app' AS kind, title,
+            ts_headline('english', translate(description, $8, ''), websearch_to_tsquery('english', $1), $2) AS snippet,
+            ts_rank(to_tsvector('english', title || ' ' || description), websearch_to_tsquery('english', $1)) AS rank
+        FROM apps
+        WHERE $3 AND hidden_at IS NULL AND to_tsvector('english', title || ' ' || description) @@ websearch_to_tsquery('english', $1)
+        UNION ALL
+        SELECT 'repo', title,
+            ts_headline('english', translate(description, $8, ''), websearch_to_tsquery('english', $1), $2),
+            ts_rank(to_tsvector('english', title || ' ' || description), websearch_to_tsquery('english', $1))
+        FROM repos
+        WHERE $4 AND hidden_at IS NULL AND to_tsvector('english', title || ' ' || description) @@ websearch_to_tsquery('english', $1)
+        UNION ALL
+        SELECT 'user', username,
+            ts_headline('simple', translate(display_name || ' ' || bio, $8, ''), websearch_to_tsquery('simple', $1), $2),
+            ts_rank(to_tsvector('simple', username || ' ' || display_name || ' ' || bio), websearch_to_tsquery('simple', $1))
+        FROM users
+        WHERE $5 AND searchable AND suspended_at IS NULL AND to_tsvector('simple', username || ' ' || display_name || ' ' || bio) @@ websearch_to_tsquery('simple', $1)
+    ) AS results
+    ORDER BY rank DESC, title ASC
+    LIMIT $6 OFFSET $7";
+
+// `%` is pg_trgm's similarity operator, which uses the trigram indexes on the lowercased titles.
+const SIMILAR_SQL: &str = "
+    SELECT kind, title, snippet, rank, COUNT(*) OVER () AS total FROM (
+        SELECT 'app' AS kind, title, LEFT(translate(description, $6, ''), 160) AS snippet, similarity(LOWER(title), LOWER($1)) AS rank
+        FROM apps
+        WHERE $2 AND hidden_at IS NULL AND LOWER(title) % LOWER($1)
+        UNION ALL
+        SELECT 'repo', title, LEFT(translate(description, $6, ''), 160), similarity(LOWER(title), LOWER($1))
+        FROM repos
+        WHERE $3 AND hidden_at IS NULL AND LOWER(title) % LOWER($1)
+        UNION ALL
+        SELECT 'user', username, translate(display_name, $6, ''), similarity(LOWER(username), LOWER($1))
+        FROM users
+        WHERE $4 AND searchable AND suspended_at IS NULL AND LOWER(username) % LOWER($1)
+    ) AS results
+    ORDER BY rank DESC, title ASC
+    LIMIT $5";
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub enum Kind {
+    App,
+    Repo,
+    User,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 3] = [
+        Kind::App,
+        Kind::Repo,
+        Kind::User,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Kind::App => "app",
+            Kind::Repo => "repo",
+            Kind::User => "user",
+        }
+    }
+
+    /// Every kind if `kind` is missing or empty, otherwise just the one it names.
+    pub fn parse_filter(kind: &Option<String>) -> Result<Vec<Kind>, &'static str> {
+        match kind.as_ref().map(|kind| kind.as_str()).unwrap_or("") {
+            "" => Ok(Kind::ALL.to_vec()),
+            kind => kind.parse::<Kind>().map(|kind| vec![kind]).map_err(|_| "Unknown kind, it can be app, repo or user")
+        }
+    }
+}
+
+impl FromStr for Kind {
+    type Err = ();
+
+    fn from_str(kind: &str) -> Result<Kind, ()> {
+        Kind::ALL.iter().find(|candidate| candidate.as_str() == kind).copied().ok_or(())
+    }
+}
+
+#[derive(QueryableByName)]
+pub struct SearchResult {
+    #[sql_type = "Text"]
+    pub kind: String,
+    #[sql_type = "Text"]
+    pub title: String,
+    /// Matches are wrapped in `START_MATCH` and `STOP_MATCH`.
+    #[sql_type = "Text"]
+    pub snippet: String,
+    #[sql_type = "Float4"]
+    pub rank: f32,
+    /// How many results there are across every page.
+    #[sql_type = "BigInt"]
+    pub total: i64,
+}
+
+impl SearchResult {
+    /// The snippet with the match markers taken out.
+    pub fn plain_snippet(&self) -> String {
+        self.snippet.replace(START_MATCH, "").replace(STOP_MATCH, "")
+    }
+
+    /// The snippet escaped, with matches in `<mark>` tags.
+    pub fn highlighted_snippet(&self) -> String {
+        clean_text(&self.snippet).replace(START_MATCH, "<mark>").replace(STOP_MATCH, "</mark>")
+    }
+}
+
+pub struct SearchResults {
+    pub results: Vec<SearchResult>,
+    pub total: i64,
+    /// Whether nothing matched the search itself, so these are titles that look like it instead.
+    pub similar: bool,
+}
+
+#[derive(Serialize)]
+pub struct CleanSearchResult {
+    pub kind: String,
+    pub title: Cleaned,
+    pub snippet_html: String,
+}
+
+impl CleanSearchResult {
+    pub fn from_result(result: &SearchResult) -> CleanSearchResult {
+        CleanSearchResult {
+            kind: result.kind.clone(),
+            title: Cleaned::new(&result.title),
+            snippet_html: result.highlighted_snippet(),
+        }
+    }
+
+    pub fn from_vec(results: &[SearchResult]) -> Vec<CleanSearchResult> {
+        results.iter().map(CleanSearchResult::from_result).collect()
+    }
+}
+
+/// One page of apps, repos and users matching `query`, best first.
+/// If nothing matches, the first page falls back to ones whose titles are similar, so typos still find something.
+pub fn search(pg_conn: &PgConnection, query: &str, kinds: &[Kind], page: i64) -> Result<SearchResults, String> {
+    let query: String = query.trim().chars().take(MAX_QUERY_LENGTH).collect();
+    let markers = format!("{}{}", START_MATCH, STOP_MATCH);
+    let headline_options = format!("StartSel={}, StopSel={}, MaxFragments=2, MaxWords=20, MinWords=5", START_MATCH, STOP_MATCH);
+    let full_text = sql_query(FULL_TEXT_SQL)
+        .bind::<Text, _>(&query)
+        .bind::<Text, _>(&headline_options)
+        .bind::<Bool, _>(kinds.contains(&Kind::App))
+        .bind::<Bool, _>(kinds.contains(&Kind::Repo))
+        .bind::<Bool, _>(kinds.contains(&Kind::User))
+        .bind::<BigInt, _>(PAGE_SIZE)
+        .bind::<BigInt, _>(page.saturating_mul(PAGE_SIZE))
+        .bind::<Text, _>(&markers)
+        .load::<SearchResult>(pg_conn);
+    let results = match full_text {
+        Ok(results) => results,
+        Err(e) => return Err(format!("Failed to search for {}: {}", query, e))
+    };
+    if !results.is_empty() || page > 0 {
+        let total = results.first().map_or(0, |result| result.total);
+        return Ok(SearchResults { results, total, similar: false })
+    }
+
+    match sql_query(SIMILAR_SQL)
+        .bind::<Text, _>(&query)
+        .bind::<Bool, _>(kinds.contains(&Kind::App))
+        .bind::<Bool, _>(kinds.contains(&Kind::Repo))
+        .bind::<Bool, _>(kinds.contains(&Kind::User))
+        .bind::<BigInt, _>(PAGE_SIZE)
+        .bind::<Text, _>(&markers)
+        .load::<SearchResult>(pg_conn) {
+        // Only the closest page of lookalikes is worth showing
+        Ok(results) => {
+            let total = results.len() as i64;
+            Ok(SearchResults { results, total, similar: true })
+        },
+        Err(e) => Err(format!("Failed to search for titles like {}: {}", query, e))
+    }
+}
+
+#[get("/search?<q>&<kind>&<page>")]
+pub fn search_page(q: Option<String>, kind: Option<String>, page: Option<i64>, db_conn: DbConn, cookies: Cookies) -> Template {
+    let (mut context, _, _) = signed_in_context(&*db_conn, cookies);
+    let q = q.unwrap_or_default();
+    let page = page_number(page);
+    let kinds = Kind::parse_filter(&kind);
+    // Only a kind that parsed goes back into the page, since it's put in links as is
+    context.insert("kind", match &kinds {
+        Ok(kinds) if kinds.len() == 1 => kinds[0].as_str(),
+        _ => ""
+    });
+    context.insert("kinds", &Kind::ALL.iter().map(|kind| kind.as_str()).collect::<Vec<&str>>());
+    context.insert("q", &Cleaned::new(&q));
+    if q.trim().is_empty() {
+        return Template::render("search", &context)
+    }
+
+    match kinds {
+        Ok(kinds) => {
+            match search(&*db_conn, &q, &kinds, page) {
+                Ok(found) => {
+                    context.insert("results", &CleanSearchResult::from_vec(&found.results));
+                    context.insert("similar", &found.similar);
+                    context.insert("pagination", &Pagination::new(page, found.total, &q));
+                },
+                Err(e) => eprintln!("{}", e)
+            }
+        },
+        Err(e) => context.insert("error", e)
+    }
+    Template::render("search", &context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        apps,
+        connections,
+        test_db,
+    };
+
+    fn add_app(pg_conn: &PgConnection, title: &str, description: &str) {
+        let owner = test_db::create_user(pg_conn, &format!("{}_owner", title));
+        let rules = connections::DomainRules {
+            insecure_hosts: Vec::new(),
+        };
+        apps::insert(pg_conn, &apps::NewApp {
+            owner_id: owner.id,
+            title: title.to_string(),
+            description: description.to_string(),
+            domain: "https://example.com".to_string(),
+        }, &rules).map_err(|e| e.1).unwrap();
+    }
+
+    #[test]
+    fn typed_markers_are_not_highlighted() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        add_app(&pg_conn, "marked", "Quizzes about ⟦<b>volcanoes</b>⟧ and ⟦lava⟧");
+
+        let found = search(&pg_conn, "volcanoes", &[Kind::App], 0).unwrap();
+        assert_eq!(found.results.len(), 1);
+        let snippet = found.results[0].highlighted_snippet();
+        assert!(!snippet.contains(START_MATCH) && !snippet.contains(STOP_MATCH));
+        assert_eq!(snippet.matches("<mark>").count(), 1);
+        assert!(!snippet.contains("<b>"));
+        assert!(!found.results[0].plain_snippet().contains("<mark>"));
+
+        let similar = search(&pg_conn, "markd", &[Kind::App], 0).unwrap();
+        assert!(similar.similar);
+        assert!(similar.results.iter().all(|result| !result.highlighted_snippet().contains("<mark>")));
+    }
+
+    #[test]
+    fn huge_pages_are_empty() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        add_app(&pg_conn, "paged", "Quizzes about glaciers");
+
+        let found = search(&pg_conn, "glaciers", &[Kind::App], i64::MAX).unwrap();
+        assert!(found.results.is_empty());
+        assert!(!found.similar);
+    }
+}
\ No newline at end of file
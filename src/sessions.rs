@@ -1,45 +1,173 @@
+use std::{
+    net::IpAddr,
+    thread,
+    time,
+};
+
+use chrono::{
+    DateTime,
+    Duration,
+    Utc,
+};
+
 use diesel::{
     prelude::*,
     PgConnection,
 };
 
 use rocket::{
-    http::Cookies,
+    fairing::AdHoc,
+    http::{
+        Cookie,
+        Cookies,
+        SameSite,
+        Status,
+    },
+    Outcome,
+    request::{
+        self,
+        FromRequest,
+        Request,
+    },
+    response::{
+        Redirect,
+        status,
+    },
+    State,
+    uri,
 };
 
+use rocket_contrib::{
+    databases::database_config,
+    templates::Template,
+};
+
+use serde::Serialize;
+
 use super::{
+    common::*,
+    csrf,
+    DbConn,
+    login_throttle,
     schema::sessions,
+    signed_in_context,
 };
 
+/// Sessions that haven't been used for this long are signed out.
+const IDLE_TIMEOUT_DAYS: i64 = 14;
+/// Sessions are signed out this long after they were created, no matter how often they're used.
+const ABSOLUTE_TIMEOUT_DAYS: i64 = 60;
+/// Length of the random key that identifies a session in its cookie.
+const KEY_LENGTH: usize = 64;
+const COOKIE_NAME: &str = "session_key";
+/// `last_seen_at` is only written once this many minutes have passed, so every request isn't a write.
+const LAST_SEEN_RESOLUTION_MINUTES: i64 = 5;
+const DEFAULT_CLEANUP_INTERVAL: u64 = 60 * 60;
+
 #[derive(Queryable)]
 pub struct Session {
     pub id: i64,
     pub logged_in_user: Option<i64>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub user_agent: String,
+    pub ip: Option<String>,
+    pub key: String,
+}
+
+impl Session {
+    pub fn is_expired(&self) -> bool {
+        let now = Utc::now();
+        self.last_seen_at <= now - Duration::days(IDLE_TIMEOUT_DAYS) || self.created_at <= now - Duration::days(ABSOLUTE_TIMEOUT_DAYS)
+    }
+}
+
+#[derive(Serialize)]
+pub struct CleanSession {
+    pub id: i64,
+    pub created_at: String,
+    pub last_seen_at: String,
+    pub user_agent: Cleaned,
+    pub ip: Option<String>,
+}
+
+impl CleanSession {
+    pub fn from_session(session: &Session) -> CleanSession {
+        CleanSession {
+            id: session.id,
+            created_at: session.created_at.format("%Y-%m-%d %H:%M").to_string(),
+            last_seen_at: session.last_seen_at.format("%Y-%m-%d %H:%M").to_string(),
+            user_agent: Cleaned::new(&session.user_agent),
+            ip: session.ip.clone(),
+        }
+    }
+
+    pub fn from_vec(sessions: &[Session]) -> Vec<CleanSession> {
+        sessions.iter().map(CleanSession::from_session).collect()
+    }
 }
 
 #[derive(Insertable)]
 #[table_name="sessions"]
 pub struct NewSession {
     pub logged_in_user: i64,
+    pub user_agent: String,
+    pub ip: Option<String>,
+    pub key: String,
 }
 
 impl NewSession {
-    pub fn new(logged_in_user: i64) -> NewSession {
+    pub fn new(logged_in_user: i64, client_info: &ClientInfo) -> NewSession {
         NewSession {
             logged_in_user,
+            user_agent: client_info.user_agent.clone(),
+            ip: client_info.ip.clone(),
+            key: random_string(KEY_LENGTH),
         }
     }
 }
 
-pub fn get_id_from_cookies(mut cookies: Cookies) -> Result<i64, String> {
-    match cookies.get_private("session_id") {
-        Some(session_id_cookie) => {
-            match session_id_cookie.value().parse::<i64>() {
-                Ok(session_id) => Ok(session_id),
-                Err(e) => Err(format!("Failed to parse session_id: {}", e))
-            }
-        },
-        None => Err("No session_id in cookies".to_string())
+/// Reverse proxies allowed to say who the client is with `X-Real-IP`. Set with `trusted_proxies`.
+pub struct TrustedProxies {
+    pub addresses: Vec<IpAddr>,
+}
+
+impl TrustedProxies {
+    /// Anyone can send `X-Real-IP`, so it's only believed when the connection comes from a trusted proxy.
+    pub fn client_ip(&self, remote: Option<IpAddr>, real_ip: Option<IpAddr>) -> Option<IpAddr> {
+        match remote {
+            Some(remote) if self.addresses.contains(&remote) => real_ip.or(Some(remote)),
+            remote => remote
+        }
+    }
+}
+
+/// What a session records about the device that signed in.
+pub struct ClientInfo {
+    pub user_agent: String,
+    pub ip: Option<String>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientInfo {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<ClientInfo, ()> {
+        let remote = request.remote().map(|address| address.ip());
+        let ip = match request.guard::<State<TrustedProxies>>() {
+            Outcome::Success(proxies) => proxies.client_ip(remote, request.real_ip()),
+            _ => remote
+        };
+        Outcome::Success(ClientInfo {
+            user_agent: request.headers().get_one("User-Agent").unwrap_or("").chars().take(256).collect(),
+            ip: ip.map(|ip| ip.to_string()),
+        })
+    }
+}
+
+pub fn get_key_from_cookies(cookies: &mut Cookies) -> Result<String, String> {
+    match cookies.get_private(COOKIE_NAME) {
+        Some(session_key_cookie) => Ok(session_key_cookie.value().to_string()),
+        None => Err("No session_key in cookies".to_string())
     }
 }
 
@@ -50,9 +178,318 @@ pub fn get(pg_conn: &PgConnection, session_id: i64) -> Result<Session, String> {
     }
 }
 
-pub fn get_from_cookies(pg_conn: &PgConnection, cookies: Cookies) -> Result<Session, String> {
-    match get_id_from_cookies(cookies) {
-        Ok(session_id) => get(pg_conn, session_id),
-        Err(e) => Err(format!("Failed to get session id: {}", e))
+pub fn get_by_key(pg_conn: &PgConnection, key: &str) -> Result<Session, String> {
+    match sessions::table.filter(sessions::key.eq(key)).first::<Session>(pg_conn) {
+        Ok(session) => Ok(session),
+        Err(e) => Err(format!("Failed to get session by key: {}", e))
+    }
+}
+
+pub fn get_by_user(pg_conn: &PgConnection, user_id: i64) -> Result<Vec<Session>, String> {
+    match sessions::table.filter(sessions::logged_in_user.eq(user_id)).order(sessions::last_seen_at.desc()).load::<Session>(pg_conn) {
+        Ok(sessions) => Ok(sessions.into_iter().filter(|session| !session.is_expired()).collect()),
+        Err(e) => Err(format!("Failed to get sessions for user {}: {}", user_id, e))
+    }
+}
+
+pub fn get_from_cookies(pg_conn: &PgConnection, mut cookies: Cookies) -> Result<Session, String> {
+    get_from_cookie_jar(pg_conn, &mut cookies)
+}
+
+/// Gets the session the cookies point to, signing it out if it has timed out.
+/// Takes the cookies by reference for routes that still need them afterwards, like to start a new session.
+pub fn get_from_cookie_jar(pg_conn: &PgConnection, cookies: &mut Cookies) -> Result<Session, String> {
+    let key = match get_key_from_cookies(cookies) {
+        Ok(key) => key,
+        Err(e) => return Err(format!("Failed to get session key: {}", e))
+    };
+    let session = match get_by_key(pg_conn, &key) {
+        Ok(session) => session,
+        Err(e) => {
+            cookies.remove_private(Cookie::named(COOKIE_NAME));
+            return Err(e)
+        }
+    };
+
+    if session.is_expired() {
+        delete(pg_conn, session.id)?;
+        cookies.remove_private(Cookie::named(COOKIE_NAME));
+        return Err(format!("Session {} has expired", session.id))
+    }
+
+    if session.last_seen_at <= Utc::now() - Duration::minutes(LAST_SEEN_RESOLUTION_MINUTES) {
+        match diesel::update(sessions::table.find(session.id)).set(sessions::last_seen_at.eq(Utc::now())).execute(pg_conn) {
+            Ok(_) => {},
+            Err(e) => eprintln!("Failed to update last seen for session {}: {}", session.id, e)
+        }
+    }
+    Ok(session)
+}
+
+/// Signs `user_id` in with a brand new session, throwing away whichever session the cookies pointed to before.
+/// Call this on login and whenever the user's privileges change so a session key can't outlive them.
+pub fn start(pg_conn: &PgConnection, cookies: &mut Cookies, user_id: i64, client_info: &ClientInfo) -> Result<Session, String> {
+    if let Ok(key) = get_key_from_cookies(cookies) {
+        match diesel::delete(sessions::table.filter(sessions::key.eq(key))).execute(pg_conn) {
+            Ok(_) => {},
+            Err(e) => return Err(format!("Failed to delete old session: {}", e))
+        }
+    }
+    match diesel::insert_into(sessions::table).values(&NewSession::new(user_id, client_info)).get_result::<Session>(pg_conn) {
+        Ok(session) => {
+            cookies.add_private(Cookie::build(COOKIE_NAME, session.key.clone()).same_site(SameSite::Lax).finish());
+            csrf::rotate(cookies);
+            Ok(session)
+        },
+        Err(e) => Err(format!("Failed to create session: {}", e))
+    }
+}
+
+/// Signs out the session the cookies point to.
+pub fn end(pg_conn: &PgConnection, cookies: &mut Cookies) -> Result<(), String> {
+    let key = get_key_from_cookies(cookies)?;
+    match diesel::delete(sessions::table.filter(sessions::key.eq(key))).execute(pg_conn) {
+        Ok(_) => {
+            cookies.remove_private(Cookie::named(COOKIE_NAME));
+            Ok(())
+        },
+        Err(e) => Err(format!("Failed to delete session: {}", e))
+    }
+}
+
+pub fn delete(pg_conn: &PgConnection, session_id: i64) -> Result<(), String> {
+    match diesel::delete(sessions::table.find(session_id)).execute(pg_conn) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to delete session {}: {}", session_id, e))
+    }
+}
+
+/// Returns whether the user had a session with that id to revoke.
+pub fn revoke(pg_conn: &PgConnection, user_id: i64, session_id: i64) -> Result<bool, String> {
+    match diesel::delete(sessions::table.find(session_id).filter(sessions::logged_in_user.eq(user_id))).execute(pg_conn) {
+        Ok(revoked) => Ok(revoked > 0),
+        Err(e) => Err(format!("Failed to revoke session {}: {}", session_id, e))
+    }
+}
+
+pub fn delete_by_user(pg_conn: &PgConnection, user_id: i64) -> QueryResult<usize> {
+    diesel::delete(sessions::table.filter(sessions::logged_in_user.eq(user_id))).execute(pg_conn)
+}
+
+/// Deletes sessions that have timed out or were never signed in.
+pub fn delete_expired(pg_conn: &PgConnection) -> Result<usize, String> {
+    let now = Utc::now();
+    match diesel::delete(sessions::table.filter(
+        sessions::logged_in_user.is_null()
+            .or(sessions::last_seen_at.le(now - Duration::days(IDLE_TIMEOUT_DAYS)))
+            .or(sessions::created_at.le(now - Duration::days(ABSOLUTE_TIMEOUT_DAYS)))
+    )).execute(pg_conn) {
+        Ok(deleted) => Ok(deleted),
+        Err(e) => Err(format!("Failed to delete expired sessions: {}", e))
+    }
+}
+
+#[get("/sessions")]
+pub fn active_sessions(db_conn: DbConn, cookies: Cookies) -> Result<Template, status::Custom<&'static str>> {
+    let (mut context, session, user) = signed_in_context(&*db_conn, cookies);
+    match (session, user) {
+        (Some(session), Some(user)) => {
+            match get_by_user(&*db_conn, user.id) {
+                Ok(sessions) => {
+                    context.insert("current_session_id", &session.id);
+                    context.insert("sessions", &CleanSession::from_vec(&sessions));
+                    match login_throttle::get_recent_attempts(&*db_conn, user.id) {
+                        Ok(login_attempts) => context.insert("login_attempts", &login_throttle::CleanLoginAttempt::from_vec(&login_attempts)),
+                        Err(e) => eprintln!("{}", e)
+                    }
+                    Ok(Template::render("sessions", &context))
+                },
+                Err(e) => {
+                    eprintln!("{}", e);
+                    Err(status::Custom(Status::InternalServerError, "Failed to get sessions"))
+                }
+            }
+        },
+        _ => Err(status::Custom(Status::Forbidden, "Must be signed in"))
+    }
+}
+
+#[post("/sessions/<id>/revoke")]
+pub fn revoke_session(id: i64, db_conn: DbConn, cookies: Cookies) -> Result<Redirect, status::Custom<&'static str>> {
+    match get_from_cookies(&*db_conn, cookies) {
+        Ok(Session { logged_in_user: Some(user_id), .. }) => {
+            match revoke(&*db_conn, user_id, id) {
+                Ok(false) => Err(status::Custom(Status::NotFound, "Session not found")),
+                Ok(true) => Ok(Redirect::to(uri!(active_sessions))),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    Err(status::Custom(Status::InternalServerError, "Failed to revoke session"))
+                }
+            }
+        },
+        _ => Err(status::Custom(Status::Forbidden, "Must be signed in"))
+    }
+}
+
+#[post("/sessions/revokeAll")]
+pub fn revoke_all_sessions(db_conn: DbConn, cookies: Cookies) -> Result<Redirect, status::Custom<&'static str>> {
+    match get_from_cookies(&*db_conn, cookies) {
+        Ok(Session { logged_in_user: Some(user_id), .. }) => {
+            match delete_by_user(&*db_conn, user_id) {
+                Ok(_) => Ok(Redirect::to(uri!(super::home))),
+                Err(_) => Err(status::Custom(Status::InternalServerError, "Failed to revoke sessions"))
+            }
+        },
+        _ => Err(status::Custom(Status::Forbidden, "Must be signed in"))
+    }
+}
+
+fn run_cleanup(database_url: String, interval: time::Duration) {
+    loop {
+        thread::sleep(interval);
+        match PgConnection::establish(&database_url) {
+            Ok(pg_conn) => {
+                match delete_expired(&pg_conn) {
+                    Ok(_) => {},
+                    Err(e) => eprintln!("Session cleanup: {}", e)
+                }
+            },
+            Err(e) => eprintln!("Session cleanup failed to connect to database: {}", e)
+        }
+    }
+}
+
+pub fn proxy_fairing() -> AdHoc {
+    AdHoc::on_attach("Trusted Proxies", |rocket| {
+        let mut addresses = Vec::new();
+        if let Ok(proxies) = rocket.config().get_slice("trusted_proxies") {
+            for proxy in proxies {
+                match proxy.as_str().map(|proxy| proxy.parse::<IpAddr>()) {
+                    Some(Ok(address)) => addresses.push(address),
+                    _ => {
+                        eprintln!("trusted_proxies should only have ip addresses, not {}", proxy);
+                        return Err(rocket);
+                    }
+                }
+            }
+        }
+        Ok(rocket.manage(TrustedProxies { addresses }))
+    })
+}
+
+pub fn cleanup_fairing() -> AdHoc {
+    AdHoc::on_attach("Session Cleanup", |rocket| {
+        let database_url = database_config("postgres", rocket.config()).map(|config| config.url.to_string());
+        let database_url = match database_url {
+            Ok(database_url) => database_url,
+            Err(e) => {
+                eprintln!("Session cleanup couldn't find database config: {}", e);
+                return Err(rocket);
+            }
+        };
+        let interval = match rocket.config().get_int("session_cleanup_interval") {
+            Ok(seconds) if seconds > 0 => time::Duration::from_secs(seconds as u64),
+            _ => time::Duration::from_secs(DEFAULT_CLEANUP_INTERVAL)
+        };
+
+        thread::spawn(move || run_cleanup(database_url, interval));
+
+        Ok(rocket)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db;
+
+    fn session_aged(created_days_ago: i64, seen_days_ago: i64) -> Session {
+        let now = Utc::now();
+        Session {
+            id: 0,
+            logged_in_user: Some(0),
+            created_at: now - Duration::days(created_days_ago),
+            last_seen_at: now - Duration::days(seen_days_ago),
+            user_agent: String::new(),
+            ip: None,
+            key: String::new(),
+        }
+    }
+
+    fn sign_in(pg_conn: &PgConnection, user_id: i64, created_days_ago: i64, seen_days_ago: i64) -> Session {
+        let session = diesel::insert_into(sessions::table).values(&NewSession::new(user_id, &test_db::client_info("192.0.2.1"))).get_result::<Session>(pg_conn).unwrap();
+        diesel::update(sessions::table.find(session.id)).set((
+            sessions::created_at.eq(Utc::now() - Duration::days(created_days_ago)),
+            sessions::last_seen_at.eq(Utc::now() - Duration::days(seen_days_ago)),
+        )).get_result::<Session>(pg_conn).unwrap()
+    }
+
+    #[test]
+    fn sessions_time_out() {
+        assert!(!session_aged(0, 0).is_expired());
+        assert!(!session_aged(IDLE_TIMEOUT_DAYS * 2, IDLE_TIMEOUT_DAYS - 1).is_expired());
+        // Idle for too long
+        assert!(session_aged(IDLE_TIMEOUT_DAYS, IDLE_TIMEOUT_DAYS).is_expired());
+        // Used every day, but too old
+        assert!(session_aged(ABSOLUTE_TIMEOUT_DAYS, 0).is_expired());
+    }
+
+    #[test]
+    fn timed_out_sessions_are_cleaned_up() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "timed_out");
+        let fresh = sign_in(&pg_conn, user.id, 1, 0);
+        let idle = sign_in(&pg_conn, user.id, IDLE_TIMEOUT_DAYS + 1, IDLE_TIMEOUT_DAYS + 1);
+        let old = sign_in(&pg_conn, user.id, ABSOLUTE_TIMEOUT_DAYS + 1, 0);
+        let ids = |sessions: Vec<Session>| sessions.iter().map(|session| session.id).collect::<Vec<i64>>();
+
+        assert_eq!(ids(get_by_user(&pg_conn, user.id).unwrap()), vec![fresh.id]);
+        delete_expired(&pg_conn).unwrap();
+        assert!(get(&pg_conn, fresh.id).is_ok());
+        assert!(get(&pg_conn, idle.id).is_err());
+        assert!(get(&pg_conn, old.id).is_err());
+    }
+
+    #[test]
+    fn only_your_own_sessions_can_be_revoked() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "revoking");
+        let someone_else = test_db::create_user(&pg_conn, "meddler");
+        let session = sign_in(&pg_conn, user.id, 0, 0);
+        let other_session = sign_in(&pg_conn, user.id, 0, 0);
+
+        assert!(!revoke(&pg_conn, someone_else.id, session.id).unwrap());
+        assert!(revoke(&pg_conn, user.id, session.id).unwrap());
+        assert!(get_by_key(&pg_conn, &session.key).is_err());
+        assert!(!revoke(&pg_conn, user.id, session.id).unwrap());
+        assert!(get(&pg_conn, other_session.id).is_ok());
+
+        assert_eq!(delete_by_user(&pg_conn, user.id).unwrap(), 1);
+        assert!(get_by_user(&pg_conn, user.id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn real_ip_is_only_believed_from_trusted_proxies() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let spoofed: IpAddr = "198.51.100.1".parse().unwrap();
+        let proxies = TrustedProxies {
+            addresses: vec![proxy],
+        };
+        assert_eq!(proxies.client_ip(Some(proxy), Some(client)), Some(client));
+        assert_eq!(proxies.client_ip(Some(proxy), None), Some(proxy));
+        assert_eq!(proxies.client_ip(Some(client), Some(spoofed)), Some(client));
+        assert_eq!(proxies.client_ip(None, Some(spoofed)), None);
+
+        let no_proxies = TrustedProxies {
+            addresses: Vec::new(),
+        };
+        assert_eq!(no_proxies.client_ip(Some(proxy), Some(spoofed)), Some(proxy));
     }
 }
\ No newline at end of file
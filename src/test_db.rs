@@ -0,0 +1,46 @@
+use std::env;
+
+use diesel::{
+    prelude::*,
+    PgConnection,
+};
+
+use super::{
+    schema,
+    sessions,
+    users,
+};
+
+/// Connects to `TEST_DATABASE_URL`, which needs to have had the migrations run on it.
+/// Everything done through the connection is rolled back when it's dropped.
+/// Returns None when the variable isn't set, so tests that need a database can be skipped.
+pub fn connect() -> Option<PgConnection> {
+    let database_url = match env::var("TEST_DATABASE_URL") {
+        Ok(database_url) => database_url,
+        Err(_) => {
+            eprintln!("TEST_DATABASE_URL isn't set, skipping");
+            return None
+        }
+    };
+    let pg_conn = PgConnection::establish(&database_url).expect("Failed to connect to TEST_DATABASE_URL");
+    pg_conn.begin_test_transaction().expect("Failed to start test transaction");
+    Some(pg_conn)
+}
+
+/// Adds a user whose email is `<username>@example.com` and whose password is `password`.
+pub fn create_user(pg_conn: &PgConnection, username: &str) -> users::User {
+    users::NewUser {
+        username: username.to_string(),
+        email: format!("{}@example.com", username),
+        password: "password".to_string(),
+    }.execute(pg_conn).expect("Failed to create test user");
+    schema::users::table.filter(schema::users::username.eq(username)).first::<users::User>(pg_conn).expect("Failed to get test user")
+}
+
+/// What a request from `ip` would carry.
+pub fn client_info(ip: &str) -> sessions::ClientInfo {
+    sessions::ClientInfo {
+        user_agent: "test".to_string(),
+        ip: Some(ip.to_string()),
+    }
+}
\ No newline at end of file
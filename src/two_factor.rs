@@ -0,0 +1,509 @@
+use chrono::{
+    DateTime,
+    Duration,
+    Utc,
+};
+
+use diesel::{
+    prelude::*,
+    PgConnection,
+};
+
+use hmac::{
+    Hmac,
+    Mac,
+    NewMac,
+};
+
+use percent_encoding::{
+    NON_ALPHANUMERIC,
+    percent_encode,
+};
+
+use qrcode::{
+    QrCode,
+    render::svg,
+};
+
+use rand::{
+    Rng,
+    thread_rng,
+};
+
+use rocket::{
+    http::{
+        Cookie,
+        Cookies,
+        Status,
+    },
+    request::LenientForm,
+    response::{
+        Redirect,
+        status,
+    },
+    State,
+    uri,
+};
+
+use rocket_contrib::templates::Template;
+
+use sha1::Sha1;
+
+use super::{
+    audit,
+    common::*,
+    crypt_eq::{
+        crypt,
+        CryptExpressionMethods,
+        gen_salt,
+    },
+    DbConn,
+    login_throttle,
+    schema,
+    schema::recovery_codes,
+    sessions,
+    signed_in_context,
+    users,
+};
+
+const ISSUER: &str = "School Things";
+const DIGITS: u32 = 6;
+/// Seconds each code is valid for.
+const STEP: i64 = 30;
+/// How many steps either side of now are accepted, to allow for clock drift.
+const SKEW: i64 = 1;
+const SECRET_BYTES: usize = 20;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+const PENDING_LOGIN_COOKIE: &str = "pending_login";
+/// How long someone has between entering their password and entering their code.
+const PENDING_LOGIN_MINUTES: i64 = 5;
+
+#[derive(FromForm)]
+pub struct CodeForm {
+    pub code: String,
+}
+
+/// Changing two-factor settings once they're on needs the password as well as a code.
+#[derive(FromForm)]
+pub struct ConfirmForm {
+    pub password: String,
+    pub code: String,
+}
+
+fn base32_alphabet() -> base32::Alphabet {
+    base32::Alphabet::RFC4648 {
+        padding: false,
+    }
+}
+
+pub fn generate_secret() -> String {
+    let bytes: [u8; SECRET_BYTES] = thread_rng().gen();
+    base32::encode(base32_alphabet(), &bytes)
+}
+
+/// The RFC 6238 code for `secret` at the given time step.
+fn code_at(secret: &[u8], counter: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_varkey(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = ((hash[offset] as u32 & 0x7f) << 24)
+        | ((hash[offset + 1] as u32) << 16)
+        | ((hash[offset + 2] as u32) << 8)
+        | (hash[offset + 3] as u32);
+    binary % 10u32.pow(DIGITS)
+}
+
+/// Returns the time step `code` matches, if any.
+fn matching_counter(secret: &str, code: &str) -> Option<i64> {
+    let secret = base32::decode(base32_alphabet(), secret)?;
+    let code = code.parse::<u32>().ok()?;
+    let now = Utc::now().timestamp() / STEP;
+    (now - SKEW..=now + SKEW).find(|counter| code_at(&secret, *counter) == code)
+}
+
+pub fn otpauth_uri(username: &str, secret: &str) -> String {
+    let label = percent_encode(format!("{}:{}", ISSUER, username).as_bytes(), NON_ALPHANUMERIC).to_string();
+    let issuer = percent_encode(ISSUER.as_bytes(), NON_ALPHANUMERIC).to_string();
+    format!("otpauth://totp/{}?secret={}&issuer={}&digits={}&period={}", label, secret, issuer, DIGITS, STEP)
+}
+
+pub fn qr_svg(uri: &str) -> Result<String, String> {
+    match QrCode::new(uri.as_bytes()) {
+        Ok(code) => Ok(code.render::<svg::Color>().min_dimensions(200, 200).build()),
+        Err(e) => Err(format!("Failed to make qr code: {}", e))
+    }
+}
+
+/// Checks a code from the user's authenticator app. Each code only works once.
+fn verify_totp(pg_conn: &PgConnection, user: &users::User, secret: &str, code: &str) -> Result<bool, String> {
+    let counter = match matching_counter(secret, code) {
+        Some(counter) => counter,
+        None => return Ok(false)
+    };
+    if user.totp_last_counter.map_or(false, |last_counter| counter <= last_counter) {
+        return Ok(false)
+    }
+    match diesel::update(schema::users::table.find(user.id)).set(schema::users::totp_last_counter.eq(counter)).execute(pg_conn) {
+        Ok(_) => Ok(true),
+        Err(e) => Err(format!("Failed to record totp counter: {}", e))
+    }
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_lowercase()
+}
+
+fn verify_recovery_code(pg_conn: &PgConnection, user_id: i64, code: &str) -> Result<bool, String> {
+    let code = normalize_recovery_code(code);
+    match recovery_codes::table
+        .filter(recovery_codes::user_id.eq(user_id))
+        .filter(recovery_codes::used_at.is_null())
+        .filter(recovery_codes::code_hash.crypt_eq(&code))
+        .select(recovery_codes::id)
+        .first::<i64>(pg_conn) {
+        Ok(id) => {
+            match diesel::update(recovery_codes::table.find(id)).set(recovery_codes::used_at.eq(Utc::now())).execute(pg_conn) {
+                Ok(_) => Ok(true),
+                Err(e) => Err(format!("Failed to use recovery code: {}", e))
+            }
+        },
+        Err(diesel::NotFound) => Ok(false),
+        Err(e) => Err(format!("Failed to check recovery code: {}", e))
+    }
+}
+
+/// Checks either a code from the user's authenticator app or one of their recovery codes.
+pub fn verify(pg_conn: &PgConnection, user: &users::User, code: &str) -> Result<bool, String> {
+    let code = code.trim();
+    match (&user.totp_secret, user.totp_enabled_at) {
+        (Some(secret), Some(_)) => {
+            if code.len() == DIGITS as usize && code.chars().all(|c| c.is_ascii_digit()) {
+                verify_totp(pg_conn, user, secret, code)
+            } else {
+                verify_recovery_code(pg_conn, user.id, code)
+            }
+        },
+        _ => Ok(false)
+    }
+}
+
+/// Replaces the user's recovery codes, returning the only copy of the new ones.
+pub fn generate_recovery_codes(pg_conn: &PgConnection, user_id: i64) -> Result<Vec<String>, String> {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| random_string(RECOVERY_CODE_LENGTH).to_lowercase()).collect();
+    let result = pg_conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user_id))).execute(pg_conn)?;
+        for code in &codes {
+            diesel::insert_into(recovery_codes::table).values((
+                recovery_codes::user_id.eq(user_id),
+                recovery_codes::code_hash.eq(crypt(code.as_str(), gen_salt("bf"))),
+            )).execute(pg_conn)?;
+        }
+        Ok(())
+    });
+    match result {
+        Ok(_) => Ok(codes),
+        Err(e) => Err(format!("Failed to generate recovery codes: {}", e))
+    }
+}
+
+/// Remembers that `user_id` got their password right, until they enter their code.
+pub fn begin_login(cookies: &mut Cookies, user_id: i64) {
+    let expires_at = Utc::now() + Duration::minutes(PENDING_LOGIN_MINUTES);
+    cookies.add_private(Cookie::new(PENDING_LOGIN_COOKIE, format!("{}_{}", user_id, expires_at.timestamp())));
+}
+
+fn pending_login(cookies: &mut Cookies) -> Option<i64> {
+    let cookie = cookies.get_private(PENDING_LOGIN_COOKIE)?;
+    let mut parts = cookie.value().splitn(2, '_');
+    match (parts.next().map(|id| id.parse::<i64>()), parts.next().map(|expires_at| expires_at.parse::<i64>())) {
+        (Some(Ok(user_id)), Some(Ok(expires_at))) if expires_at > Utc::now().timestamp() => Some(user_id),
+        _ => None
+    }
+}
+
+fn signed_in_user(pg_conn: &PgConnection, cookies: Cookies) -> Result<users::User, status::Custom<&'static str>> {
+    users::get_from_cookies(pg_conn, cookies).map_err(|_| status::Custom(Status::Forbidden, "Must be signed in"))
+}
+
+/// Checks the password and code through `users::confirm`, so wrong guesses count towards the login throttle.
+fn confirm(pg_conn: &PgConnection, throttle: &login_throttle::Throttle, client_info: &sessions::ClientInfo, user: &users::User, confirm_form: &ConfirmForm) -> Result<users::User, status::Custom<String>> {
+    users::confirm(pg_conn, throttle, client_info, &users::ConfirmUser {
+        username: user.username.clone(),
+        password: confirm_form.password.clone(),
+        code: Some(confirm_form.code.clone()),
+    }).map_err(|e| status::Custom(Status::Forbidden, e))
+}
+
+#[get("/login/twoFactor")]
+pub fn login_two_factor(db_conn: DbConn, cookies: Cookies) -> Template {
+    let (context, _, _) = signed_in_context(&*db_conn, cookies);
+    Template::render("login_two_factor", &context)
+}
+
+#[post("/login/twoFactor", data = "<code_form>")]
+pub fn submit_login_two_factor(code_form: LenientForm<CodeForm>, db_conn: DbConn, mut cookies: Cookies, client_info: sessions::ClientInfo, throttle: State<login_throttle::Throttle>) -> Result<Redirect, Template> {
+    let user = match pending_login(&mut cookies).map(|user_id| users::get_active(&*db_conn, user_id)) {
+        Some(Ok(user)) => user,
+        _ => {
+            cookies.remove_private(Cookie::named(PENDING_LOGIN_COOKIE));
+            return Ok(Redirect::to(uri!(users::login: "Your login expired, please try again".to_string(), String::new())))
+        }
+    };
+
+    let error = match throttle.check(&*db_conn, &user.username, &client_info) {
+        Err(message) => Some(message),
+        Ok(_) => {
+            match verify(&*db_conn, &user, &code_form.code) {
+                Ok(true) => {
+                    throttle.succeeded(&*db_conn, &user.username, &client_info);
+                    audit::record(&*db_conn, Some(&user), Some(user.id), audit::Action::Login, "two-factor", &client_info);
+                    cookies.remove_private(Cookie::named(PENDING_LOGIN_COOKIE));
+                    match sessions::start(&*db_conn, &mut cookies, user.id, &client_info) {
+                        Ok(_) => return Ok(Redirect::to(uri!(users::user_profile: user.username))),
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            Some("Failed to create session".to_string())
+                        }
+                    }
+                },
+                Ok(false) => {
+                    throttle.failed(&*db_conn, &user.username, &client_info);
+                    audit::record(&*db_conn, None, Some(user.id), audit::Action::LoginFailed, "two-factor", &client_info);
+                    Some("Invalid code".to_string())
+                },
+                Err(e) => {
+                    eprintln!("{}", e);
+                    Some("Failed to check code".to_string())
+                }
+            }
+        }
+    };
+
+    let (mut context, _, _) = signed_in_context(&*db_conn, cookies);
+    context.insert("error", &error);
+    Err(Template::render("login_two_factor", &context))
+}
+
+#[get("/twoFactor")]
+pub fn two_factor(db_conn: DbConn, cookies: Cookies) -> Result<Template, status::Custom<&'static str>> {
+    let (mut context, _, user) = signed_in_context(&*db_conn, cookies);
+    match user {
+        Some(user) => {
+            match recovery_codes::table.filter(recovery_codes::user_id.eq(user.id)).filter(recovery_codes::used_at.is_null()).count().get_result::<i64>(&*db_conn) {
+                Ok(remaining) => context.insert("recovery_codes_remaining", &remaining),
+                Err(e) => eprintln!("Failed to count recovery codes: {}", e)
+            }
+            Ok(Template::render("two_factor", &context))
+        },
+        None => Err(status::Custom(Status::Forbidden, "Must be signed in"))
+    }
+}
+
+#[post("/twoFactor/setup")]
+pub fn setup_two_factor(db_conn: DbConn, cookies: Cookies) -> Result<Template, status::Custom<&'static str>> {
+    let (mut context, _, user) = signed_in_context(&*db_conn, cookies);
+    let user = match user {
+        Some(user) => user,
+        None => return Err(status::Custom(Status::Forbidden, "Must be signed in"))
+    };
+    if user.totp_enabled_at.is_some() {
+        return Err(status::Custom(Status::BadRequest, "Two-factor authentication is already enabled"))
+    }
+
+    // The secret isn't used for logins until it's confirmed with a code
+    let secret = generate_secret();
+    match diesel::update(schema::users::table.find(user.id)).set((
+        schema::users::totp_secret.eq(&secret),
+        schema::users::totp_last_counter.eq::<Option<i64>>(None),
+    )).execute(&*db_conn) {
+        Ok(_) => {},
+        Err(_) => return Err(status::Custom(Status::InternalServerError, "Failed to save two-factor secret"))
+    }
+    let uri = otpauth_uri(&user.username, &secret);
+    match qr_svg(&uri) {
+        Ok(qr_svg) => context.insert("qr_svg", &qr_svg),
+        Err(e) => eprintln!("{}", e)
+    }
+    context.insert("secret", &secret);
+    Ok(Template::render("two_factor_setup", &context))
+}
+
+#[post("/twoFactor/confirm", data = "<code_form>")]
+pub fn confirm_two_factor(code_form: LenientForm<CodeForm>, db_conn: DbConn, mut cookies: Cookies, client_info: sessions::ClientInfo) -> Result<Template, status::Custom<&'static str>> {
+    let user = match sessions::get_from_cookie_jar(&*db_conn, &mut cookies).and_then(|session| users::get_from_session(&*db_conn, &session)) {
+        Ok(user) => user,
+        Err(_) => return Err(status::Custom(Status::Forbidden, "Must be signed in"))
+    };
+    let secret = match (&user.totp_secret, user.totp_enabled_at) {
+        (Some(secret), None) => secret,
+        (_, Some(_)) => return Err(status::Custom(Status::BadRequest, "Two-factor authentication is already enabled")),
+        (None, None) => return Err(status::Custom(Status::BadRequest, "Set up two-factor authentication first"))
+    };
+    match verify_totp(&*db_conn, &user, secret, code_form.code.trim()) {
+        Ok(true) => {},
+        Ok(false) => return Err(status::Custom(Status::BadRequest, "Invalid code, go back and try again")),
+        Err(e) => {
+            eprintln!("{}", e);
+            return Err(status::Custom(Status::InternalServerError, "Failed to check code"))
+        }
+    }
+
+    match diesel::update(schema::users::table.find(user.id)).set(schema::users::totp_enabled_at.eq(Utc::now())).execute(&*db_conn) {
+        Ok(_) => {},
+        Err(_) => return Err(status::Custom(Status::InternalServerError, "Failed to enable two-factor authentication"))
+    }
+    audit::record(&*db_conn, Some(&user), Some(user.id), audit::Action::TwoFactorEnabled, "", &client_info);
+    let codes = match generate_recovery_codes(&*db_conn, user.id) {
+        Ok(codes) => codes,
+        Err(e) => {
+            eprintln!("{}", e);
+            return Err(status::Custom(Status::InternalServerError, "Failed to generate recovery codes"))
+        }
+    };
+    match sessions::start(&*db_conn, &mut cookies, user.id, &client_info) {
+        Ok(_) => {},
+        Err(e) => eprintln!("{}", e)
+    }
+
+    let (mut context, _, _) = signed_in_context(&*db_conn, cookies);
+    context.insert("recovery_codes", &codes);
+    Ok(Template::render("two_factor_recovery_codes", &context))
+}
+
+#[post("/twoFactor/recoveryCodes", data = "<confirm_form>")]
+pub fn regenerate_recovery_codes(confirm_form: LenientForm<ConfirmForm>, db_conn: DbConn, cookies: Cookies, client_info: sessions::ClientInfo, throttle: State<login_throttle::Throttle>) -> Result<Template, status::Custom<String>> {
+    let (mut context, _, user) = signed_in_context(&*db_conn, cookies);
+    let user = match user {
+        Some(user) => user,
+        None => return Err(status::Custom(Status::Forbidden, "Must be signed in".to_string()))
+    };
+    confirm(&*db_conn, &throttle, &client_info, &user, &confirm_form)?;
+    match generate_recovery_codes(&*db_conn, user.id) {
+        Ok(codes) => {
+            context.insert("recovery_codes", &codes);
+            Ok(Template::render("two_factor_recovery_codes", &context))
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(status::Custom(Status::InternalServerError, "Failed to generate recovery codes".to_string()))
+        }
+    }
+}
+
+#[post("/twoFactor/disable", data = "<confirm_form>")]
+pub fn disable_two_factor(confirm_form: LenientForm<ConfirmForm>, db_conn: DbConn, cookies: Cookies, client_info: sessions::ClientInfo, throttle: State<login_throttle::Throttle>) -> Result<Redirect, status::Custom<String>> {
+    let user = signed_in_user(&*db_conn, cookies).map_err(|e| status::Custom(e.0, e.1.to_string()))?;
+    confirm(&*db_conn, &throttle, &client_info, &user, &confirm_form)?;
+    let result = db_conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::update(schema::users::table.find(user.id)).set((
+            schema::users::totp_secret.eq::<Option<String>>(None),
+            schema::users::totp_enabled_at.eq::<Option<DateTime<Utc>>>(None),
+            schema::users::totp_last_counter.eq::<Option<i64>>(None),
+        )).execute(&*db_conn)?;
+        diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user.id))).execute(&*db_conn)
+    });
+    match result {
+        Ok(_) => {
+            audit::record(&*db_conn, Some(&user), Some(user.id), audit::Action::TwoFactorDisabled, "", &client_info);
+            Ok(Redirect::to(uri!(two_factor)))
+        },
+        Err(_) => Err(status::Custom(Status::InternalServerError, "Failed to disable two-factor authentication".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_db;
+
+    /// The SHA1 secret from RFC 6238 Appendix B.
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn codes_match_rfc_6238() {
+        // The appendix's codes are 8 digits, these are their last 6
+        let vectors = [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+            (20000000000, 353130),
+        ];
+        for (time, code) in vectors.iter() {
+            assert_eq!(code_at(RFC_SECRET, time / STEP), *code, "time {}", time);
+        }
+    }
+
+    #[test]
+    fn codes_match_within_the_skew() {
+        let secret = base32::encode(base32_alphabet(), RFC_SECRET);
+        let now = Utc::now().timestamp() / STEP;
+        let window: Vec<u32> = (now - SKEW..=now + SKEW).map(|counter| code_at(RFC_SECRET, counter)).collect();
+        for counter in now - SKEW..=now + SKEW {
+            let code = format!("{:06}", code_at(RFC_SECRET, counter));
+            assert_eq!(matching_counter(&secret, &code).map(|matched| code_at(RFC_SECRET, matched)), Some(code_at(RFC_SECRET, counter)));
+        }
+        let outside = (0..1_000_000).find(|code| !window.contains(code)).unwrap();
+        assert_eq!(matching_counter(&secret, &format!("{:06}", outside)), None);
+        assert_eq!(matching_counter(&secret, "not a code"), None);
+    }
+
+    #[test]
+    fn changing_settings_needs_the_password() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "guarded");
+        let secret = base32::encode(base32_alphabet(), RFC_SECRET);
+        diesel::update(schema::users::table.find(user.id)).set((
+            schema::users::totp_secret.eq(&secret),
+            schema::users::totp_enabled_at.eq(Utc::now()),
+        )).execute(&pg_conn).unwrap();
+        let user = users::get(&pg_conn, user.id).unwrap();
+        let throttle = login_throttle::Throttle::new(Box::new(login_throttle::MemoryLimiter::new()));
+        let client_info = test_db::client_info("192.0.2.1");
+        let code = format!("{:06}", code_at(RFC_SECRET, Utc::now().timestamp() / STEP));
+
+        let wrong_password = ConfirmForm {
+            password: "wrong".to_string(),
+            code: code.clone(),
+        };
+        assert!(confirm(&pg_conn, &throttle, &client_info, &user, &wrong_password).is_err());
+        let right = ConfirmForm {
+            password: "password".to_string(),
+            code,
+        };
+        assert_eq!(confirm(&pg_conn, &throttle, &client_info, &user, &right).map(|confirmed| confirmed.id).ok(), Some(user.id));
+        // Each code only works once
+        assert!(confirm(&pg_conn, &throttle, &client_info, &user, &right).is_err());
+    }
+
+    #[test]
+    fn wrong_codes_are_throttled() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "guessed");
+        diesel::update(schema::users::table.find(user.id)).set((
+            schema::users::totp_secret.eq(base32::encode(base32_alphabet(), RFC_SECRET)),
+            schema::users::totp_enabled_at.eq(Utc::now()),
+        )).execute(&pg_conn).unwrap();
+        let user = users::get(&pg_conn, user.id).unwrap();
+        let throttle = login_throttle::Throttle::new(Box::new(login_throttle::MemoryLimiter::new()));
+        let client_info = test_db::client_info("192.0.2.1");
+
+        let guess = ConfirmForm {
+            password: "password".to_string(),
+            code: "wrong".to_string(),
+        };
+        for _ in 0..5 {
+            assert_eq!(confirm(&pg_conn, &throttle, &client_info, &user, &guess).map_err(|e| e.1).err(), Some("Invalid two-factor code".to_string()));
+        }
+        assert!(confirm(&pg_conn, &throttle, &client_info, &user, &guess).map_err(|e| e.1).err().unwrap().starts_with("Too many failed logins"));
+    }
+}
\ No newline at end of file
@@ -1,16 +1,22 @@
 use ammonia::clean_text;
 
+use chrono::{
+    DateTime,
+    Duration,
+    Utc,
+};
+
 use rocket::{
     http::{
-        Cookie,
         Cookies,
         Status,
     },
-    request::Form,
+    request::LenientForm,
     response::{
         Redirect,
         status,
     },
+    State,
     uri,
 };
 
@@ -30,29 +36,82 @@ use diesel::{
 use serde::Serialize;
 
 use super::{
+    api_tokens,
+    apps,
+    audit,
+    avatars,
     common::*,
     crypt_eq::CryptExpressionMethods,
     DbConn,
+    email_verifications,
+    login_throttle,
+    mail,
+    oidc,
+    policy,
+    repos,
     schema,
     sessions,
     signed_in_context,
+    two_factor,
 };
 
 use validator::validate_email;
 
+/// How long an old username redirects to its user's profile, during which nobody else can take it.
+const USERNAME_GRACE_DAYS: i64 = 30;
+/// How long a user has to wait between renames, so they can't hold onto lots of names at once.
+const RENAME_COOLDOWN_DAYS: i64 = 7;
+/// How many recent additions to their repos a profile lists.
+const PROFILE_ACTIVITY: i64 = 10;
 
 #[derive(Queryable, Serialize)]
 pub struct User {
     pub id: i64,
     pub username: String,
     pub email: String,
+    #[serde(skip_serializing)]
     pub password_hash: String,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing)]
+    pub totp_secret: Option<String>,
+    pub totp_enabled_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing)]
+    pub totp_last_counter: Option<i64>,
+    pub role: String,
+    pub suspended_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub display_name: String,
+    /// Markdown, only ever rendered through `render_markdown`.
+    pub bio: String,
+    /// Whether other people can see the email on their profile.
+    pub email_visible: bool,
+    /// Whether they show up when people search for users.
+    pub searchable: bool,
+    /// Whether their profile is listed in the sitemap and open to search engines.
+    pub indexed: bool,
 }
 
+impl User {
+    /// Suspended users can't sign in, and any sessions or tokens they have stop working.
+    pub fn check_active(&self) -> Result<(), &'static str> {
+        match self.suspended_at {
+            Some(_) => Err("This account has been suspended"),
+            None => Ok(())
+        }
+    }
+
+    /// Anything unrecognized is treated as the least privileged role.
+    pub fn role(&self) -> policy::Role {
+        self.role.parse().unwrap_or(policy::Role::Student)
+    }
+}
+
+/// Only for showing users their own account, since it includes their email. Anyone else gets a `PublicUser`.
 #[derive(Serialize)]
 pub struct CleanUser {
     pub username: Cleaned,
     pub email: Cleaned,
+    pub display_name: Cleaned,
 }
 
 impl CleanUser {
@@ -60,17 +119,100 @@ impl CleanUser {
         CleanUser {
             username: Cleaned::new(&user.username),
             email: Cleaned::new(&user.email),
+            display_name: Cleaned::new(&user.display_name),
         }
     }
+}
 
-    pub fn from_vec(users: &Vec<User>) -> Vec<CleanUser> {
-        let mut cleaned = Vec::new();
-        
-        for user in users {
-            cleaned.push(CleanUser::from_user(&user));
+/// What anyone can see about a user. The email is left out unless they've made it visible.
+#[derive(Serialize)]
+pub struct PublicUser {
+    pub id: i64,
+    pub username: Cleaned,
+    pub display_name: Cleaned,
+    pub role: String,
+    pub email: Option<Cleaned>,
+    pub joined_at: String,
+}
+
+impl PublicUser {
+    pub fn from_user(user: &User) -> PublicUser {
+        PublicUser {
+            id: user.id,
+            username: Cleaned::new(&user.username),
+            display_name: Cleaned::new(&user.display_name),
+            role: user.role.clone(),
+            email: if user.email_visible { Some(Cleaned::new(&user.email)) } else { None },
+            joined_at: user.created_at.format("%Y-%m-%d").to_string(),
         }
+    }
 
-        return cleaned;
+    pub fn from_vec(users: &[User]) -> Vec<PublicUser> {
+        users.iter().map(PublicUser::from_user).collect()
+    }
+}
+
+#[derive(Serialize)]
+pub struct ProfileApp {
+    pub title: Cleaned,
+    pub description: Cleaned,
+    pub connected: bool,
+    pub connected_error: Cleaned,
+    pub hidden: bool,
+}
+
+impl ProfileApp {
+    pub fn from_app(app: &apps::App) -> ProfileApp {
+        ProfileApp {
+            title: Cleaned::new(&app.title),
+            description: Cleaned::new(&app.description),
+            connected: app.connected,
+            connected_error: Cleaned::new(&app.connected_error),
+            hidden: app.hidden_at.is_some(),
+        }
+    }
+
+    pub fn from_vec(apps: &[apps::App]) -> Vec<ProfileApp> {
+        apps.iter().map(ProfileApp::from_app).collect()
+    }
+}
+
+#[derive(Serialize)]
+pub struct ProfileRepo {
+    pub title: Cleaned,
+    pub description: Cleaned,
+    pub hidden: bool,
+}
+
+impl ProfileRepo {
+    pub fn from_repo(repo: &repos::Repo) -> ProfileRepo {
+        ProfileRepo {
+            title: Cleaned::new(&repo.title),
+            description: Cleaned::new(&repo.description),
+            hidden: repo.hidden_at.is_some(),
+        }
+    }
+
+    pub fn from_vec(repos: &[repos::Repo]) -> Vec<ProfileRepo> {
+        repos.iter().map(ProfileRepo::from_repo).collect()
+    }
+}
+
+/// An app being added to one of the user's repos.
+#[derive(Serialize)]
+pub struct ProfileActivity {
+    pub repo: Cleaned,
+    pub app: Cleaned,
+    pub added_at: String,
+}
+
+impl ProfileActivity {
+    pub fn from_vec(additions: &[(repos::Repo, apps::App, DateTime<Utc>)]) -> Vec<ProfileActivity> {
+        additions.iter().map(|(repo, app, added_at)| ProfileActivity {
+            repo: Cleaned::new(&repo.title),
+            app: Cleaned::new(&app.title),
+            added_at: added_at.format("%Y-%m-%d").to_string(),
+        }).collect()
     }
 }
 
@@ -80,6 +222,19 @@ pub struct LoginUser {
     pub password: String,
 }
 
+/// A password, and a two-factor code if the user has it enabled, to confirm something destructive.
+#[derive(FromForm)]
+pub struct ConfirmUser {
+    pub username: String,
+    pub password: String,
+    pub code: Option<String>,
+}
+
+#[derive(FromForm)]
+pub struct RoleForm {
+    pub role: String,
+}
+
 #[derive(FromForm, QueryId)]
 pub struct NewUser {
     pub username: String,
@@ -116,9 +271,95 @@ pub fn get_by_username(pg_conn: &PgConnection, username: String) -> Result<User,
     }
 }
 
+/// Gets the user that had `username` before renaming, as long as they did within the grace period.
+pub fn get_by_old_username(pg_conn: &PgConnection, username: &str) -> Result<User, String> {
+    match schema::username_redirects::table
+        .inner_join(schema::users::table)
+        .filter(lower(schema::username_redirects::old_username).eq(username.to_lowercase()))
+        .filter(schema::username_redirects::created_at.gt(Utc::now() - Duration::days(USERNAME_GRACE_DAYS)))
+        .select(schema::users::all_columns)
+        .first::<User>(pg_conn) {
+        Ok(user) => Ok(user),
+        Err(e) => Err(format!("Failed to get user by old username {}", e))
+    }
+}
+
+/// Whether someone other than `user_id` gave up `username` too recently for it to be taken.
+pub fn is_reserved(pg_conn: &PgConnection, username: &str, user_id: Option<i64>) -> Result<bool, String> {
+    match schema::username_redirects::table
+        .filter(lower(schema::username_redirects::old_username).eq(username.to_lowercase()))
+        .filter(schema::username_redirects::created_at.gt(Utc::now() - Duration::days(USERNAME_GRACE_DAYS)))
+        .select(schema::username_redirects::user_id)
+        .load::<i64>(pg_conn) {
+        Ok(owner_ids) => Ok(owner_ids.iter().any(|owner_id| Some(*owner_id) != user_id)),
+        Err(e) => Err(format!("Failed to check username {}: {}", username, e))
+    }
+}
+
+pub fn validate_username(username: &str) -> Result<(), &'static str> {
+    if !validate_title(username) || username.len() > 24 {return Err("Username must be 3-24 letters, numbers, dashes or underscores")}
+    Ok(())
+}
+
+/// Renames the user, leaving their old username redirecting to them for the grace period.
+pub fn rename(pg_conn: &PgConnection, user: &User, username: &str) -> Result<User, status::Custom<&'static str>> {
+    validate_username(username).map_err(|e| status::Custom(Status::BadRequest, e))?;
+    if username == user.username {
+        return Err(status::Custom(Status::BadRequest, "That's already your username"))
+    }
+    let internal_error = |e: String| {
+        eprintln!("{}", e);
+        status::Custom(Status::InternalServerError, "Failed to change username")
+    };
+    if is_reserved(pg_conn, username, Some(user.id)).map_err(internal_error)? {
+        return Err(status::Custom(Status::BadRequest, "Duplicate username"))
+    }
+    match schema::username_redirects::table
+        .filter(schema::username_redirects::user_id.eq(user.id))
+        .filter(schema::username_redirects::created_at.gt(Utc::now() - Duration::days(RENAME_COOLDOWN_DAYS)))
+        .count()
+        .get_result::<i64>(pg_conn) {
+        Ok(0) => {},
+        Ok(_) => return Err(status::Custom(Status::BadRequest, "You can only change your username once a week")),
+        Err(e) => return Err(internal_error(format!("Failed to check recent renames of user {}: {}", user.id, e)))
+    }
+
+    let result = pg_conn.transaction::<_, diesel::result::Error, _>(|| {
+        let renamed = diesel::update(schema::users::table.find(user.id))
+            .set(schema::users::username.eq(username))
+            .get_result::<User>(pg_conn)?;
+        // Clears expired redirects, and the user's own if they're taking an old name back
+        diesel::delete(schema::username_redirects::table.filter(
+            lower(schema::username_redirects::old_username).eq(username.to_lowercase())
+                .or(lower(schema::username_redirects::old_username).eq(user.username.to_lowercase()))
+        )).execute(pg_conn)?;
+        diesel::insert_into(schema::username_redirects::table).values((
+            schema::username_redirects::old_username.eq(&user.username),
+            schema::username_redirects::user_id.eq(user.id),
+        )).execute(pg_conn)?;
+        Ok(renamed)
+    });
+    match result {
+        Ok(renamed) => Ok(renamed),
+        Err(e) => {
+            match &*e.to_string() {
+                "duplicate key value violates unique constraint \"users_username_unique_idx\"" => Err(status::Custom(Status::BadRequest, "Duplicate username")),
+                _ => Err(internal_error(format!("Failed to rename user {}: {}", user.id, e)))
+            }
+        }
+    }
+}
+
+/// Gets a user that's allowed to use the site, for anything acting on their behalf.
+pub fn get_active(pg_conn: &PgConnection, user_id: i64) -> Result<User, String> {
+    let user = get(pg_conn, user_id)?;
+    user.check_active()?;
+    Ok(user)
+}
+
 pub fn get_from_session(pg_conn: &PgConnection, session: &sessions::Session) -> Result<User, String> {
     match session.logged_in_user {
-        Some(logged_in_user) => get(pg_conn, logged_in_user),
+        Some(logged_in_user) => get_active(pg_conn, logged_in_user),
         None => Err("Session is not logged in".to_string())
     }
 }
@@ -127,7 +368,7 @@ pub fn get_from_cookies(pg_conn: &PgConnection, cookies: Cookies) -> Result<User
     match sessions::get_from_cookies(pg_conn, cookies) {
         Ok(session) => {
             match session.logged_in_user {
-                Some(logged_in_user) => get(pg_conn, logged_in_user),
+                Some(logged_in_user) => get_active(pg_conn, logged_in_user),
                 None => Err("Session is not logged in".to_string())
             }
         },
@@ -135,6 +376,43 @@ pub fn get_from_cookies(pg_conn: &PgConnection, cookies: Cookies) -> Result<User
     }
 }
 
+/// Checks the credentials someone gave to confirm a destructive action. Wrong passwords and two-factor codes count towards the same throttle as logins.
+pub fn confirm(pg_conn: &PgConnection, throttle: &login_throttle::Throttle, client_info: &sessions::ClientInfo, confirm_user: &ConfirmUser) -> Result<User, String> {
+    throttle.check(pg_conn, &confirm_user.username, client_info)?;
+    match schema::users::table.filter(schema::users::username.eq(&confirm_user.username)).filter(schema::users::password_hash.crypt_eq(&confirm_user.password)).first::<User>(pg_conn) {
+        Ok(user) => {
+            user.check_active()?;
+            if user.totp_enabled_at.is_none() {
+                throttle.succeeded(pg_conn, &confirm_user.username, client_info);
+                return Ok(user)
+            }
+            match &confirm_user.code {
+                Some(code) => {
+                    match two_factor::verify(pg_conn, &user, code) {
+                        Ok(true) => {
+                            throttle.succeeded(pg_conn, &confirm_user.username, client_info);
+                            Ok(user)
+                        },
+                        Ok(false) => {
+                            throttle.failed(pg_conn, &confirm_user.username, client_info);
+                            Err("Invalid two-factor code".to_string())
+                        },
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            Err("Failed to check two-factor code".to_string())
+                        }
+                    }
+                },
+                None => Err("Two-factor code required".to_string())
+            }
+        },
+        Err(_) => {
+            throttle.failed(pg_conn, &confirm_user.username, client_info);
+            Err("Failed to authenticate user".to_string())
+        }
+    }
+}
+
 pub fn get_all(pg_conn: &PgConnection) -> Result<Vec<User>, String> {
     match schema::users::table.load::<User>(pg_conn) {
         Ok(users) => Ok(users),
@@ -142,9 +420,70 @@ pub fn get_all(pg_conn: &PgConnection) -> Result<Vec<User>, String> {
     }
 }
 
+/// One page of the users whose username or email contains `search`, newest first, along with how many match in total.
+pub fn get_page(pg_conn: &PgConnection, search: &str, page: i64) -> Result<(Vec<User>, i64), String> {
+    let pattern = contains_pattern(search);
+    let total = schema::users::table
+        .filter(schema::users::username.ilike(&pattern).or(schema::users::email.ilike(&pattern)))
+        .count()
+        .get_result::<i64>(pg_conn);
+    let users = schema::users::table
+        .filter(schema::users::username.ilike(&pattern).or(schema::users::email.ilike(&pattern)))
+        .order(schema::users::created_at.desc())
+        .limit(PAGE_SIZE)
+        .offset(page.saturating_mul(PAGE_SIZE))
+        .load::<User>(pg_conn);
+    match (users, total) {
+        (Ok(users), Ok(total)) => Ok((users, total)),
+        (Err(e), _) | (_, Err(e)) => Err(format!("Failed to get page {} of users: {}", page, e))
+    }
+}
+
+/// Suspends the user and signs them out everywhere, or lifts their suspension.
+pub fn set_suspended(pg_conn: &PgConnection, user_id: i64, suspended: bool) -> Result<(), String> {
+    let suspended_at = if suspended { Some(Utc::now()) } else { None };
+    let result = pg_conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::update(schema::users::table.find(user_id)).set(schema::users::suspended_at.eq(suspended_at)).execute(pg_conn)?;
+        if suspended {
+            sessions::delete_by_user(pg_conn, user_id)?;
+        }
+        Ok(())
+    });
+    result.map_err(|e| format!("Failed to set user {} suspended: {}", user_id, e))
+}
+
+/// Signs the user out everywhere, so no session carries on with the privileges of their old role.
+pub fn change_role(pg_conn: &PgConnection, user_id: i64, role: policy::Role) -> Result<(), String> {
+    let result = pg_conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::update(schema::users::table.find(user_id)).set(schema::users::role.eq(role.as_str())).execute(pg_conn)?;
+        sessions::delete_by_user(pg_conn, user_id)
+    });
+    result.map(|_| ()).map_err(|e| format!("Failed to set role of user {}: {}", user_id, e))
+}
+
+/// Users who are happy for their profiles to be in the sitemap.
+pub fn get_indexed(pg_conn: &PgConnection) -> Result<Vec<User>, String> {
+    match schema::users::table
+        .filter(schema::users::indexed.eq(true))
+        .filter(schema::users::suspended_at.is_null())
+        .order(schema::users::id.asc())
+        .load::<User>(pg_conn) {
+        Ok(users) => Ok(users),
+        Err(e) => Err(format!("Failed to get indexed users {}", e))
+    }
+}
+
+pub fn get_recent(pg_conn: &PgConnection, limit: i64) -> Result<Vec<User>, String> {
+    match schema::users::table.order(schema::users::created_at.desc()).limit(limit).load::<User>(pg_conn) {
+        Ok(users) => Ok(users),
+        Err(e) => Err(format!("Failed to get recent users {}", e))
+    }
+}
+
 #[get("/login?<error>&<username>")]
-pub fn login(error: Option<String>, username: Option<String>, db_conn: DbConn, cookies: Cookies) -> Template {
+pub fn login(error: Option<String>, username: Option<String>, db_conn: DbConn, cookies: Cookies, providers: State<oidc::Providers>) -> Template {
     let (mut context, _, _) = signed_in_context(&*db_conn, cookies);
+    context.insert("oidc_providers", &oidc::CleanProvider::from_vec(&providers.providers));
     match error {
         Some(error) => context.insert("error", &clean_text(&error)),
         None => {}
@@ -174,53 +513,66 @@ pub fn signup(error: Option<String>, username: Option<String>, email: Option<Str
     Template::render("signup", &context)
 }
 
-fn update_session_logged_in_user(user: User, mut cookies: Cookies, db_conn: DbConn) -> Result<String, String> {
-    match cookies.get_private("session_id") {
-        Some(session_id_cookie) => {
-            match session_id_cookie.value().parse::<i64>() {
-                Ok(session_id) => {
-                    match diesel::update(schema::sessions::table.find(session_id)).set(schema::sessions::logged_in_user.eq(user.id)).execute(&*db_conn) {
-                        Ok(_) => Ok("Signed in!".to_string()),
-                        Err(_) => Err("Failed to update session".to_string())
-                    }
-                },
-                Err(_) => Err("Failed to parse session_id cookie".to_string())
-            }
-        },
-        None => {
-            match diesel::insert_into(schema::sessions::table).values(&sessions::NewSession::new(user.id)).get_result::<sessions::Session>(&*db_conn) {
-                Ok(session) => {
-                    cookies.add_private(Cookie::new("session_id", format!("{}", session.id)));
-                    Ok("Signed in!".to_string())
-                },
-                Err(_) => Err("Failed to create session".to_string())
-            }
+fn update_session_logged_in_user(user: User, mut cookies: Cookies, db_conn: DbConn, client_info: sessions::ClientInfo) -> Result<String, String> {
+    match sessions::start(&*db_conn, &mut cookies, user.id, &client_info) {
+        Ok(_) => Ok("Signed in!".to_string()),
+        Err(e) => {
+            eprintln!("{}", e);
+            Err("Failed to create session".to_string())
         }
     }
 }
 
 #[post("/login", data = "<login_user>")]
-pub fn submit_login(db_conn: DbConn, login_user: Form<LoginUser>, cookies: Cookies) -> Redirect {
+pub fn submit_login(db_conn: DbConn, login_user: LenientForm<LoginUser>, mut cookies: Cookies, client_info: sessions::ClientInfo, throttle: State<login_throttle::Throttle>) -> Redirect {
     let username = &login_user.username;
+    match throttle.check(&*db_conn, username, &client_info) {
+        Ok(_) => {},
+        Err(message) => return Redirect::to(uri!(login: message, username))
+    }
     let user_query = schema::users::table.filter(schema::users::username.eq(&username)).filter(schema::users::password_hash.crypt_eq(&login_user.password));
     match user_query.first::<User>(&*db_conn) {
         Ok(user) => {
-            match update_session_logged_in_user(user, cookies, db_conn) {
+            match user.check_active() {
+                Ok(_) => {},
+                Err(e) => {
+                    audit::record(&*db_conn, None, Some(user.id), audit::Action::LoginFailed, "suspended", &client_info);
+                    return Redirect::to(uri!(login: e.to_string(), username))
+                }
+            }
+            // The password is right, but the login isn't finished until the second factor is too
+            if user.totp_enabled_at.is_some() {
+                two_factor::begin_login(&mut cookies, user.id);
+                return Redirect::to(uri!(two_factor::login_two_factor))
+            }
+            throttle.succeeded(&*db_conn, username, &client_info);
+            audit::record(&*db_conn, Some(&user), Some(user.id), audit::Action::Login, "password", &client_info);
+            match update_session_logged_in_user(user, cookies, db_conn, client_info) {
                 Ok(_) => Redirect::to(uri!(user_profile: username)),
-                Err(_) => Redirect::to(uri!(login: "Failed to set session_id cookie.".to_string(), username))
+                Err(_) => Redirect::to(uri!(login: "Failed to set session cookie.".to_string(), username))
             }
         },
-        Err(_) => Redirect::to(uri!(login: "Couldn't authenticate user".to_string(), username))
+        Err(_) => {
+            throttle.failed(&*db_conn, username, &client_info);
+            let user_id = get_by_username(&*db_conn, username.clone()).ok().map(|user| user.id);
+            audit::record(&*db_conn, None, user_id, audit::Action::LoginFailed, username, &client_info);
+            Redirect::to(uri!(login: "Couldn't authenticate user".to_string(), username))
+        }
     }
 }
 
 #[post("/signup", data = "<new_user_form>")]
-pub fn submit_signup(db_conn: DbConn, new_user_form: Form<NewUser>, cookies: Cookies) -> Redirect {
+pub fn submit_signup(db_conn: DbConn, new_user_form: LenientForm<NewUser>, cookies: Cookies, client_info: sessions::ClientInfo, mail: State<mail::Mail>) -> Redirect {
     let new_user = new_user_form.into_inner();
     let email = new_user.email.clone();
     let username = new_user.username.clone();
-    match validate_title(&username) && username.len() <= 24 {
-        true => {
+    match validate_username(&username) {
+        Ok(_) => {
+            match is_reserved(&*db_conn, &username, None) {
+                Ok(false) => {},
+                Ok(true) => return Redirect::to(uri!(signup: "Duplicate username".to_string(), username, email)),
+                Err(e) => eprintln!("{}", e)
+            }
             match validate_email(&email) {
                 true => {
                     match new_user.password.len() >= 1 && new_user.password.len() <= 72 {
@@ -230,9 +582,13 @@ pub fn submit_signup(db_conn: DbConn, new_user_form: Form<NewUser>, cookies: Coo
                                     // TODO: Make it so custom models::NewUser insertion script is able to return models::User itself
                                     match schema::users::table.filter(schema::users::email.eq(&email)).first::<User>(&*db_conn) {
                                         Ok(user) => {
-                                            match update_session_logged_in_user(user, cookies, db_conn) {
+                                            match email_verifications::send(&*db_conn, &mail, user.id, &user.email, None) {
+                                                Ok(_) => {},
+                                                Err(e) => eprintln!("{}", e)
+                                            }
+                                            match update_session_logged_in_user(user, cookies, db_conn, client_info) {
                                                 Ok(_) => Redirect::to(uri!(user_profile: &username)),
-                                                Err(_) => Redirect::to(uri!(signup: "Failed to set session_id cookie.".to_string(), username, email))
+                                                Err(_) => Redirect::to(uri!(signup: "Failed to set session cookie.".to_string(), username, email))
                                             }
                                         },
                                         Err(_) => Redirect::to(uri!(signup: "Failed to retrieve new user to add them to session".to_string(), username, email))
@@ -256,41 +612,232 @@ pub fn submit_signup(db_conn: DbConn, new_user_form: Form<NewUser>, cookies: Coo
                 false => Redirect::to(uri!(signup: "Invalid email".to_string(), username, email))
             }
         },
-        false => Redirect::to(uri!(signup: "Invalid username".to_string(), username, email))
+        Err(_) => Redirect::to(uri!(signup: "Invalid username".to_string(), username, email))
     }
 }
 
 #[post("/signout")]
-pub fn signout(db_conn: DbConn, cookies: Cookies) -> Result<Redirect, status::Custom<String>> {
-    match sessions::get_from_cookies(&*db_conn, cookies) {
-        Ok(session) => {
-            match session.logged_in_user {
-                Some(_) => {
-                    match diesel::update(schema::sessions::table.filter(schema::sessions::id.eq(session.id))).set(schema::sessions::logged_in_user.eq::<Option<i64>>(None)).execute(&*db_conn) {
-                        Ok(_) => Ok(Redirect::to(uri!(super::home))),
-                        Err(_) => {
-                            Err(status::Custom(Status::InternalServerError, "Failed to update session".to_string()))
-                        }
+pub fn signout(db_conn: DbConn, mut cookies: Cookies, client_info: sessions::ClientInfo) -> Result<Redirect, status::Custom<String>> {
+    match sessions::get_key_from_cookies(&mut cookies) {
+        Ok(_) => {
+            let user = sessions::get_from_cookie_jar(&*db_conn, &mut cookies).and_then(|session| get_from_session(&*db_conn, &session));
+            match sessions::end(&*db_conn, &mut cookies) {
+                Ok(_) => {
+                    if let Ok(user) = user {
+                        audit::record(&*db_conn, Some(&user), Some(user.id), audit::Action::Signout, "", &client_info);
                     }
+                    Ok(Redirect::to(uri!(super::home)))
                 },
-                None => Err(status::Custom(Status::BadRequest, "Session not signed in".to_string()))
+                Err(_) => {
+                    Err(status::Custom(Status::InternalServerError, "Failed to delete session".to_string()))
+                }
             }
         },
-        Err(_) => {
-            Err(status::Custom(Status::InternalServerError, "Failed to get session from cookies".to_string()))
-        }
+        Err(_) => Err(status::Custom(Status::BadRequest, "Session not signed in".to_string()))
     }
 }
 
 #[get("/users/<username>")]
-pub fn user_profile(username: String, db_conn: DbConn, cookies: Cookies) -> Result<Template, status::NotFound<String>> {
-    let (mut context, _, _) = signed_in_context(&*db_conn, cookies);
-    match get_by_username(&*db_conn, username) {
+pub fn user_profile(username: String, db_conn: DbConn, cookies: Cookies, providers: State<oidc::Providers>) -> Page {
+    let (mut context, _, signed_in_user) = signed_in_context(&*db_conn, cookies);
+    match get_by_username(&*db_conn, username.clone()) {
         Ok(user) => {
-            context.insert("profile", &user);
-            context.insert("profile_cleaned", &CleanUser::from_user(&user));
-            Ok(Template::render("user_profile", &context))
+            let personal = signed_in_user.map_or(false, |signed_in_user| signed_in_user.id == user.id);
+            if personal {
+                match api_tokens::get_by_user(&*db_conn, user.id) {
+                    Ok(tokens) => context.insert("api_tokens", &api_tokens::CleanApiToken::from_vec(&tokens)),
+                    Err(e) => eprintln!("{}", e)
+                }
+                match oidc::get_by_user(&*db_conn, user.id) {
+                    Ok(identities) => context.insert("linked_accounts", &oidc::LinkedAccount::from_providers(&providers.providers, &identities)),
+                    Err(e) => eprintln!("{}", e)
+                }
+            }
+            match avatars::exists(&*db_conn, user.id) {
+                Ok(has_avatar) => context.insert("has_avatar", &has_avatar),
+                Err(e) => eprintln!("{}", e)
+            }
+            // Owners see their hidden apps and repos too, so they know what's been hidden
+            let owned_apps = if personal { apps::get_by_owner(&*db_conn, user.id) } else { apps::get_visible_by_owner(&*db_conn, user.id) };
+            match owned_apps {
+                Ok(owned_apps) => context.insert("apps", &ProfileApp::from_vec(&owned_apps)),
+                Err(e) => eprintln!("{}", e)
+            }
+            let owned_repos = if personal { repos::get_by_owner(&*db_conn, user.id) } else { repos::get_visible_by_owner(&*db_conn, user.id) };
+            match owned_repos {
+                Ok(owned_repos) => context.insert("repos", &ProfileRepo::from_vec(&owned_repos)),
+                Err(e) => eprintln!("{}", e)
+            }
+            match repos::get_recent_additions_by_owner(&*db_conn, user.id, PROFILE_ACTIVITY) {
+                Ok(additions) => context.insert("activity", &ProfileActivity::from_vec(&additions)),
+                Err(e) => eprintln!("{}", e)
+            }
+            context.insert("bio_html", &render_markdown(&user.bio));
+            context.insert("roles", &policy::Role::ALL.iter().map(|role| role.as_str()).collect::<Vec<&str>>());
+            context.insert("profile", &PublicUser::from_user(&user));
+            context.insert("indexed", &user.indexed);
+            Page::Found(Template::render("user_profile", &context))
+        },
+        Err(_) => {
+            match get_by_old_username(&*db_conn, &username) {
+                Ok(user) => Page::Moved(Redirect::moved(uri!(user_profile: user.username))),
+                Err(_) => Page::NotFound(status::NotFound("Couldn't find user".to_string()))
+            }
+        }
+    }
+}
+
+#[post("/users/<username>/role", data = "<role_form>")]
+pub fn set_role(username: String, role_form: LenientForm<RoleForm>, db_conn: DbConn, cookies: Cookies, client_info: sessions::ClientInfo) -> Result<Redirect, status::Custom<&'static str>> {
+    let admin = get_from_cookies(&*db_conn, cookies).map_err(|_| status::Custom(Status::Forbidden, "Must be signed in"))?;
+    policy::require_role(&admin, policy::Role::Admin)?;
+    let role = role_form.role.parse::<policy::Role>().map_err(|_| status::Custom(Status::BadRequest, "Unknown role"))?;
+    let user = get_by_username(&*db_conn, username).map_err(|_| status::Custom(Status::NotFound, "Couldn't find user"))?;
+    // Otherwise the last admin could demote themselves and leave nobody to undo it
+    if user.id == admin.id {
+        return Err(status::Custom(Status::BadRequest, "You can't change your own role"))
+    }
+    match change_role(&*db_conn, user.id, role) {
+        Ok(_) => {
+            audit::record(&*db_conn, Some(&admin), Some(user.id), audit::Action::SetRole, &format!("{} to {}", user.username, role.as_str()), &client_info);
+            Ok(Redirect::to(uri!(user_profile: user.username)))
         },
-        Err(_) => Err(status::NotFound("Couldn't find user".to_string()))
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(status::Custom(Status::InternalServerError, "Failed to set role"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        schema::sessions as sessions_table,
+        test_db,
+    };
+
+    fn sign_in(pg_conn: &PgConnection, user: &User) {
+        diesel::insert_into(sessions_table::table).values(&sessions::NewSession::new(user.id, &test_db::client_info("192.0.2.1"))).execute(pg_conn).unwrap();
+    }
+
+    #[test]
+    fn changing_roles_signs_the_user_out() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "demoted");
+        let bystander = test_db::create_user(&pg_conn, "bystander");
+        sign_in(&pg_conn, &user);
+        sign_in(&pg_conn, &bystander);
+
+        change_role(&pg_conn, user.id, policy::Role::Admin).unwrap();
+        assert_eq!(get(&pg_conn, user.id).unwrap().role(), policy::Role::Admin);
+        assert!(sessions::get_by_user(&pg_conn, user.id).unwrap().is_empty());
+        assert_eq!(sessions::get_by_user(&pg_conn, bystander.id).unwrap().len(), 1);
+    }
+
+    /// Makes the user's renames look like they happened `days` ago.
+    fn age_renames(pg_conn: &PgConnection, user_id: i64, days: i64) {
+        diesel::update(schema::username_redirects::table.filter(schema::username_redirects::user_id.eq(user_id)))
+            .set(schema::username_redirects::created_at.eq(Utc::now() - Duration::days(days)))
+            .execute(pg_conn).unwrap();
+    }
+
+    #[test]
+    fn old_usernames_redirect_for_the_grace_period() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "before");
+
+        let renamed = rename(&pg_conn, &user, "after").map_err(|e| e.1).unwrap();
+        assert_eq!(renamed.username, "after");
+        assert!(get_by_username(&pg_conn, "before".to_string()).is_err());
+        assert_eq!(get_by_old_username(&pg_conn, "before").unwrap().id, user.id);
+        assert_eq!(get_by_old_username(&pg_conn, "BEFORE").unwrap().id, user.id);
+
+        age_renames(&pg_conn, user.id, USERNAME_GRACE_DAYS);
+        assert!(get_by_old_username(&pg_conn, "before").is_err());
+    }
+
+    #[test]
+    fn released_usernames_are_held_for_their_old_owner() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "released");
+        let other = test_db::create_user(&pg_conn, "other");
+        let user = rename(&pg_conn, &user, "renamed").map_err(|e| e.1).unwrap();
+
+        assert!(is_reserved(&pg_conn, "Released", None).unwrap());
+        assert!(!is_reserved(&pg_conn, "released", Some(user.id)).unwrap());
+        assert_eq!(rename(&pg_conn, &other, "released").err().map(|e| e.1), Some("Duplicate username"));
+        // Taken names are refused whatever their case
+        assert_eq!(rename(&pg_conn, &other, "Renamed").err().map(|e| e.1), Some("Duplicate username"));
+
+        age_renames(&pg_conn, user.id, RENAME_COOLDOWN_DAYS);
+        let user = rename(&pg_conn, &user, "released").map_err(|e| e.1).unwrap();
+        assert_eq!(user.username, "released");
+        assert!(is_reserved(&pg_conn, "renamed", Some(other.id)).unwrap());
+
+        age_renames(&pg_conn, user.id, USERNAME_GRACE_DAYS);
+        assert!(!is_reserved(&pg_conn, "renamed", Some(other.id)).unwrap());
+        assert!(rename(&pg_conn, &other, "renamed").is_ok());
+    }
+
+    #[test]
+    fn renames_have_a_cooldown() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "restless");
+        let user = rename(&pg_conn, &user, "restless2").map_err(|e| e.1).unwrap();
+
+        assert_eq!(rename(&pg_conn, &user, "restless3").err().map(|e| e.1), Some("You can only change your username once a week"));
+        age_renames(&pg_conn, user.id, RENAME_COOLDOWN_DAYS - 1);
+        assert!(rename(&pg_conn, &user, "restless3").is_err());
+        age_renames(&pg_conn, user.id, RENAME_COOLDOWN_DAYS);
+        assert!(rename(&pg_conn, &user, "restless3").is_ok());
+    }
+
+    #[test]
+    fn profiles_only_show_emails_made_visible() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let user = test_db::create_user(&pg_conn, "private");
+        let profile = serde_json::to_string(&PublicUser::from_user(&user)).unwrap();
+        assert!(!profile.contains("private@example.com"));
+        // The owner's own view of their account still has it
+        assert_eq!(CleanUser::from_user(&user).email.html, "private@example.com");
+
+        diesel::update(schema::users::table.find(user.id)).set(schema::users::email_visible.eq(true)).execute(&pg_conn).unwrap();
+        let user = get(&pg_conn, user.id).unwrap();
+        assert_eq!(PublicUser::from_user(&user).email.map(|email| email.html), Some("private@example.com".to_string()));
+    }
+
+    #[test]
+    fn only_indexed_profiles_go_in_the_sitemap() {
+        let pg_conn = match test_db::connect() {
+            Some(pg_conn) => pg_conn,
+            None => return
+        };
+        let indexed = test_db::create_user(&pg_conn, "indexed");
+        let hidden = test_db::create_user(&pg_conn, "unindexed");
+        let suspended = test_db::create_user(&pg_conn, "suspended");
+        diesel::update(schema::users::table.filter(schema::users::id.eq_any(vec![indexed.id, suspended.id]))).set(schema::users::indexed.eq(true)).execute(&pg_conn).unwrap();
+        diesel::update(schema::users::table.find(hidden.id)).set(schema::users::indexed.eq(false)).execute(&pg_conn).unwrap();
+        set_suspended(&pg_conn, suspended.id, true).unwrap();
+
+        let ids: Vec<i64> = get_indexed(&pg_conn).unwrap().iter().map(|user| user.id).collect();
+        assert!(ids.contains(&indexed.id));
+        assert!(!ids.contains(&hidden.id));
+        assert!(!ids.contains(&suspended.id));
     }
 }
\ No newline at end of file